@@ -0,0 +1,159 @@
+use image::Color;
+
+// How much of the source or destination color goes into the blend
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha,
+}
+
+// How the weighted source and destination are combined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendEquation {
+    Add,
+    Subtract,
+    ReverseSubtract,
+    Min,
+    Max,
+}
+
+// Mirrors the fixed function blend unit on a GPU - color and alpha get their own
+// factors and equation so things like premultiplied alpha work properly
+#[derive(Clone, Copy, Debug)]
+pub struct BlendState {
+    pub enabled: bool,
+    pub src_color: BlendFactor,
+    pub dst_color: BlendFactor,
+    pub color_equation: BlendEquation,
+    pub src_alpha: BlendFactor,
+    pub dst_alpha: BlendFactor,
+    pub alpha_equation: BlendEquation,
+}
+
+impl Default for BlendState {
+    fn default() -> Self {
+        BlendState::replace()
+    }
+}
+
+impl BlendState {
+    fn new(src: BlendFactor, dst: BlendFactor, equation: BlendEquation) -> Self {
+        BlendState {
+            enabled: true,
+            src_color: src,
+            dst_color: dst,
+            color_equation: equation,
+            src_alpha: src,
+            dst_alpha: dst,
+            alpha_equation: equation,
+        }
+    }
+
+    // Blending off, the fragment just overwrites whatever is there
+    pub fn replace() -> Self {
+        BlendState {
+            enabled: false,
+            ..BlendState::new(BlendFactor::One, BlendFactor::Zero, BlendEquation::Add)
+        }
+    }
+
+    // Regular "over" compositing for straight (non-premultiplied) alpha
+    pub fn alpha() -> Self {
+        BlendState {
+            src_alpha: BlendFactor::One,
+            ..BlendState::new(BlendFactor::SrcAlpha, BlendFactor::OneMinusSrcAlpha, BlendEquation::Add)
+        }
+    }
+
+    pub fn additive() -> Self {
+        BlendState {
+            src_alpha: BlendFactor::One,
+            dst_alpha: BlendFactor::One,
+            ..BlendState::new(BlendFactor::SrcAlpha, BlendFactor::One, BlendEquation::Add)
+        }
+    }
+
+    pub fn multiply() -> Self {
+        BlendState {
+            src_alpha: BlendFactor::Zero,
+            dst_alpha: BlendFactor::One,
+            ..BlendState::new(BlendFactor::DstColor, BlendFactor::Zero, BlendEquation::Add)
+        }
+    }
+
+    // "Over" when the source color has already been multiplied by its alpha
+    pub fn premultiplied() -> Self {
+        BlendState::new(BlendFactor::One, BlendFactor::OneMinusSrcAlpha, BlendEquation::Add)
+    }
+
+    pub fn blend(&self, src: Color, dst: Color) -> Color {
+        if !self.enabled {
+            return src;
+        }
+
+        let out = self.blend_unit(to_unit(src), to_unit(dst));
+        Color(from_unit(out[0]), from_unit(out[1]), from_unit(out[2]), from_unit(out[3]))
+    }
+
+    // The blend itself, on unclamped colors with alpha as the last channel - so it
+    // works for HDR targets too
+    pub fn blend_unit(&self, s: [f64; 4], d: [f64; 4]) -> [f64; 4] {
+        if !self.enabled {
+            return s;
+        }
+
+        let mut out = [0.0; 4];
+        // The first three channels are color, the last is alpha
+        for i in 0..3 {
+            let sf = factor(self.src_color, &s, &d, i);
+            let df = factor(self.dst_color, &s, &d, i);
+            out[i] = combine(self.color_equation, s[i], sf, d[i], df);
+        }
+        let sf = factor(self.src_alpha, &s, &d, 3);
+        let df = factor(self.dst_alpha, &s, &d, 3);
+        out[3] = combine(self.alpha_equation, s[3], sf, d[3], df);
+        out
+    }
+}
+
+fn to_unit(c: Color) -> [f64; 4] {
+    [c.0 as f64 / 255.0, c.1 as f64 / 255.0, c.2 as f64 / 255.0, c.3 as f64 / 255.0]
+}
+
+fn from_unit(v: f64) -> u8 {
+    (v.max(0.0).min(1.0) * 255.0).round() as u8
+}
+
+fn factor(f: BlendFactor, src: &[f64; 4], dst: &[f64; 4], channel: usize) -> f64 {
+    match f {
+        BlendFactor::Zero => 0.0,
+        BlendFactor::One => 1.0,
+        BlendFactor::SrcColor => src[channel],
+        BlendFactor::OneMinusSrcColor => 1.0 - src[channel],
+        BlendFactor::DstColor => dst[channel],
+        BlendFactor::OneMinusDstColor => 1.0 - dst[channel],
+        BlendFactor::SrcAlpha => src[3],
+        BlendFactor::OneMinusSrcAlpha => 1.0 - src[3],
+        BlendFactor::DstAlpha => dst[3],
+        BlendFactor::OneMinusDstAlpha => 1.0 - dst[3],
+    }
+}
+
+fn combine(equation: BlendEquation, src: f64, src_factor: f64, dst: f64, dst_factor: f64) -> f64 {
+    // Like GL, min and max ignore the factors entirely
+    match equation {
+        BlendEquation::Add => src * src_factor + dst * dst_factor,
+        BlendEquation::Subtract => src * src_factor - dst * dst_factor,
+        BlendEquation::ReverseSubtract => dst * dst_factor - src * src_factor,
+        BlendEquation::Min => src.min(dst),
+        BlendEquation::Max => src.max(dst),
+    }
+}
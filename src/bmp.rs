@@ -0,0 +1,508 @@
+use image::{Image, Color};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write, BufWriter};
+use std::io::{Error, ErrorKind};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use error::{LoadError, read_file};
+
+// Compression methods from the info header
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_BITFIELDS: u32 = 3;
+
+const FILE_HEADER_SIZE: u32 = 14;
+const INFO_HEADER_SIZE: u32 = 40;
+const V4_HEADER_SIZE: u32 = 108;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BMPFormat {
+    Rgb24,
+    // Written with a V4 header and bitfield masks so readers know the alpha is real
+    Rgba32,
+    // Only works for images with 256 colors or fewer
+    Paletted8,
+    Rle8,
+}
+
+#[derive(Debug)]
+struct BMPHeader {
+    data_offset: u32,
+    width: i32,
+    // Negative means the rows are stored top-down
+    height: i32,
+    bits_per_pixel: u16,
+    compression: u32,
+    colors_used: u32,
+    // Red, green, blue and alpha masks for bitfield images
+    masks: [u32; 4],
+    // Size of each palette entry - the old OS/2 core header uses three bytes
+    palette_entry_size: usize,
+    header_size: u32,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+impl BMPHeader {
+    fn from_reader(r: &mut Read) -> Result<BMPHeader, Error> {
+        let mut magic = [0u8; 2];
+        try!(r.read_exact(&mut magic));
+        if &magic != b"BM" {
+            return Err(invalid("Not a BMP file"));
+        }
+        let _file_size = try!(r.read_u32::<LittleEndian>());
+        let _reserved = try!(r.read_u32::<LittleEndian>());
+        let data_offset = try!(r.read_u32::<LittleEndian>());
+        let header_size = try!(r.read_u32::<LittleEndian>());
+
+        if header_size == 12 {
+            let width = try!(r.read_u16::<LittleEndian>()) as i32;
+            let height = try!(r.read_u16::<LittleEndian>()) as i32;
+            let _planes = try!(r.read_u16::<LittleEndian>());
+            let bits_per_pixel = try!(r.read_u16::<LittleEndian>());
+            return Ok(BMPHeader {
+                data_offset: data_offset,
+                width: width,
+                height: height,
+                bits_per_pixel: bits_per_pixel,
+                compression: BI_RGB,
+                colors_used: 0,
+                masks: [0; 4],
+                palette_entry_size: 3,
+                header_size: header_size,
+            });
+        }
+
+        if header_size < INFO_HEADER_SIZE {
+            return Err(invalid("Unsupported BMP header"));
+        }
+
+        let width = try!(r.read_i32::<LittleEndian>());
+        let height = try!(r.read_i32::<LittleEndian>());
+        let _planes = try!(r.read_u16::<LittleEndian>());
+        let bits_per_pixel = try!(r.read_u16::<LittleEndian>());
+        let compression = try!(r.read_u32::<LittleEndian>());
+        let _image_size = try!(r.read_u32::<LittleEndian>());
+        let _x_pixels_per_meter = try!(r.read_i32::<LittleEndian>());
+        let _y_pixels_per_meter = try!(r.read_i32::<LittleEndian>());
+        let colors_used = try!(r.read_u32::<LittleEndian>());
+        let _colors_important = try!(r.read_u32::<LittleEndian>());
+
+        // V2 and later headers carry the masks, otherwise for BI_BITFIELDS they directly
+        // follow the info header
+        let mut masks = [0u32; 4];
+        let mask_count = if header_size >= 56 {
+            4
+        } else if header_size >= 52 || compression == BI_BITFIELDS {
+            3
+        } else {
+            0
+        };
+        for mask in masks.iter_mut().take(mask_count) {
+            *mask = try!(r.read_u32::<LittleEndian>());
+        }
+
+        Ok(BMPHeader {
+            data_offset: data_offset,
+            width: width,
+            height: height,
+            bits_per_pixel: bits_per_pixel,
+            compression: compression,
+            colors_used: colors_used,
+            masks: masks,
+            palette_entry_size: 4,
+            header_size: header_size,
+        })
+    }
+}
+
+pub fn read_bmp_file(filename: &str) -> Result<Image, LoadError> {
+    let data = try!(read_file(filename));
+    decode_bmp(&data).map_err(|e| LoadError::io(filename, e))
+}
+
+pub fn decode_bmp(data: &[u8]) -> Result<Image, Error> {
+    let header = try!(BMPHeader::from_reader(&mut &data[..]));
+    let width = header.width;
+    let height = match header.height.checked_abs() {
+        Some(height) => height,
+        None => return Err(invalid("BMP height is out of range")),
+    };
+    let top_down = header.height < 0;
+
+    if width <= 0 || height == 0 {
+        return Err(invalid("BMP has no pixels"));
+    }
+    match (width as usize).checked_mul(height as usize) {
+        Some(pixels) if pixels <= i32::max_value() as usize => {},
+        _ => return Err(invalid("BMP dimensions are too large")),
+    }
+
+    // The palette sits between the headers and the pixel data
+    let mut palette: Vec<Color> = Vec::new();
+    if header.bits_per_pixel == 8 {
+        let masks_size = if header.compression == BI_BITFIELDS && header.header_size == INFO_HEADER_SIZE { 12 } else { 0 };
+        let palette_start = match FILE_HEADER_SIZE.checked_add(header.header_size)
+            .and_then(|size| size.checked_add(masks_size)) {
+            Some(start) => start as usize,
+            None => return Err(invalid("BMP header size is out of range")),
+        };
+        let count = if header.colors_used == 0 { 256 } else { header.colors_used.min(256) as usize };
+        for i in 0..count {
+            let start = palette_start + i * header.palette_entry_size;
+            if start >= data.len() || data.len() - start < 3 {
+                break;
+            }
+            palette.push(Color(data[start], data[start + 1], data[start + 2], 255));
+        }
+    }
+
+    if header.data_offset as usize > data.len() {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "BMP pixel data is missing"));
+    }
+    let pixels = &data[header.data_offset as usize..];
+
+    let mut colors = match (header.bits_per_pixel, header.compression) {
+        (8, BI_RLE8) => try!(decode_rle8(pixels, width, height, &palette)),
+        (8, BI_RGB) => try!(decode_rows(pixels, width, height, 1, |p| {
+            palette.get(p[0] as usize).cloned().ok_or_else(|| invalid("BMP palette index out of range"))
+        })),
+        (24, BI_RGB) => try!(decode_rows(pixels, width, height, 3, |p| Ok(Color(p[0], p[1], p[2], 255)))),
+        (32, BI_RGB) => try!(decode_rows(pixels, width, height, 4, |p| Ok(Color(p[0], p[1], p[2], p[3])))),
+        (32, BI_BITFIELDS) => {
+            let masks = header.masks;
+            try!(decode_rows(pixels, width, height, 4, |p| {
+                let v = (p[0] as u32) | (p[1] as u32) << 8 | (p[2] as u32) << 16 | (p[3] as u32) << 24;
+                let alpha = if masks[3] == 0 { 255 } else { extract(v, masks[3]) };
+                Ok(Color(extract(v, masks[2]), extract(v, masks[1]), extract(v, masks[0]), alpha))
+            }))
+        },
+        _ => return Err(invalid("Unsupported BMP pixel format")),
+    };
+
+    // Plain 32 bit BMPs usually leave the fourth byte as zero padding rather than alpha
+    if header.bits_per_pixel == 32 && header.compression == BI_RGB && colors.iter().all(|c| c.3 == 0) {
+        for c in colors.iter_mut() {
+            c.3 = 255;
+        }
+    }
+
+    // Bottom-up is what the renderer uses, so only top-down images need flipping
+    if top_down {
+        colors = colors.chunks(width as usize).rev().flat_map(|row| row.iter().cloned()).collect();
+    }
+
+    let mut image = Image::new(width, height);
+    image.set_data_buffer(colors);
+    Ok(image)
+}
+
+// Pull the bits under a mask out and scale them to eight bits
+fn extract(v: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let max = mask >> shift;
+    (((v & mask) >> shift) as u64 * 255 / max as u64) as u8
+}
+
+// Uncompressed rows, each padded out to a multiple of four bytes
+fn decode_rows<F>(data: &[u8], width: i32, height: i32, bytes_per_pixel: usize, mut f: F)
+                  -> Result<Vec<Color>, Error>
+    where F: FnMut(&[u8]) -> Result<Color, Error> {
+    let row_size = width as usize * bytes_per_pixel;
+    let stride = (row_size + 3) & !3;
+    let size = stride.checked_mul(height as usize - 1).and_then(|size| size.checked_add(row_size));
+    if size.map_or(true, |size| data.len() < size) {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "BMP pixel data is truncated"));
+    }
+
+    let mut colors = Vec::with_capacity((width * height) as usize);
+    for y in 0..height as usize {
+        let row = &data[y * stride..];
+        for x in 0..width as usize {
+            colors.push(try!(f(&row[x * bytes_per_pixel..(x + 1) * bytes_per_pixel])));
+        }
+    }
+    Ok(colors)
+}
+
+fn decode_rle8(data: &[u8], width: i32, height: i32, palette: &[Color]) -> Result<Vec<Color>, Error> {
+    let (width, height) = (width as usize, height as usize);
+    // A run turns two bytes into at most 255 pixels. Deltas and an early end of bitmap
+    // can leave more than that as background, but a header claiming far more pixels than
+    // the runs could fill is more likely bogus than worth allocating for.
+    if width * height > data.len() / 2 * 255 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "BMP RLE data is too short for the image size"));
+    }
+
+    // Skipped pixels take the first palette entry
+    let background = palette.get(0).cloned().unwrap_or(Color(0, 0, 0, 255));
+    let mut colors = vec![background; width * height];
+    let lookup = |i: u8| palette.get(i as usize).cloned().ok_or_else(|| invalid("BMP palette index out of range"));
+
+    let (mut x, mut y) = (0usize, 0usize);
+    let mut i = 0;
+    while i + 1 < data.len() && y < height {
+        let (count, value) = (data[i] as usize, data[i + 1]);
+        i += 2;
+
+        if count > 0 {
+            // Encoded run
+            let c = try!(lookup(value));
+            for _ in 0..count {
+                if x < width {
+                    colors[y * width + x] = c;
+                }
+                x += 1;
+            }
+            continue;
+        }
+
+        match value {
+            // End of line
+            0 => {
+                x = 0;
+                y += 1;
+            },
+            // End of bitmap
+            1 => break,
+            // Delta, move right and up
+            2 => {
+                if i + 1 >= data.len() {
+                    break;
+                }
+                x += data[i] as usize;
+                y += data[i + 1] as usize;
+                i += 2;
+            },
+            // Absolute run of literal indices, padded to an even number of bytes
+            n => {
+                let n = n as usize;
+                if i + n > data.len() {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "BMP RLE data is truncated"));
+                }
+                for k in 0..n {
+                    if x < width && y < height {
+                        colors[y * width + x] = try!(lookup(data[i + k]));
+                    }
+                    x += 1;
+                }
+                i += n + (n & 1);
+            }
+        }
+    }
+
+    Ok(colors)
+}
+
+pub fn write_bmp_file(image: &Image, filename: &str, format: BMPFormat) -> Result<(), Error> {
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    try!(f.write_all(&try!(encode_bmp(image, format))));
+    f.flush()
+}
+
+pub fn encode_bmp(image: &Image, format: BMPFormat) -> Result<Vec<u8>, Error> {
+    let (width, height) = (image.width.max(0) as usize, image.height.max(0) as usize);
+
+    // Build the palette first for the 8 bit formats, in order of first use
+    let mut palette: Vec<Color> = Vec::new();
+    let mut indices: HashMap<(u8, u8, u8), u8> = HashMap::new();
+    if format == BMPFormat::Paletted8 || format == BMPFormat::Rle8 {
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let c = image.get_pixel(x, y);
+                let key = (c.0, c.1, c.2);
+                if !indices.contains_key(&key) {
+                    if palette.len() == 256 {
+                        return Err(Error::new(ErrorKind::InvalidInput,
+                                              "Image has too many colors for an 8 bit BMP"));
+                    }
+                    indices.insert(key, palette.len() as u8);
+                    palette.push(c);
+                }
+            }
+        }
+    }
+    let index_of = |c: Color| indices[&(c.0, c.1, c.2)];
+
+    // Pixel data, bottom row first
+    let mut pixels: Vec<u8> = Vec::new();
+    match format {
+        BMPFormat::Rgb24 | BMPFormat::Rgba32 | BMPFormat::Paletted8 => {
+            let bytes_per_pixel = match format {
+                BMPFormat::Rgb24 => 3,
+                BMPFormat::Rgba32 => 4,
+                _ => 1,
+            };
+            let stride = (width * bytes_per_pixel + 3) & !3;
+            for y in 0..image.height {
+                let start = pixels.len();
+                for x in 0..image.width {
+                    let c = image.get_pixel(x, y);
+                    match bytes_per_pixel {
+                        3 => pixels.extend(&[c.0, c.1, c.2]),
+                        4 => pixels.extend(&[c.0, c.1, c.2, c.3]),
+                        _ => pixels.push(index_of(c)),
+                    }
+                }
+                pixels.resize(start + stride, 0);
+            }
+        },
+        BMPFormat::Rle8 => {
+            for y in 0..image.height {
+                let row: Vec<u8> = (0..image.width).map(|x| index_of(image.get_pixel(x, y))).collect();
+                encode_rle8_row(&row, &mut pixels);
+                // End of line
+                pixels.extend(&[0, 0]);
+            }
+            // End of bitmap
+            pixels.extend(&[0, 1]);
+        },
+    }
+
+    let (header_size, bits_per_pixel, compression) = match format {
+        BMPFormat::Rgb24 => (INFO_HEADER_SIZE, 24, BI_RGB),
+        BMPFormat::Rgba32 => (V4_HEADER_SIZE, 32, BI_BITFIELDS),
+        BMPFormat::Paletted8 => (INFO_HEADER_SIZE, 8, BI_RGB),
+        BMPFormat::Rle8 => (INFO_HEADER_SIZE, 8, BI_RLE8),
+    };
+    let data_offset = FILE_HEADER_SIZE + header_size + palette.len() as u32 * 4;
+
+    let mut out: Vec<u8> = Vec::with_capacity(data_offset as usize + pixels.len());
+    out.extend(b"BM");
+    try!(out.write_u32::<LittleEndian>(data_offset + pixels.len() as u32));
+    try!(out.write_u32::<LittleEndian>(0));
+    try!(out.write_u32::<LittleEndian>(data_offset));
+
+    try!(out.write_u32::<LittleEndian>(header_size));
+    try!(out.write_i32::<LittleEndian>(width as i32));
+    try!(out.write_i32::<LittleEndian>(height as i32));
+    try!(out.write_u16::<LittleEndian>(1));
+    try!(out.write_u16::<LittleEndian>(bits_per_pixel));
+    try!(out.write_u32::<LittleEndian>(compression));
+    try!(out.write_u32::<LittleEndian>(pixels.len() as u32));
+    // 72 DPI
+    try!(out.write_i32::<LittleEndian>(2835));
+    try!(out.write_i32::<LittleEndian>(2835));
+    try!(out.write_u32::<LittleEndian>(palette.len() as u32));
+    try!(out.write_u32::<LittleEndian>(0));
+
+    if format == BMPFormat::Rgba32 {
+        // Red, green, blue and alpha masks, then the color space ('sRGB') and its unused
+        // endpoints and gamma
+        for &mask in &[0x00ff0000u32, 0x0000ff00, 0x000000ff, 0xff000000] {
+            try!(out.write_u32::<LittleEndian>(mask));
+        }
+        out.extend(b"BGRs");
+        out.extend(&[0u8; 48]);
+    }
+
+    for c in palette.iter() {
+        out.extend(&[c.0, c.1, c.2, 0]);
+    }
+    out.extend(pixels);
+    Ok(out)
+}
+
+fn encode_rle8_row(row: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < row.len() {
+        let mut run = 1;
+        while i + run < row.len() && run < 255 && row[i + run] == row[i] {
+            run += 1;
+        }
+
+        if run > 1 {
+            out.extend(&[run as u8, row[i]]);
+            i += run;
+            continue;
+        }
+
+        // Gather literals until the next repeat, absolute runs need at least three
+        let start = i;
+        while i < row.len() && i - start < 255 && !(i + 1 < row.len() && row[i + 1] == row[i]) {
+            i += 1;
+        }
+        let literals = &row[start..i.max(start + 1)];
+        i = start + literals.len();
+
+        if literals.len() < 3 {
+            for &v in literals {
+                out.extend(&[1, v]);
+            }
+        } else {
+            out.extend(&[0, literals.len() as u8]);
+            out.extend(literals);
+            if literals.len() & 1 == 1 {
+                out.push(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{TempFile, test_image, assert_same_pixels};
+
+    // Overwrite one of the header's 32 bit fields
+    fn with_field(bmp: &[u8], offset: usize, value: u32) -> Vec<u8> {
+        let mut bmp = bmp.to_vec();
+        (&mut bmp[offset..offset + 4]).write_u32::<LittleEndian>(value).unwrap();
+        bmp
+    }
+
+    #[test]
+    fn round_trip() {
+        // Odd widths so rows need padding
+        let image = test_image(31, 12);
+        for &(format, alpha) in [(BMPFormat::Rgb24, false), (BMPFormat::Rgba32, true)].iter() {
+            assert_same_pixels(&image, &decode_bmp(&encode_bmp(&image, format).unwrap()).unwrap(), alpha);
+        }
+
+        let file = TempFile::new("round_trip.bmp");
+        write_bmp_file(&image, file.name(), BMPFormat::Rgba32).unwrap();
+        assert_same_pixels(&image, &read_bmp_file(file.name()).unwrap(), true);
+    }
+
+    #[test]
+    fn paletted_round_trip() {
+        // Few enough colors for a palette, with long runs for RLE
+        let image = test_image(7, 9);
+        for &format in [BMPFormat::Paletted8, BMPFormat::Rle8].iter() {
+            assert_same_pixels(&image, &decode_bmp(&encode_bmp(&image, format).unwrap()).unwrap(), false);
+        }
+    }
+
+    #[test]
+    fn too_many_colors() {
+        assert!(encode_bmp(&test_image(64, 64), BMPFormat::Paletted8).is_err());
+    }
+
+    #[test]
+    fn truncated_file() {
+        let bmp = encode_bmp(&test_image(16, 16), BMPFormat::Rgb24).unwrap();
+        assert!(decode_bmp(&bmp[..bmp.len() / 2]).is_err());
+        assert!(decode_bmp(&bmp[..10]).is_err());
+    }
+
+    #[test]
+    fn bad_sizes() {
+        // Header size, width and height
+        let (size, width, height) = (14, 18, 22);
+        let bmp = encode_bmp(&test_image(8, 8), BMPFormat::Rgb24).unwrap();
+        assert!(decode_bmp(&with_field(&bmp, height, 0x80000000)).is_err());
+        assert!(decode_bmp(&with_field(&with_field(&bmp, width, 0x7fffffff), height, 0x7fffffff)).is_err());
+        assert!(decode_bmp(&with_field(&with_field(&bmp, width, 0x10000), height, 0x10000)).is_err());
+
+        let paletted = encode_bmp(&test_image(8, 8), BMPFormat::Paletted8).unwrap();
+        assert!(decode_bmp(&with_field(&paletted, size, 0xffffffff)).is_err());
+
+        let rle = encode_bmp(&test_image(8, 8), BMPFormat::Rle8).unwrap();
+        assert!(decode_bmp(&with_field(&with_field(&rle, width, 30000), height, 30000)).is_err());
+    }
+}
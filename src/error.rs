@@ -0,0 +1,81 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+// Why a model, material library or image couldn't be loaded, with enough to find the
+// problem in the file. Decoders that only see bytes use io::Error and get the path
+// attached by whatever read the file.
+#[derive(Debug)]
+pub enum LoadError {
+    // Opening or reading the file failed, or a binary format had bad data in it
+    Io { path: PathBuf, error: io::Error },
+    // Something in a text format that doesn't parse. Lines start at 1.
+    Parse { path: PathBuf, line: usize, token: String, message: String },
+    // Nothing wrong with the file, we just can't read it, e.g. an unknown extension
+    Unsupported { path: PathBuf, message: String },
+}
+
+impl LoadError {
+    pub fn io<P: AsRef<Path>>(path: P, error: io::Error) -> Self {
+        LoadError::Io { path: path.as_ref().to_path_buf(), error: error }
+    }
+
+    pub fn parse<P: AsRef<Path>>(path: P, line: usize, token: &str, message: &str) -> Self {
+        LoadError::Parse {
+            path: path.as_ref().to_path_buf(),
+            line: line,
+            token: token.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn unsupported<P: AsRef<Path>>(path: P, message: &str) -> Self {
+        LoadError::Unsupported { path: path.as_ref().to_path_buf(), message: message.to_string() }
+    }
+
+    pub fn path(&self) -> &Path {
+        match *self {
+            LoadError::Io { ref path, .. } => path,
+            LoadError::Parse { ref path, .. } => path,
+            LoadError::Unsupported { ref path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io { ref path, ref error } => write!(f, "{}: {}", path.display(), error),
+            LoadError::Parse { ref path, line, ref token, ref message } =>
+                write!(f, "{}:{}: {} '{}'", path.display(), line, message, token),
+            LoadError::Unsupported { ref path, ref message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl error::Error for LoadError {
+    fn description(&self) -> &str {
+        match *self {
+            LoadError::Io { .. } => "couldn't read file",
+            LoadError::Parse { .. } => "couldn't parse file",
+            LoadError::Unsupported { .. } => "unsupported file",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            LoadError::Io { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// The whole file, for the readers that decode from memory
+pub fn read_file(filename: &str) -> Result<Vec<u8>, LoadError> {
+    let mut data = Vec::new();
+    try!(File::open(filename).and_then(|mut f| f.read_to_end(&mut data))
+         .map_err(|e| LoadError::io(filename, e)));
+    Ok(data)
+}
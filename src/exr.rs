@@ -0,0 +1,274 @@
+use hdr::HdrImage;
+use zlib;
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::io::Error;
+use byteorder::{WriteBytesExt, LittleEndian};
+
+// Single part scanline OpenEXR, written as 32 bit float RGBA
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrCompression {
+    None,
+    // zlib over blocks of 16 scanlines
+    Zip,
+}
+
+impl ExrCompression {
+    fn id(&self) -> u8 {
+        match *self {
+            ExrCompression::None => 0,
+            ExrCompression::Zip => 3,
+        }
+    }
+
+    fn lines_per_block(&self) -> i32 {
+        match *self {
+            ExrCompression::None => 1,
+            ExrCompression::Zip => 16,
+        }
+    }
+}
+
+const MAGIC: u32 = 20000630;
+const VERSION: u32 = 2;
+// Pixel type for 32 bit floats
+const FLOAT: i32 = 2;
+// Channels have to be listed in alphabetical order
+const CHANNELS: [&'static str; 4] = ["A", "B", "G", "R"];
+
+fn attribute(out: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+    out.extend(name.as_bytes());
+    out.push(0);
+    out.extend(type_name.as_bytes());
+    out.push(0);
+    out.write_i32::<LittleEndian>(value.len() as i32).unwrap();
+    out.extend(value);
+}
+
+fn header(image: &HdrImage, compression: ExrCompression) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.write_u32::<LittleEndian>(MAGIC).unwrap();
+    out.write_u32::<LittleEndian>(VERSION).unwrap();
+
+    let mut channels = Vec::new();
+    for name in CHANNELS.iter() {
+        channels.extend(name.as_bytes());
+        channels.push(0);
+        channels.write_i32::<LittleEndian>(FLOAT).unwrap();
+        // pLinear and three reserved bytes
+        channels.extend(&[0, 0, 0, 0]);
+        // x and y sampling
+        channels.write_i32::<LittleEndian>(1).unwrap();
+        channels.write_i32::<LittleEndian>(1).unwrap();
+    }
+    channels.push(0);
+    attribute(&mut out, "channels", "chlist", &channels);
+
+    attribute(&mut out, "compression", "compression", &[compression.id()]);
+
+    let mut window = Vec::new();
+    for &v in &[0, 0, image.width - 1, image.height - 1] {
+        window.write_i32::<LittleEndian>(v).unwrap();
+    }
+    attribute(&mut out, "dataWindow", "box2i", &window);
+    attribute(&mut out, "displayWindow", "box2i", &window);
+
+    // Increasing y, top scanline first
+    attribute(&mut out, "lineOrder", "lineOrder", &[0]);
+
+    let mut one = Vec::new();
+    one.write_f32::<LittleEndian>(1.0).unwrap();
+    attribute(&mut out, "pixelAspectRatio", "float", &one);
+    attribute(&mut out, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut out, "screenWindowWidth", "float", &one);
+
+    // End of header
+    out.push(0);
+    out
+}
+
+// One block of scanlines, each stored channel by channel
+fn block_data(image: &HdrImage, first_line: i32, lines: i32) -> Vec<u8> {
+    let mut out = Vec::with_capacity((lines * image.width * 16) as usize);
+    for line in first_line..first_line + lines {
+        // EXR counts scanlines from the top, the image stores the bottom row first
+        let y = image.height - 1 - line;
+        for name in CHANNELS.iter() {
+            for x in 0..image.width {
+                let c = image.get_pixel(x, y);
+                let v = match *name {
+                    "R" => c.r,
+                    "G" => c.g,
+                    "B" => c.b,
+                    _ => c.a,
+                };
+                out.write_f32::<LittleEndian>(v).unwrap();
+            }
+        }
+    }
+    out
+}
+
+// ZIP compression splits the bytes into two interleaved halves and delta encodes them
+// before deflating, which makes float data compress much better
+fn zip_block(raw: &[u8]) -> Vec<u8> {
+    let half = (raw.len() + 1) / 2;
+    let mut t = vec![0u8; raw.len()];
+    for (i, &b) in raw.iter().enumerate() {
+        if i % 2 == 0 {
+            t[i / 2] = b;
+        } else {
+            t[half + i / 2] = b;
+        }
+    }
+
+    for i in (1..t.len()).rev() {
+        t[i] = t[i].wrapping_sub(t[i - 1]).wrapping_add(128);
+    }
+
+    zlib::compress(&t)
+}
+
+pub fn write_exr_file(image: &HdrImage, filename: &str, compression: ExrCompression) -> Result<(), Error> {
+    let header = header(image, compression);
+    let lines_per_block = compression.lines_per_block();
+    let num_blocks = (image.height + lines_per_block - 1) / lines_per_block;
+
+    let mut blocks: Vec<Vec<u8>> = Vec::with_capacity(num_blocks as usize);
+    for block in 0..num_blocks {
+        let first_line = block * lines_per_block;
+        let lines = lines_per_block.min(image.height - first_line);
+        let raw = block_data(image, first_line, lines);
+
+        let data = match compression {
+            ExrCompression::None => raw,
+            ExrCompression::Zip => {
+                // If compressing doesn't help the block is stored as is
+                let zipped = zip_block(&raw);
+                if zipped.len() < raw.len() { zipped } else { raw }
+            }
+        };
+
+        let mut chunk = Vec::with_capacity(data.len() + 8);
+        chunk.write_i32::<LittleEndian>(first_line).unwrap();
+        chunk.write_i32::<LittleEndian>(data.len() as i32).unwrap();
+        chunk.extend(data);
+        blocks.push(chunk);
+    }
+
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    try!(f.write_all(&header));
+
+    // The offset table points at each block from the start of the file
+    let mut offset = (header.len() + blocks.len() * 8) as u64;
+    for block in blocks.iter() {
+        try!(f.write_u64::<LittleEndian>(offset));
+        offset += block.len() as u64;
+    }
+    for block in blocks.iter() {
+        try!(f.write_all(block));
+    }
+
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use byteorder::{ByteOrder, ReadBytesExt};
+    use std::io::{Cursor, Read};
+    use hdr::HdrColor;
+    use testutil::TempFile;
+
+    // Just enough of a reader for what the writer makes. Pixels come back top row first,
+    // channels in the order they're stored.
+    fn read_exr(data: &[u8], width: usize, height: usize) -> Vec<[f32; 4]> {
+        let mut r = Cursor::new(data);
+        assert_eq!(r.read_u32::<LittleEndian>().unwrap(), MAGIC);
+        assert_eq!(r.read_u32::<LittleEndian>().unwrap(), VERSION);
+
+        // Attributes up to the empty name at the end of the header
+        let string = |r: &mut Cursor<&[u8]>| {
+            let mut s = Vec::new();
+            loop {
+                match r.read_u8().unwrap() {
+                    0 => return s,
+                    b => s.push(b),
+                }
+            }
+        };
+        let mut lines_per_block = 0;
+        loop {
+            let name = string(&mut r);
+            if name.is_empty() {
+                break;
+            }
+            string(&mut r);
+            let mut value = vec![0; r.read_i32::<LittleEndian>().unwrap() as usize];
+            r.read_exact(&mut value).unwrap();
+            if name == b"compression" {
+                lines_per_block = if value[0] == 3 { 16 } else { 1 };
+            }
+        }
+
+        let blocks = (height + lines_per_block - 1) / lines_per_block;
+        let offsets: Vec<u64> = (0..blocks).map(|_| r.read_u64::<LittleEndian>().unwrap()).collect();
+        let mut pixels = vec![[0.0; 4]; width * height];
+        for &offset in offsets.iter() {
+            let offset = offset as usize;
+            let first_line = LittleEndian::read_i32(&data[offset..]) as usize;
+            let size = LittleEndian::read_i32(&data[offset + 4..]) as usize;
+            let lines = lines_per_block.min(height - first_line);
+            let mut raw = data[offset + 8..offset + 8 + size].to_vec();
+
+            // Undo the zip predictor and interleaving, unless the block was stored as is
+            if raw.len() < lines * width * 16 {
+                let mut t = zlib::decompress(&raw).unwrap();
+                for i in 1..t.len() {
+                    t[i] = t[i].wrapping_add(t[i - 1]).wrapping_sub(128);
+                }
+                let half = (t.len() + 1) / 2;
+                raw = (0..t.len()).map(|i| if i % 2 == 0 { t[i / 2] } else { t[half + i / 2] }).collect();
+            }
+
+            for line in 0..lines {
+                for channel in 0..4 {
+                    for x in 0..width {
+                        let at = ((line * 4 + channel) * width + x) * 4;
+                        pixels[(first_line + line) * width + x][channel] = LittleEndian::read_f32(&raw[at..]);
+                    }
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn round_trip() {
+        // More than one zip block, with the last one partly filled
+        let (width, height) = (13, 21);
+        let mut image = HdrImage::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                image.set_pixel(x, y, HdrColor::new(x as f32 * 1.5, y as f32 / 3.0, 100.0, 0.5));
+            }
+        }
+
+        for &compression in [ExrCompression::None, ExrCompression::Zip].iter() {
+            let file = TempFile::new(&format!("round_trip_{:?}.exr", compression));
+            write_exr_file(&image, file.name(), compression).unwrap();
+            let pixels = read_exr(&fs::read(&file.path).unwrap(), width as usize, height as usize);
+
+            for y in 0..height {
+                for x in 0..width {
+                    let c = image.get_pixel(x, y);
+                    // Stored top row first, channels alphabetically
+                    let p = pixels[((height - 1 - y) * width + x) as usize];
+                    assert_eq!(p, [c.a, c.b, c.g, c.r]);
+                }
+            }
+        }
+    }
+}
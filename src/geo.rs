@@ -2,6 +2,13 @@ use std::fmt;
 use std::ops::{Add, Sub, Mul};
 use num::ToPrimitive;
 use image::{Image, Color};
+use state::DrawState;
+use oit::OitBuffer;
+use sampler::Sampler;
+use hdr::{HdrImage, HdrColor};
+
+// Range of the zbuffer - normalized z of -1 to 1 maps onto 0 to DEPTH
+pub const DEPTH: f64 = 65535.0;
 
 pub trait VecNum: Add + Sub + Mul + Sized + ToPrimitive + Copy {}
 
@@ -139,6 +146,18 @@ impl<T> Vec3<T> where T: VecNum + Mul<T, Output=T> + Add<T, Output=T> {
     }
 }
 
+impl<T> Add for Vec3<T> where T: VecNum + Add<Output=T> {
+    type Output = Vec3<T>;
+
+    fn add(self, other: Vec3<T>) -> Vec3<T> {
+        Vec3 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
 impl<T> Sub for Vec3<T> where T: VecNum + Sub<Output=T> {
     type Output = Vec3<T>;
 
@@ -180,11 +199,157 @@ pub fn cross_product<T>(a: Vec3<T>, b: Vec3<T>) -> Vec3<T>
     }
 }
 
+// Affine transform, stored row by row. Points are column vectors so a * b applies b first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mat4 {
+    pub m: [[f64; 4]; 4],
+}
+
+impl Mat4 {
+    pub fn identity() -> Self {
+        Mat4 {
+            m: [[1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 1.0]]
+        }
+    }
+
+    // 16 values going down the columns first, the way glTF and OpenGL store them
+    pub fn from_column_major(values: &[f64]) -> Self {
+        let mut m = [[0.0; 4]; 4];
+        for col in 0..4 {
+            for row in 0..4 {
+                m[row][col] = values[col * 4 + row];
+            }
+        }
+        Mat4 { m: m }
+    }
+
+    pub fn translation(t: Vec3<f64>) -> Self {
+        let mut mat = Mat4::identity();
+        mat.m[0][3] = t.x;
+        mat.m[1][3] = t.y;
+        mat.m[2][3] = t.z;
+        mat
+    }
+
+    pub fn scale(s: Vec3<f64>) -> Self {
+        let mut mat = Mat4::identity();
+        mat.m[0][0] = s.x;
+        mat.m[1][1] = s.y;
+        mat.m[2][2] = s.z;
+        mat
+    }
+
+    // From a unit quaternion, x, y and z being the vector part
+    pub fn rotation(x: f64, y: f64, z: f64, w: f64) -> Self {
+        Mat4 {
+            m: [[1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - z * w), 2.0 * (x * z + y * w), 0.0],
+                [2.0 * (x * y + z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - x * w), 0.0],
+                [2.0 * (x * z - y * w), 2.0 * (y * z + x * w), 1.0 - 2.0 * (x * x + y * y), 0.0],
+                [0.0, 0.0, 0.0, 1.0]]
+        }
+    }
+
+    pub fn transform_point(&self, p: Vec3<f64>) -> Vec3<f64> {
+        let m = &self.m;
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        let w = if w == 0.0 { 1.0 } else { w };
+        Vec3 {
+            x: (m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3]) / w,
+            y: (m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3]) / w,
+            z: (m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3]) / w,
+        }
+    }
+
+    // Directions like tangents ignore the translation
+    pub fn transform_vector(&self, v: Vec3<f64>) -> Vec3<f64> {
+        let m = &self.m;
+        Vec3 {
+            x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        }
+    }
+
+    // Normals need the inverse transpose to stay perpendicular under non-uniform scale.
+    // The cofactor matrix is that times the determinant, which doesn't matter once the
+    // result is normalized - except for the sign, which flips with mirroring transforms.
+    pub fn transform_normal(&self, n: Vec3<f64>) -> Vec3<f64> {
+        let m = &self.m;
+        let cof = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+        let c = [[cof(1, 2, 1, 2), -cof(1, 2, 0, 2), cof(1, 2, 0, 1)],
+                 [-cof(0, 2, 1, 2), cof(0, 2, 0, 2), -cof(0, 2, 0, 1)],
+                 [cof(0, 1, 1, 2), -cof(0, 1, 0, 2), cof(0, 1, 0, 1)]];
+        let det = m[0][0] * c[0][0] + m[0][1] * c[0][1] + m[0][2] * c[0][2];
+        let sign = if det < 0.0 { -1.0 } else { 1.0 };
+
+        let out = Vec3 {
+            x: (c[0][0] * n.x + c[0][1] * n.y + c[0][2] * n.z) * sign,
+            y: (c[1][0] * n.x + c[1][1] * n.y + c[1][2] * n.z) * sign,
+            z: (c[2][0] * n.x + c[2][1] * n.y + c[2][2] * n.z) * sign,
+        };
+        out.normalize()
+    }
+}
+
+impl Mul for Mat4 {
+    type Output = Mat4;
+
+    fn mul(self, rhs: Mat4) -> Mat4 {
+        let mut m = [[0.0; 4]; 4];
+        for row in 0..4 {
+            for col in 0..4 {
+                m[row][col] = (0..4).map(|k| self.m[row][k] * rhs.m[k][col]).sum();
+            }
+        }
+        Mat4 { m: m }
+    }
+}
+
+// Integer pixel rectangle, used for viewports and scissors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Rect {
+            x: x,
+            y: y,
+            width: width,
+            height: height,
+        }
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+
+    // Overlap of the two rectangles, empty (zero sized) if they don't touch
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = (self.x + self.width).min(other.x + other.width);
+        let y1 = (self.y + self.height).min(other.y + other.height);
+        Rect::new(x0, y0, (x1 - x0).max(0), (y1 - y0).max(0))
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Vertex {
     pub coords: Vec3<f64>,
     // Should this be an int?
     texture: Option<Vec2<f64>>,
+    normal: Option<Vec3<f64>>,
+    color: Option<Color>,
+    // Tangent and the sign of the bitangent, for normal mapping
+    tangent: Option<(Vec3<f64>, f64)>,
     pub screen_coords: Vec3<f64>,
     screen_texture: Option<Vec2<f64>>,
 }
@@ -194,6 +359,9 @@ impl Vertex {
         Vertex {
             coords: coords,
             texture: None,
+            normal: None,
+            color: None,
+            tangent: None,
             screen_coords: coords,
             screen_texture: None
         }
@@ -201,30 +369,58 @@ impl Vertex {
 
     pub fn from_vec(coords: Vec<f64>) -> Self {
         let coords = Vec3::from_vec(coords);
-        Vertex {
-            coords: coords,
-            texture: None,
-            screen_coords: coords,
-            screen_texture: None
-        }
+        Vertex::new(coords)
     }
 
+    // The v coordinate is optional in OBJ files and defaults to 0
     pub fn set_texture(&mut self, coords: Vec<f64>) {
-        self.texture = Some(Vec2 {
+        self.set_uv(Vec2 {
             x: coords[0],
-            y: coords[1]
+            y: coords.get(1).cloned().unwrap_or(0.0)
         });
     }
 
+    pub fn set_uv(&mut self, uv: Vec2<f64>) {
+        self.texture = Some(uv);
+    }
+
+    pub fn texture(&self) -> Option<Vec2<f64>> {
+        self.texture
+    }
+
+    pub fn set_normal(&mut self, normal: Vec3<f64>) {
+        self.normal = Some(normal);
+    }
+
+    pub fn normal(&self) -> Option<Vec3<f64>> {
+        self.normal
+    }
+
+    pub fn set_color(&mut self, color: Color) {
+        self.color = Some(color);
+    }
+
+    pub fn color(&self) -> Option<Color> {
+        self.color
+    }
+
+    pub fn set_tangent(&mut self, tangent: Vec3<f64>, handedness: f64) {
+        self.tangent = Some((tangent, handedness));
+    }
+
+    pub fn tangent(&self) -> Option<(Vec3<f64>, f64)> {
+        self.tangent
+    }
+
     pub fn scale_to_image(self, width: i32, height: i32) -> Vertex {
-        let new_coords = Vec3{x: (self.coords.x + 1.0) * (width as f64) / 2.0,
-                              y: (self.coords.y + 1.0) * (height as f64) / 2.0,
-                              z: self.coords.z};
+        self.scale_to_viewport(&Rect::new(0, 0, width, height))
+    }
+
+    pub fn scale_to_viewport(self, viewport: &Rect) -> Vertex {
         Vertex {
-            coords: self.coords,
-            texture: self.texture,
-            screen_coords: new_coords,
+            screen_coords: viewport_transform(self.coords, viewport),
             screen_texture: None,
+            ..self
         }
     }
 
@@ -232,29 +428,49 @@ impl Vertex {
         let new_coords = Vec2{x: ((self.texture.unwrap().x) * (width as f64)),
                               y: (self.texture.unwrap().y) * (height as f64)};
         Vertex {
-            coords: self.coords,
-            texture: self.texture,
-            screen_coords: self.screen_coords,
             screen_texture: Some(new_coords),
+            ..self
         }
     }
 }
 
-#[derive(Debug)]
+// Lighting of a face in linear light, for the HDR path. The texture is multiplied by
+// diffuse, then specular is added on top.
+#[derive(Clone, Copy, Debug)]
+pub struct FaceLight {
+    pub diffuse: HdrColor,
+    pub specular: HdrColor,
+}
+
+// Map normalized -1 to 1 coordinates onto the pixels of the viewport
+pub fn viewport_transform(coords: Vec3<f64>, viewport: &Rect) -> Vec3<f64> {
+    // Depth gets the same treatment so the zbuffer has some precision to work with
+    Vec3{x: viewport.x as f64 + (coords.x + 1.0) * (viewport.width as f64) / 2.0,
+         y: viewport.y as f64 + (coords.y + 1.0) * (viewport.height as f64) / 2.0,
+         z: (coords.z + 1.0) * DEPTH / 2.0}
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct Triangle {
-    pub vertices: Vec<Vertex>,
+    pub vertices: [Vertex; 3],
 }
 
 impl Triangle {
-    pub fn new(vertices: Vec<Vertex>) -> Self {
+    pub fn new(vertices: [Vertex; 3]) -> Self {
         Triangle {
             vertices: vertices
         }
     }
 
     pub fn scale_to_image(&self, width: i32, height: i32) -> Triangle {
+        self.scale_to_viewport(&Rect::new(0, 0, width, height))
+    }
+
+    pub fn scale_to_viewport(&self, viewport: &Rect) -> Triangle {
         Triangle {
-            vertices: self.vertices.iter().map(|v| v.scale_to_image(width, height)).collect()
+            vertices: [self.vertices[0].scale_to_viewport(viewport),
+                       self.vertices[1].scale_to_viewport(viewport),
+                       self.vertices[2].scale_to_viewport(viewport)]
         }
     }
 
@@ -266,78 +482,169 @@ impl Triangle {
         norm.normalize()
     }
 
-    pub fn draw(&self, mut image: &mut Image, color: Color, texture: &Image) {
-        let bbox: Vec<Vec2<i32>> = self.find_bounding_box();
-        let bbox = self.clip_bounding_box(bbox, &image);
-
-        // Iterate over the pixels in the bounding box
-            for x in bbox[0].x..bbox[3].x {
-                for y in bbox[0].y..bbox[2].y {
-                    let p = Vec2{x: x, y: y};
-                    let bc = new_barycentric(&self.vertices[0].screen_coords.xy().to_i32(),
-                                             &self.vertices[1].screen_coords.xy().to_i32(),
-                                             &self.vertices[2].screen_coords.xy().to_i32(),
-                                             &p);
-
-                    // If any of the barycentric coordinates are negative, don't draw
-                    if bc.x >= 0.0 && bc.y >= 0.0 && bc.z >= 0.0 {
-                        // Scale the coordinates
-
-                        // Compute the depth
-                        let z = self.vertices[0].coords.x * bc.x
-                            + self.vertices[1].coords.y * bc.y
-                            + self.vertices[2].coords.z * bc.z;
-
-                        // Compute the texture coordinates
-                        // Scale to texture dimensions
-                        let tex_1 = self.vertices[0].scale_to_texture(texture.width, texture.height)
-                            .screen_texture.unwrap();
-                        let tex_2 = self.vertices[1].scale_to_texture(texture.width, texture.height)
-                            .screen_texture.unwrap();
-                        let tex_3 = self.vertices[2].scale_to_texture(texture.width, texture.height)
-                            .screen_texture.unwrap();
-
-                        let text_coords = Vec2 {
-                            x: tex_1.x * bc.x + tex_2.x * bc.y + tex_3.x * bc.z,
-                            y: tex_1.y * bc.x + tex_2.y * bc.y + tex_3.y * bc.z,
-                        };
-
-                        let texture_color = texture.get_pixel(text_coords.x as i32,
-                                                              text_coords.y as i32);
-
-                        if z as i32 > image.get_depth(x, y) {
-                            image.set_depth(x, y, z);
-                            image.set_pixel(x, y, texture_color);
-                        }
-                    }
+    pub fn draw(&self, image: &mut Image, color: Color, texture: &Image, state: &DrawState) {
+        let bbox = self.find_bounding_box();
+        let bbox = self.clip_bounding_box_to(bbox, &state.scissor_rect(image));
+        let tex = self.texture_coords();
+        let colors = self.vertex_colors();
+        let grad = self.texture_derivatives();
+
+        let bias = state.depth_bias.offset(self.depth_slope());
+
+        self.rasterize(bbox, |x, y, bc, z| {
+            let z = z - bias;
+            if image.depth_stencil_test(x, y, z, &state.stencil) {
+                let fragment = shade_fragment(&tex, &colors, grad, bc, color, texture, &state.sampler);
+                image.set_depth(x, y, z);
+                image.blend_pixel(x, y, fragment, &state.blend);
+            }
+        });
+    }
+
+    // Translucent triangles are depth tested against the opaque pass but don't write
+    // depth - their fragments get accumulated and composited later by the OitBuffer. The
+    // stencil test and ops apply the same as for opaque triangles.
+    pub fn draw_translucent(&self, image: &mut Image, oit: &mut OitBuffer, color: Color, texture: &Image,
+                            state: &DrawState) {
+        let bbox = self.find_bounding_box();
+        let bbox = self.clip_bounding_box_to(bbox, &state.scissor_rect(image));
+        let tex = self.texture_coords();
+        let colors = self.vertex_colors();
+        let grad = self.texture_derivatives();
+        let bias = state.depth_bias.offset(self.depth_slope());
+
+        self.rasterize(bbox, |x, y, bc, z| {
+            let z = z - bias;
+            if image.depth_stencil_test(x, y, z, &state.stencil) {
+                let fragment = shade_fragment(&tex, &colors, grad, bc, color, texture, &state.sampler);
+                oit.add_fragment(x, y, fragment, z);
+            }
+        });
+    }
+
+    // Draw into a float framebuffer, the texture is decoded to linear light and lit without
+    // clipping so bright lights can go past 1.0. There's no stencil on HDR targets.
+    pub fn draw_hdr(&self, image: &mut HdrImage, light: &FaceLight, color: Color, texture: &Image,
+                    state: &DrawState) {
+        let bbox = self.find_bounding_box();
+        let bbox = self.clip_bounding_box_to(bbox, &state.scissor_within(image.rect()));
+        let tex = self.texture_coords();
+        let colors = self.vertex_colors();
+        let grad = self.texture_derivatives();
+        let bias = state.depth_bias.offset(self.depth_slope());
+
+        self.rasterize(bbox, |x, y, bc, z| {
+            let z = z - bias;
+            if z as i32 > image.get_depth(x, y) {
+                let fragment = shade_fragment(&tex, &colors, grad, bc, color, texture, &state.sampler);
+                let t = state.sampler.color_space.decode(fragment);
+                let fragment = HdrColor::new(t.r * light.diffuse.r + light.specular.r,
+                                             t.g * light.diffuse.g + light.specular.g,
+                                             t.b * light.diffuse.b + light.specular.b, t.a);
+                let out = state.blend.blend_unit(fragment.to_unit(), image.get_pixel(x, y).to_unit());
+                image.set_depth(x, y, z);
+                image.set_pixel(x, y, HdrColor::from_unit(out));
+            }
+        });
+    }
+
+    // Largest change in screen depth per pixel step in x or y, used for slope scaled bias
+    pub fn depth_slope(&self) -> f64 {
+        let v = self.vertices[1].screen_coords - self.vertices[0].screen_coords;
+        let w = self.vertices[2].screen_coords - self.vertices[0].screen_coords;
+        let n = cross_product(v, w);
+
+        // Seen exactly edge on, there's nothing to draw anyway
+        if n.z == 0.0 {
+            return 0.0;
+        }
+
+        (n.x / n.z).abs().max((n.y / n.z).abs())
+    }
+
+    // Walk the pixels in the bounding box, handing every one covered by the triangle to
+    // the fragment function along with its barycentric coordinates and depth
+    pub fn rasterize<F>(&self, bbox: [Vec2<i32>; 4], mut fragment: F)
+        where F: FnMut(i32, i32, Vec3<f64>, f64) {
+        let t0 = self.vertices[0].screen_coords.xy().to_i32();
+        let t1 = self.vertices[1].screen_coords.xy().to_i32();
+        let t2 = self.vertices[2].screen_coords.xy().to_i32();
+
+        for x in bbox[0].x..bbox[3].x {
+            for y in bbox[0].y..bbox[2].y {
+                let p = Vec2{x: x, y: y};
+                let bc = new_barycentric(&t0, &t1, &t2, &p);
+
+                // If any of the barycentric coordinates are negative, don't draw
+                if bc.x >= 0.0 && bc.y >= 0.0 && bc.z >= 0.0 {
+                    // Compute the depth
+                    let z = self.vertices[0].screen_coords.z * bc.x
+                        + self.vertices[1].screen_coords.z * bc.y
+                        + self.vertices[2].screen_coords.z * bc.z;
+
+                    fragment(x, y, bc, z);
                 }
             }
-        // }
+        }
     }
 
-    pub fn find_bounding_box(&self) -> Vec<Vec2<i32>> {
-        // Find coordinates of the corners of the bounding box
-        let mut xs: Vec<i32> = self.vertices.iter().map(|v| v.screen_coords.x as i32).collect();
-        let mut ys: Vec<i32> = self.vertices.iter().map(|v| v.screen_coords.y as i32).collect();
-        xs.sort_by(|a, b| a.cmp(b));
-        ys.sort_by(|a, b| a.cmp(b));
+    // Screen space derivatives of the texture coordinates - constant across the triangle
+    // since they're interpolated linearly in screen space
+    pub fn texture_derivatives(&self) -> (Vec2<f64>, Vec2<f64>) {
+        let tex = self.texture_coords();
+        let p0 = self.vertices[0].screen_coords;
+        let p1 = self.vertices[1].screen_coords;
+        let p2 = self.vertices[2].screen_coords;
+
+        let denom = (p1.x - p0.x) * (p2.y - p0.y) - (p2.x - p0.x) * (p1.y - p0.y);
+        if denom == 0.0 {
+            return (Vec2::new(0.0, 0.0), Vec2::new(0.0, 0.0));
+        }
+
+        let d_dx = |a0: f64, a1: f64, a2: f64| ((a1 - a0) * (p2.y - p0.y) - (a2 - a0) * (p1.y - p0.y)) / denom;
+        let d_dy = |a0: f64, a1: f64, a2: f64| ((a2 - a0) * (p1.x - p0.x) - (a1 - a0) * (p2.x - p0.x)) / denom;
+
+        (Vec2::new(d_dx(tex[0].x, tex[1].x, tex[2].x), d_dx(tex[0].y, tex[1].y, tex[2].y)),
+         Vec2::new(d_dy(tex[0].x, tex[1].x, tex[2].x), d_dy(tex[0].y, tex[1].y, tex[2].y)))
+    }
 
-        let (min_x, max_x) = (xs[0], xs[2]);
-        let (min_y, max_y) = (ys[0], ys[2]);
+    // Only used when every corner has a color
+    fn vertex_colors(&self) -> Option<[Color; 3]> {
+        match (self.vertices[0].color, self.vertices[1].color, self.vertices[2].color) {
+            (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+            _ => None,
+        }
+    }
 
-        vec![Vec2{x: min_x, y: min_y},
-             Vec2{x: min_x, y: max_y},
-             Vec2{x: max_x, y: max_y},
-             Vec2{x: max_x, y: min_y}]
+    // Normalized texture coordinates of each corner
+    fn texture_coords(&self) -> [Vec2<f64>; 3] {
+        let uv = |v: &Vertex| v.texture.unwrap_or(Vec2::new(0.0, 0.0));
+        [uv(&self.vertices[0]), uv(&self.vertices[1]), uv(&self.vertices[2])]
     }
 
-    pub fn clip_bounding_box(&self, bbox: Vec<Vec2<i32>>, image: &Image) -> Vec<Vec2<i32>> {
-        let mut result = Vec::with_capacity(4);
+    pub fn find_bounding_box(&self) -> [Vec2<i32>; 4] {
+        // Find coordinates of the corners of the bounding box
+        let xs = self.vertices.iter().map(|v| v.screen_coords.x as i32);
+        let ys = self.vertices.iter().map(|v| v.screen_coords.y as i32);
+        let (min_x, max_x) = (xs.clone().min().unwrap(), xs.max().unwrap());
+        let (min_y, max_y) = (ys.clone().min().unwrap(), ys.max().unwrap());
+
+        [Vec2{x: min_x, y: min_y},
+         Vec2{x: min_x, y: max_y},
+         Vec2{x: max_x, y: max_y},
+         Vec2{x: max_x, y: min_y}]
+    }
 
-        for i in bbox {
-            let clipped_bounds = Vec2{x: clip(i.x, 0, image.width),
-                                      y: clip(i.y, 0, image.height)};
-            result.push(clipped_bounds);
+    pub fn clip_bounding_box(&self, bbox: [Vec2<i32>; 4], image: &Image) -> [Vec2<i32>; 4] {
+        self.clip_bounding_box_to(bbox, &image.rect())
+    }
+
+    pub fn clip_bounding_box_to(&self, bbox: [Vec2<i32>; 4], rect: &Rect) -> [Vec2<i32>; 4] {
+        let mut result = bbox;
+
+        for i in result.iter_mut() {
+            *i = Vec2{x: clip(i.x, rect.x, rect.x + rect.width),
+                      y: clip(i.y, rect.y, rect.y + rect.height)};
         }
 
         result
@@ -368,6 +675,32 @@ pub fn new_barycentric(t0: &Vec2<i32>, t1: &Vec2<i32>, t2: &Vec2<i32>, p: &Vec2<
     }
 }
 
+fn shade_fragment(tex: &[Vec2<f64>; 3], colors: &Option<[Color; 3]>, grad: (Vec2<f64>, Vec2<f64>),
+                  bc: Vec3<f64>, color: Color, texture: &Image, sampler: &Sampler) -> Color {
+    let text_coords = Vec2 {
+        x: tex[0].x * bc.x + tex[1].x * bc.y + tex[2].x * bc.z,
+        y: tex[0].y * bc.x + tex[1].y * bc.y + tex[2].y * bc.z,
+    };
+
+    let mut texture_color = sampler.sample_grad(texture, text_coords, grad.0, grad.1);
+
+    // Vertex colors tint the texture, scans and the like often have nothing else
+    if let Some(ref c) = *colors {
+        let mix = |t: u8, a: u8, b: u8, c: u8| {
+            let v = a as f64 * bc.x + b as f64 * bc.y + c as f64 * bc.z;
+            (t as f64 * v / 255.0).round().max(0.0).min(255.0) as u8
+        };
+        texture_color = Color(mix(texture_color.0, c[0].0, c[1].0, c[2].0),
+                              mix(texture_color.1, c[0].1, c[1].1, c[2].1),
+                              mix(texture_color.2, c[0].2, c[1].2, c[2].2),
+                              mix(texture_color.3, c[0].3, c[1].3, c[2].3));
+    }
+
+    // The fragment is only as opaque as both the texture and the color
+    let alpha = texture_color.alpha() as u32 * color.alpha() as u32 / 255;
+    texture_color.with_alpha(alpha as u8)
+}
+
 fn clip(x: i32, min: i32, max: i32) -> i32 {
     if x < min {
         min
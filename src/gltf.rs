@@ -0,0 +1,921 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use byteorder::{ByteOrder, LittleEndian};
+
+use geo::{Vertex, Vec3, Triangle, Mat4, cross_product};
+use hdr::HdrColor;
+use image::{Image, read_image_file, decode_image};
+use json::{self, Json};
+use material::{Material, TextureMap};
+use error::{LoadError, read_file};
+use model::{Model, Part};
+use tonemap::ColorSpace;
+
+const GLB_MAGIC: &'static [u8] = b"glTF";
+const GLB_CHUNK_JSON: u32 = 0x4e4f534a;
+const GLB_CHUNK_BIN: u32 = 0x004e4942;
+
+// Primitive modes we can do something with, lines are skipped
+const MODE_POINTS: u32 = 0;
+const MODE_TRIANGLES: u32 = 4;
+const MODE_TRIANGLE_STRIP: u32 = 5;
+const MODE_TRIANGLE_FAN: u32 = 6;
+
+// Node hierarchies deeper than this are assumed to be cycles
+const MAX_NODE_DEPTH: usize = 64;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+pub fn read_gltf_file(filename: &str) -> Result<Model, LoadError> {
+    let data = try!(read_file(filename));
+    let parsed = if data.starts_with(GLB_MAGIC) {
+        read_glb(&data)
+    } else {
+        json::parse(&data).map(|json| (json, None))
+    };
+    let (json, bin) = try!(parsed.map_err(|e| LoadError::io(filename, e)));
+
+    let mut doc = Document {
+        json: json,
+        buffers: Vec::new(),
+        path: PathBuf::from(filename),
+    };
+    try!(doc.load_buffers(bin));
+    doc.build().map_err(|e| LoadError::io(filename, e))
+}
+
+// The binary container: a 12 byte header then chunks, JSON first and an optional buffer
+fn read_glb(data: &[u8]) -> Result<(Json, Option<Vec<u8>>), Error> {
+    if data.len() < 12 {
+        return Err(invalid("GLB header is truncated"));
+    }
+    let version = LittleEndian::read_u32(&data[4..8]);
+    if version != 2 {
+        return Err(invalid("Only glTF 2.0 is supported"));
+    }
+    let length = (LittleEndian::read_u32(&data[8..12]) as usize).min(data.len());
+
+    let mut json = None;
+    let mut bin = None;
+    let mut pos = 12;
+    while pos + 8 <= length {
+        let chunk_length = LittleEndian::read_u32(&data[pos..pos + 4]) as usize;
+        let chunk_type = LittleEndian::read_u32(&data[pos + 4..pos + 8]);
+        let start = pos + 8;
+        let end = match start.checked_add(chunk_length) {
+            Some(end) if end <= length => end,
+            _ => return Err(invalid("GLB chunk is truncated")),
+        };
+
+        match chunk_type {
+            GLB_CHUNK_JSON => json = Some(try!(json::parse(&data[start..end]))),
+            GLB_CHUNK_BIN if bin.is_none() => bin = Some(data[start..end].to_vec()),
+            _ => {},
+        }
+        // Chunks are padded to four bytes
+        pos = (end + 3) & !3;
+    }
+
+    match json {
+        Some(json) => Ok((json, bin)),
+        None => Err(invalid("GLB has no JSON chunk")),
+    }
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+        b'+' | b'-' => Some(62),
+        b'/' | b'_' => Some(63),
+        _ => None,
+    }
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+
+    for &c in text.as_bytes() {
+        if c == b'=' {
+            break;
+        }
+        if (c as char).is_whitespace() {
+            continue;
+        }
+        let value = try!(base64_value(c).ok_or_else(|| invalid("Bad base64 data")));
+        bits = (bits << 6) | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+
+    Ok(out)
+}
+
+// URIs can have escapes like %20 in them, which won't be in the filename
+fn decode_uri(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 3 <= bytes.len() {
+            ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            },
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// A number with a default for when it's missing
+fn number(json: &Json, default: f64) -> f64 {
+    json.as_f64().unwrap_or(default)
+}
+
+// Offsets and counts, which default to zero. Anything that isn't a whole number is an
+// error rather than something to round.
+fn size(json: &Json, name: &str) -> Result<usize, Error> {
+    if json.is_null() {
+        return Ok(0);
+    }
+    json.as_usize().ok_or_else(|| invalid(&format!("glTF {} must be a whole number", name)))
+}
+
+// Number of components in an accessor element
+fn component_count(kind: &str) -> Option<usize> {
+    match kind {
+        "SCALAR" => Some(1),
+        "VEC2" => Some(2),
+        "VEC3" => Some(3),
+        "VEC4" | "MAT2" => Some(4),
+        "MAT3" => Some(9),
+        "MAT4" => Some(16),
+        _ => None,
+    }
+}
+
+fn read_component(component_type: u32, normalized: bool, b: &[u8]) -> f64 {
+    // Normalized integers map onto 0 to 1, or -1 to 1 when signed
+    match component_type {
+        5120 => {
+            let v = b[0] as i8 as f64;
+            if normalized { (v / 127.0).max(-1.0) } else { v }
+        },
+        5121 => {
+            let v = b[0] as f64;
+            if normalized { v / 255.0 } else { v }
+        },
+        5122 => {
+            let v = LittleEndian::read_i16(b) as f64;
+            if normalized { (v / 32767.0).max(-1.0) } else { v }
+        },
+        5123 => {
+            let v = LittleEndian::read_u16(b) as f64;
+            if normalized { v / 65535.0 } else { v }
+        },
+        5125 => LittleEndian::read_u32(b) as f64,
+        _ => LittleEndian::read_f32(b) as f64,
+    }
+}
+
+// Replace the alpha of every texel
+fn map_alpha<F: Fn(u8) -> u8>(image: &mut Image, f: F) {
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let c = image.get_pixel(x, y);
+            image.set_pixel(x, y, c.with_alpha(f(c.alpha())));
+        }
+    }
+}
+
+fn component_size(component_type: u32) -> Option<usize> {
+    match component_type {
+        5120 | 5121 => Some(1),
+        5122 | 5123 => Some(2),
+        5125 | 5126 => Some(4),
+        _ => None,
+    }
+}
+
+fn node_transform(node: &Json) -> Mat4 {
+    if let Some(m) = node.get("matrix").as_f64_vec() {
+        if m.len() == 16 {
+            return Mat4::from_column_major(&m);
+        }
+    }
+
+    let t = node.get("translation").as_f64_vec().unwrap_or(vec![0.0, 0.0, 0.0]);
+    let r = node.get("rotation").as_f64_vec().unwrap_or(vec![0.0, 0.0, 0.0, 1.0]);
+    let s = node.get("scale").as_f64_vec().unwrap_or(vec![1.0, 1.0, 1.0]);
+    if t.len() != 3 || r.len() != 4 || s.len() != 3 {
+        return Mat4::identity();
+    }
+
+    Mat4::translation(Vec3::new(t[0], t[1], t[2])) *
+        Mat4::rotation(r[0], r[1], r[2], r[3]) *
+        Mat4::scale(Vec3::new(s[0], s[1], s[2]))
+}
+
+// Mirroring transforms turn the triangles inside out, so their winding has to be flipped
+fn is_mirrored(transform: &Mat4) -> bool {
+    let m = &transform.m;
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+              m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+              m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    det < 0.0
+}
+
+// Corners of the triangles in a primitive, as indices into its vertex attributes
+fn triangle_corners(mode: u32, indices: &[usize]) -> Vec<[usize; 3]> {
+    let mut triangles = Vec::new();
+    match mode {
+        MODE_TRIANGLES => {
+            for tri in indices.chunks(3).filter(|t| t.len() == 3) {
+                triangles.push([tri[0], tri[1], tri[2]]);
+            }
+        },
+        MODE_TRIANGLE_STRIP => {
+            // Every other triangle is wound the other way round
+            for i in 0..indices.len().saturating_sub(2) {
+                if i % 2 == 0 {
+                    triangles.push([indices[i], indices[i + 1], indices[i + 2]]);
+                } else {
+                    triangles.push([indices[i + 1], indices[i], indices[i + 2]]);
+                }
+            }
+        },
+        MODE_TRIANGLE_FAN => {
+            for i in 1..indices.len().saturating_sub(1) {
+                triangles.push([indices[0], indices[i], indices[i + 1]]);
+            }
+        },
+        _ => {},
+    }
+    triangles
+}
+
+// Tangents for every vertex of a primitive from its texture coordinates, for files that
+// don't have their own. Accumulated per triangle like smoothed normals, the bitangents
+// only matter for working out the handedness later.
+fn generate_tangents(positions: &[Vec3<f64>], uvs: &[Vec<f64>], triangles: &[[usize; 3]])
+                     -> Vec<(Vec3<f64>, Vec3<f64>)> {
+    let zero = Vec3::new(0.0, 0.0, 0.0);
+    let mut tangents = vec![(zero, zero); positions.len()];
+
+    for tri in triangles.iter() {
+        let (p0, p1, p2) = (positions[tri[0]], positions[tri[1]], positions[tri[2]]);
+        let (t0, t1, t2) = (&uvs[tri[0]], &uvs[tri[1]], &uvs[tri[2]]);
+        let (e1, e2) = (p1 - p0, p2 - p0);
+        let (du1, dv1) = (t1[0] - t0[0], t1[1] - t0[1]);
+        let (du2, dv2) = (t2[0] - t0[0], t2[1] - t0[1]);
+
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() < 1e-12 {
+            continue;
+        }
+        let r = 1.0 / det;
+        let tangent = (e1 * dv2 - e2 * dv1) * r;
+        let bitangent = (e2 * du1 - e1 * du2) * r;
+
+        for &i in tri.iter() {
+            tangents[i] = (tangents[i].0 + tangent, tangents[i].1 + bitangent);
+        }
+    }
+
+    tangents
+}
+
+struct Document {
+    json: Json,
+    buffers: Vec<Vec<u8>>,
+    // The .gltf or .glb file, external files are relative to it
+    path: PathBuf,
+}
+
+// Everything the scene adds up to, before it's turned into a model
+struct Scene {
+    faces: Vec<Triangle>,
+    face_materials: Vec<usize>,
+    face_parts: Vec<usize>,
+    parts: Vec<Part>,
+    points: Vec<Vertex>,
+}
+
+impl Document {
+    fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new(""))
+    }
+
+    // Embedded data URIs, external files, or the binary chunk of a GLB
+    fn load_buffers(&mut self, mut bin: Option<Vec<u8>>) -> Result<(), LoadError> {
+        let mut buffers = Vec::new();
+
+        for buffer in self.json.get("buffers").members() {
+            let data = match buffer.get("uri").as_str() {
+                Some(uri) => try!(self.load_uri(uri)),
+                None => try!(bin.take().ok_or_else(|| LoadError::io(&self.path, invalid("glTF buffer has no data")))),
+            };
+            let length = number(buffer.get("byteLength"), 0.0) as usize;
+            if data.len() < length {
+                return Err(LoadError::io(&self.path, invalid("glTF buffer is shorter than its byteLength")));
+            }
+            buffers.push(data);
+        }
+
+        self.buffers = buffers;
+        Ok(())
+    }
+
+    // Problems with embedded data are blamed on the model, external files on themselves
+    fn load_uri(&self, uri: &str) -> Result<Vec<u8>, LoadError> {
+        if uri.starts_with("data:") {
+            let data = match uri.find(";base64,") {
+                Some(i) => decode_base64(&uri[i + 8..]),
+                None => Err(invalid("Only base64 data URIs are supported")),
+            };
+            return data.map_err(|e| LoadError::io(&self.path, e));
+        }
+
+        read_file(self.dir().join(decode_uri(uri)).to_str().unwrap_or(""))
+    }
+
+    // The bytes of a buffer view, and the stride between its elements if it has one
+    fn buffer_view(&self, index: usize) -> Result<(&[u8], Option<usize>), Error> {
+        let view = self.json.get("bufferViews").at(index);
+        let buffer = try!(view.get("buffer").as_usize()
+                          .and_then(|b| self.buffers.get(b))
+                          .ok_or_else(|| invalid("glTF buffer view refers to a missing buffer")));
+        let offset = try!(size(view.get("byteOffset"), "byteOffset"));
+        let length = try!(view.get("byteLength").as_usize().ok_or_else(|| invalid("glTF buffer view has no length")));
+        match offset.checked_add(length) {
+            Some(end) if end <= buffer.len() => Ok((&buffer[offset..end], view.get("byteStride").as_usize())),
+            _ => Err(invalid("glTF buffer view is out of bounds")),
+        }
+    }
+
+    // Every element of an accessor, converted to floats. Sparse accessors start out as their
+    // buffer view, or zeros without one, then have the elements they list replaced.
+    fn accessor(&self, index: usize) -> Result<Vec<Vec<f64>>, Error> {
+        let accessor = self.json.get("accessors").at(index);
+        if accessor.is_null() {
+            return Err(invalid("glTF refers to a missing accessor"));
+        }
+        let count = try!(size(accessor.get("count"), "count"));
+        let components = try!(accessor.get("type").as_str().and_then(component_count)
+                              .ok_or_else(|| invalid("Unknown glTF accessor type")));
+        let component_type = number(accessor.get("componentType"), 0.0) as u32;
+        let normalized = accessor.get("normalized").as_bool().unwrap_or(false);
+
+        let mut elements = match accessor.get("bufferView").as_usize() {
+            Some(view) => try!(self.read_elements(view, try!(size(accessor.get("byteOffset"), "byteOffset")),
+                                                  count, components, component_type, normalized)),
+            None => {
+                // Nothing bounds the count without a buffer view. Real files with accessors
+                // like this carry other data at least as big, so hold it to the buffers.
+                if count > self.buffers.iter().map(|b| b.len()).sum() {
+                    return Err(invalid("glTF accessor is longer than its buffers"));
+                }
+                vec![vec![0.0; components]; count]
+            },
+        };
+
+        let sparse = accessor.get("sparse");
+        if !sparse.is_null() {
+            let sparse_count = try!(size(sparse.get("count"), "count"));
+            let indices = sparse.get("indices");
+            let values = sparse.get("values");
+            let (index_view, value_view) = match (indices.get("bufferView").as_usize(), values.get("bufferView").as_usize()) {
+                (Some(i), Some(v)) => (i, v),
+                _ => return Err(invalid("glTF sparse accessor is missing a buffer view")),
+            };
+
+            let index_type = number(indices.get("componentType"), 0.0) as u32;
+            // Unsigned byte, short or int
+            if ![5121, 5123, 5125].contains(&index_type) {
+                return Err(invalid("glTF sparse indices must be unsigned integers"));
+            }
+            let targets = try!(self.read_elements(index_view, try!(size(indices.get("byteOffset"), "byteOffset")),
+                                                  sparse_count, 1, index_type, false));
+            let replacements = try!(self.read_elements(value_view, try!(size(values.get("byteOffset"), "byteOffset")),
+                                                       sparse_count, components, component_type, normalized));
+
+            for (target, value) in targets.iter().zip(replacements.into_iter()) {
+                match elements.get_mut(target[0] as usize) {
+                    Some(element) => *element = value,
+                    None => return Err(invalid("glTF sparse index is out of bounds")),
+                }
+            }
+        }
+
+        Ok(elements)
+    }
+
+    // count elements of a buffer view starting at offset, tightly packed unless the view
+    // has a stride
+    fn read_elements(&self, view: usize, offset: usize, count: usize, components: usize, component_type: u32,
+                     normalized: bool) -> Result<Vec<Vec<f64>>, Error> {
+        let size = try!(component_size(component_type).ok_or_else(|| invalid("Unknown glTF component type")));
+        let (data, stride) = try!(self.buffer_view(view));
+        let element_size = components * size;
+        let stride = stride.unwrap_or(element_size);
+        // Overlapping elements would let a huge count fit in a few bytes
+        if stride < element_size {
+            return Err(invalid("glTF buffer view stride is smaller than its elements"));
+        }
+
+        // Counts and offsets come straight from the file, so check the end doesn't overflow
+        let end = count.checked_sub(1)
+            .and_then(|last| last.checked_mul(stride))
+            .and_then(|n| n.checked_add(offset))
+            .and_then(|n| n.checked_add(element_size));
+        match end {
+            Some(end) if end > data.len() => return Err(invalid("glTF accessor is out of bounds")),
+            None if count > 0 => return Err(invalid("glTF accessor is out of bounds")),
+            _ => {},
+        }
+
+        let mut elements = Vec::with_capacity(count);
+        for i in 0..count {
+            let start = offset + i * stride;
+            elements.push((0..components)
+                          .map(|c| read_component(component_type, normalized, &data[start + c * size..]))
+                          .collect());
+        }
+        Ok(elements)
+    }
+
+    // An attribute of a primitive, whose elements have to be one of the given sizes
+    fn attribute(&self, primitive: &Json, name: &str, components: &[usize]) -> Result<Option<Vec<Vec<f64>>>, Error> {
+        let values = match primitive.get("attributes").get(name).as_usize() {
+            Some(index) => try!(self.accessor(index)),
+            None => return Ok(None),
+        };
+        if values.first().map_or(false, |v| !components.contains(&v.len())) {
+            return Err(invalid(&format!("glTF {} attribute has the wrong type", name)));
+        }
+        Ok(Some(values))
+    }
+
+    // Images come from a file next to the model, a data URI, or a buffer view
+    fn load_image(&self, index: usize) -> Result<(PathBuf, Image), LoadError> {
+        let image = self.json.get("images").at(index);
+
+        if let Some(uri) = image.get("uri").as_str() {
+            if !uri.starts_with("data:") {
+                let path = self.dir().join(decode_uri(uri));
+                let loaded = try!(read_image_file(path.to_str().unwrap_or("")));
+                return Ok((path, loaded));
+            }
+        }
+
+        let embedded = match (image.get("uri").as_str(), image.get("bufferView").as_usize()) {
+            (Some(uri), _) => decode_image(&try!(self.load_uri(uri))),
+            (None, Some(view)) => self.buffer_view(view).and_then(|(data, _)| decode_image(data)),
+            (None, None) => Err(invalid("glTF image has no data")),
+        };
+        embedded.map(|loaded| (self.path.clone(), loaded)).map_err(|e| LoadError::io(&self.path, e))
+    }
+
+    // A textureInfo object from a material, None if it's missing or can't be loaded
+    fn load_texture(&self, info: &Json, warnings: &mut Vec<LoadError>) -> Option<TextureMap> {
+        let source = info.get("index").as_usize()
+            .and_then(|t| self.json.get("textures").at(t).get("source").as_usize());
+        let source = match source {
+            Some(source) => source,
+            None => return None,
+        };
+
+        match self.load_image(source) {
+            Ok((path, image)) => Some(TextureMap { path: path, image: image }),
+            Err(e) => {
+                warnings.push(e);
+                None
+            }
+        }
+    }
+
+    fn material(&self, index: usize, warnings: &mut Vec<LoadError>) -> Material {
+        let json = self.json.get("materials").at(index);
+        let name = json.get("name").as_str().map(|n| n.to_string()).unwrap_or(format!("material{}", index));
+        let mut material = Material::new(&name);
+        let pbr = json.get("pbrMetallicRoughness");
+
+        let base = pbr.get("baseColorFactor").as_f64_vec().unwrap_or(vec![1.0, 1.0, 1.0, 1.0]);
+        if base.len() == 4 {
+            material.diffuse = HdrColor::new(base[0] as f32, base[1] as f32, base[2] as f32, 1.0);
+        }
+        material.metallic = number(pbr.get("metallicFactor"), 1.0);
+        material.roughness = number(pbr.get("roughnessFactor"), 1.0);
+        let emissive = json.get("emissiveFactor").as_f64_vec().unwrap_or(vec![0.0, 0.0, 0.0]);
+        if emissive.len() == 3 {
+            material.emissive = HdrColor::new(emissive[0] as f32, emissive[1] as f32, emissive[2] as f32, 1.0);
+        }
+
+        material.diffuse_map = self.load_texture(pbr.get("baseColorTexture"), warnings);
+        material.metallic_roughness_map = self.load_texture(pbr.get("metallicRoughnessTexture"), warnings);
+        material.normal_map = self.load_texture(json.get("normalTexture"), warnings);
+        material.occlusion_map = self.load_texture(json.get("occlusionTexture"), warnings);
+        material.emissive_map = self.load_texture(json.get("emissiveTexture"), warnings);
+
+        // Alpha only counts when blending or masking, opaque materials ignore whatever the
+        // texture has. Masked ones are cut out where the alpha is below the cutoff.
+        let base_alpha = base.get(3).cloned().unwrap_or(1.0);
+        match json.get("alphaMode").as_str() {
+            Some("BLEND") => material.dissolve = base_alpha,
+            Some("MASK") => {
+                let cutoff = number(json.get("alphaCutoff"), 0.5);
+                let cut = |a: f64| if a * base_alpha >= cutoff { 255 } else { 0 };
+                match material.diffuse_map {
+                    Some(ref mut map) => map_alpha(&mut map.image, |a| cut(a as f64 / 255.0)),
+                    None => material.dissolve = cut(1.0) as f64 / 255.0,
+                }
+            },
+            _ => {
+                if let Some(ref mut map) = material.diffuse_map {
+                    map_alpha(&mut map.image, |_| 255);
+                }
+            },
+        }
+
+        material.build_texture();
+        material
+    }
+
+    fn build(&self) -> Result<Model, Error> {
+        let mut scene = Scene {
+            faces: Vec::new(),
+            face_materials: Vec::new(),
+            face_parts: Vec::new(),
+            parts: vec![Part { name: "default".to_string(), object: String::new(), visible: true }],
+            points: Vec::new(),
+        };
+
+        for root in self.roots() {
+            try!(self.add_node(&mut scene, root, Mat4::identity(), 0));
+        }
+
+        // Material 0 is the default, for primitives without one
+        let mut materials = vec![Material::new("default")];
+        let mut warnings = Vec::new();
+        for i in 0..self.json.get("materials").members().len() {
+            materials.push(self.material(i, &mut warnings));
+        }
+
+        let mut model = Model::from_faces(scene.faces);
+        model.face_materials = scene.face_materials;
+        model.materials = materials;
+        model.face_parts = scene.face_parts;
+        model.parts = scene.parts;
+        model.points = scene.points;
+        model.warnings = warnings;
+        Ok(model)
+    }
+
+    // The nodes of the default scene, or every node that isn't a child if there are no scenes
+    fn roots(&self) -> Vec<usize> {
+        let scenes = self.json.get("scenes");
+        if !scenes.members().is_empty() {
+            let scene = self.json.get("scene").as_usize().unwrap_or(0);
+            return scenes.at(scene).get("nodes").members().iter().filter_map(|n| n.as_usize()).collect();
+        }
+
+        let nodes = self.json.get("nodes").members();
+        let children: Vec<usize> = nodes.iter()
+            .flat_map(|n| n.get("children").members().iter().filter_map(|c| c.as_usize()))
+            .collect();
+        (0..nodes.len()).filter(|i| !children.contains(i)).collect()
+    }
+
+    fn add_node(&self, scene: &mut Scene, index: usize, parent: Mat4, depth: usize) -> Result<(), Error> {
+        if depth > MAX_NODE_DEPTH {
+            return Err(invalid("glTF node hierarchy is too deep"));
+        }
+        let node = self.json.get("nodes").at(index);
+        let transform = parent * node_transform(node);
+
+        if let Some(mesh_index) = node.get("mesh").as_usize() {
+            let mesh = self.json.get("meshes").at(mesh_index);
+            let mesh_name = mesh.get("name").as_str().map(|n| n.to_string()).unwrap_or(format!("mesh{}", mesh_index));
+            let name = node.get("name").as_str().map(|n| n.to_string()).unwrap_or(format!("node{}", index));
+            scene.parts.push(Part { name: name, object: mesh_name, visible: true });
+            let part = scene.parts.len() - 1;
+
+            for primitive in mesh.get("primitives").members() {
+                try!(self.add_primitive(scene, primitive, &transform, part));
+            }
+        }
+
+        for child in node.get("children").members() {
+            if let Some(child) = child.as_usize() {
+                try!(self.add_node(scene, child, transform, depth + 1));
+            }
+        }
+        Ok(())
+    }
+
+    fn add_primitive(&self, scene: &mut Scene, primitive: &Json, transform: &Mat4, part: usize) -> Result<(), Error> {
+        let positions: Vec<Vec3<f64>> = match try!(self.attribute(primitive, "POSITION", &[3])) {
+            Some(p) => p.into_iter().map(Vec3::from_vec).collect(),
+            None => return Ok(()),
+        };
+        let normals = try!(self.attribute(primitive, "NORMAL", &[3]));
+        let uvs = try!(self.attribute(primitive, "TEXCOORD_0", &[2]));
+        let tangents = try!(self.attribute(primitive, "TANGENT", &[4]));
+        let colors = try!(self.attribute(primitive, "COLOR_0", &[3, 4]));
+
+        // Everything is indexed the same way as the positions
+        for &(name, ref values) in [("NORMAL", &normals), ("TEXCOORD_0", &uvs), ("TANGENT", &tangents),
+                                    ("COLOR_0", &colors)].iter() {
+            if values.as_ref().map_or(false, |v| v.len() != positions.len()) {
+                return Err(invalid(&format!("glTF {} attribute doesn't have one element per vertex", name)));
+            }
+        }
+
+        let indices: Vec<usize> = match primitive.get("indices").as_usize() {
+            Some(i) => try!(self.accessor(i)).iter().map(|e| e[0] as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+        if indices.iter().any(|&i| i >= positions.len()) {
+            return Err(invalid("glTF primitive refers to a missing vertex"));
+        }
+
+        let mode = number(primitive.get("mode"), MODE_TRIANGLES as f64) as u32;
+        let triangles = triangle_corners(mode, &indices);
+
+        let generated = match (&tangents, &uvs) {
+            (&None, &Some(ref uvs)) => Some(generate_tangents(&positions, uvs, &triangles)),
+            _ => None,
+        };
+
+        let mirrored = is_mirrored(transform);
+        let vertex = |i: usize| {
+            let mut v = Vertex::new(transform.transform_point(positions[i]));
+            if let Some(ref normals) = normals {
+                v.set_normal(transform.transform_normal(Vec3::from_vec(normals[i].clone())));
+            }
+            // glTF puts the origin of the texture at the top left
+            if let Some(ref uvs) = uvs {
+                v.set_texture(vec![uvs[i][0], 1.0 - uvs[i][1]]);
+            }
+            if let Some(ref tangents) = tangents {
+                let t = &tangents[i];
+                let w = t.get(3).cloned().unwrap_or(1.0) * if mirrored { -1.0 } else { 1.0 };
+                v.set_tangent(transform.transform_vector(Vec3::new(t[0], t[1], t[2])).normalize(), w);
+            }
+            // Colors are linear, and RGB or RGBA
+            if let Some(ref colors) = colors {
+                let c = &colors[i];
+                let a = c.get(3).cloned().unwrap_or(1.0);
+                v.set_color(ColorSpace::Srgb.encode(HdrColor::new(c[0] as f32, c[1] as f32, c[2] as f32, a as f32)));
+            }
+            v
+        };
+
+        if mode == MODE_POINTS {
+            for &i in indices.iter() {
+                scene.points.push(vertex(i));
+            }
+            return Ok(());
+        }
+
+        let material = primitive.get("material").as_usize().map_or(0, |m| m + 1);
+
+        for tri in triangles.iter() {
+            let corners = if mirrored { [tri[0], tri[2], tri[1]] } else { *tri };
+            let mut face = Triangle::new([vertex(corners[0]), vertex(corners[1]), vertex(corners[2])]);
+
+            // Without normals the primitive is meant to look flat
+            if normals.is_none() {
+                let n = face.surface_normal();
+                for v in face.vertices.iter_mut() {
+                    v.set_normal(n);
+                }
+            }
+
+            // Generated tangents are made perpendicular to the normal, the bitangent
+            // decides which way round they go
+            if let Some(ref generated) = generated {
+                for (v, &i) in face.vertices.iter_mut().zip(corners.iter()) {
+                    let (t, b) = generated[i];
+                    let (t, b) = (transform.transform_vector(t), transform.transform_vector(b));
+                    let n = v.normal().unwrap();
+                    let t = t - n * (n * t);
+                    if t * t > 1e-24 {
+                        let w = if cross_product(n, t) * b < 0.0 { -1.0 } else { 1.0 };
+                        v.set_tangent(t.normalize(), w);
+                    }
+                }
+            }
+
+            scene.faces.push(face);
+            scene.face_materials.push(material);
+            scene.face_parts.push(part);
+        }
+
+        scene.points.extend(positions.iter().enumerate().map(|(i, _)| vertex(i)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use byteorder::WriteBytesExt;
+    use testutil::TempFile;
+
+    fn encode_base64(data: &[u8]) -> String {
+        let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for chunk in data.chunks(3) {
+            let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+            let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+            for i in 0..4 {
+                out.push(if i <= chunk.len() { alphabet[(n >> (18 - i * 6)) & 63] as char } else { '=' });
+            }
+        }
+        out
+    }
+
+    // A unit quad: positions, texture coordinates and six u16 indices
+    fn quad_buffer() -> Vec<u8> {
+        let mut data = Vec::new();
+        for &v in [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0,
+                   0.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0].iter() {
+            data.write_f32::<LittleEndian>(v).unwrap();
+        }
+        for &i in [0, 1, 2, 0, 2, 3].iter() {
+            data.write_u16::<LittleEndian>(i).unwrap();
+        }
+        data
+    }
+
+    fn quad_json(uri: Option<&str>, accessors: &str) -> String {
+        let uri = uri.map_or(String::new(), |u| format!(", \"uri\": \"{}\"", u));
+        format!(r#"{{"asset": {{"version": "2.0"}},
+            "buffers": [{{"byteLength": 92{}}}],
+            "bufferViews": [{{"buffer": 0, "byteOffset": 0, "byteLength": 48}},
+                            {{"buffer": 0, "byteOffset": 48, "byteLength": 32}},
+                            {{"buffer": 0, "byteOffset": 80, "byteLength": 12}}],
+            "accessors": [{}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0, "TEXCOORD_0": 1}}, "indices": 2}}]}}],
+            "nodes": [{{"mesh": 0, "translation": [0, 0, 2]}}],
+            "scenes": [{{"nodes": [0]}}], "scene": 0}}"#, uri, accessors)
+    }
+
+    const QUAD_ACCESSORS: &'static str = r#"
+        {"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3"},
+        {"bufferView": 1, "componentType": 5126, "count": 4, "type": "VEC2"},
+        {"bufferView": 2, "componentType": 5123, "count": 6, "type": "SCALAR"}"#;
+
+    fn glb(json: &str, bin: &[u8]) -> Vec<u8> {
+        let mut json = json.as_bytes().to_vec();
+        while json.len() % 4 != 0 {
+            json.push(b' ');
+        }
+        let mut data = GLB_MAGIC.to_vec();
+        data.write_u32::<LittleEndian>(2).unwrap();
+        data.write_u32::<LittleEndian>((12 + 8 + json.len() + 8 + bin.len()) as u32).unwrap();
+        data.write_u32::<LittleEndian>(json.len() as u32).unwrap();
+        data.write_u32::<LittleEndian>(GLB_CHUNK_JSON).unwrap();
+        data.extend(json);
+        data.write_u32::<LittleEndian>(bin.len() as u32).unwrap();
+        data.write_u32::<LittleEndian>(GLB_CHUNK_BIN).unwrap();
+        data.extend(bin);
+        data
+    }
+
+    fn load(name: &str, data: &[u8]) -> Result<Model, LoadError> {
+        let file = TempFile::new(name);
+        fs::write(&file.path, data).unwrap();
+        read_gltf_file(file.name())
+    }
+
+    #[test]
+    fn embedded_and_binary() {
+        let uri = format!("data:application/octet-stream;base64,{}", encode_base64(&quad_buffer()));
+        let gltf = load("embedded.gltf", quad_json(Some(&uri), QUAD_ACCESSORS).as_bytes()).unwrap();
+        let binary = load("binary.glb", &glb(&quad_json(None, QUAD_ACCESSORS), &quad_buffer())).unwrap();
+
+        for model in [&gltf, &binary].iter() {
+            assert_eq!(model.mesh.triangle_count(), 2);
+            for i in 0..model.mesh.triangle_count() {
+                for v in model.mesh.triangle(i).vertices.iter() {
+                    // The node moves it along z, and the texture follows the corner
+                    assert_eq!(v.coords.z, 2.0);
+                    let t = v.texture().unwrap();
+                    assert_eq!((t.x, t.y), (v.coords.x, v.coords.y));
+                }
+            }
+        }
+        assert_eq!(gltf.mesh.positions.len(), binary.mesh.positions.len());
+        assert_eq!(gltf.mesh.indices, binary.mesh.indices);
+    }
+
+    #[test]
+    fn sparse_accessor() {
+        // Corners 1 and 2 of the positions are replaced. Without indices the first three
+        // corners make a triangle.
+        // The indices go where the quad's indices were
+        let mut buffer = quad_buffer();
+        buffer.truncate(80);
+        for &i in [1u16, 2].iter() {
+            buffer.write_u16::<LittleEndian>(i).unwrap();
+        }
+        for &v in [1.0, 5.0, 0.0, 1.0, 6.0, 0.0].iter() {
+            buffer.write_f32::<LittleEndian>(v).unwrap();
+        }
+        let json = r#"{"asset": {"version": "2.0"},
+            "buffers": [{"byteLength": 108}],
+            "bufferViews": [{"buffer": 0, "byteOffset": 0, "byteLength": 48},
+                            {"buffer": 0, "byteOffset": 80, "byteLength": 4},
+                            {"buffer": 0, "byteOffset": 84, "byteLength": 24}],
+            "accessors": [{"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3",
+                           "sparse": {"count": 2, "indices": {"bufferView": 1, "componentType": 5123},
+                                      "values": {"bufferView": 2}}}],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}}]}],
+            "nodes": [{"mesh": 0}],
+            "scenes": [{"nodes": [0]}], "scene": 0}"#;
+        let model = load("sparse.glb", &glb(json, &buffer)).unwrap();
+
+        let positions: Vec<(f64, f64)> = model.mesh.positions.iter().map(|p| (p.x, p.y)).collect();
+        for p in [(1.0, 5.0), (1.0, 6.0), (0.0, 0.0)].iter() {
+            assert!(positions.contains(p), "{:?} in {:?}", p, positions);
+        }
+        assert!(!positions.contains(&(1.0, 0.0)));
+
+        // The second index points past the end of the accessor
+        buffer[82] = 9;
+        assert!(load("sparse_bad.glb", &glb(json, &buffer)).is_err());
+
+        // Without a buffer view the count isn't held to anything in the file
+        let json = json.replace(r#""bufferView": 0, "componentType": 5126, "count": 4"#,
+                                r#""componentType": 5126, "count": 1e18"#);
+        assert!(load("sparse_huge.glb", &glb(&json, &buffer)).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        let buffer = quad_buffer();
+        // Accessors reaching past the end of their buffer view
+        let json = quad_json(None, &QUAD_ACCESSORS.replace(r#""count": 6"#, r#""count": 600"#));
+        assert!(load("long_accessor.glb", &glb(&json, &buffer)).is_err());
+
+        let data = glb(&quad_json(None, QUAD_ACCESSORS), &buffer);
+        assert!(load("truncated.glb", &data[..data.len() - 20]).is_err());
+        assert!(load("not_json.gltf", b"{\"asset\": ").is_err());
+        assert!(load("bad_base64.gltf", quad_json(Some("data:application/octet-stream;base64,@@@@"), QUAD_ACCESSORS).as_bytes()).is_err());
+
+        // Attributes with fewer elements than there are positions
+        let accessors = format!(r#"{},
+            {{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"}}"#, QUAD_ACCESSORS);
+        let json = quad_json(None, &accessors).replace(r#""TEXCOORD_0": 1}"#, r#""TEXCOORD_0": 1, "NORMAL": 3}"#);
+        assert!(load("short_normals.glb", &glb(&json, &buffer)).is_err());
+        let json = quad_json(None, &QUAD_ACCESSORS.replace(r#""count": 4, "type": "VEC2""#, r#""count": 3, "type": "VEC2""#));
+        assert!(load("short_uvs.glb", &glb(&json, &buffer)).is_err());
+
+        // Attributes with the wrong number of components
+        let json = quad_json(None, &QUAD_ACCESSORS.replace(r#""type": "VEC2""#, r#""type": "SCALAR""#));
+        assert!(load("scalar_uvs.glb", &glb(&json, &buffer)).is_err());
+        let json = quad_json(None, &QUAD_ACCESSORS.replace(r#""count": 4, "type": "VEC3""#, r#""count": 4, "type": "VEC2""#));
+        assert!(load("flat_positions.glb", &glb(&json, &buffer)).is_err());
+    }
+
+    #[test]
+    fn huge_sizes() {
+        let buffer = quad_buffer();
+        let json = quad_json(None, QUAD_ACCESSORS).replace(r#""byteOffset": 48"#, r#""byteOffset": 1e30"#);
+        assert!(load("view_offset.glb", &glb(&json, &buffer)).is_err());
+
+        let huge = [(r#""bufferView": 0,"#, r#""bufferView": 0, "byteOffset": 1e30,"#),
+                    (r#""count": 6"#, r#""count": 1e18"#),
+                    (r#""count": 6"#, r#""count": 5.5"#),
+                    (r#""bufferView": 2,"#, r#""bufferView": 2, "byteOffset": -2,"#)];
+        for &(from, to) in huge.iter() {
+            let json = quad_json(None, &QUAD_ACCESSORS.replace(from, to));
+            assert!(load("accessor_size.glb", &glb(&json, &buffer)).is_err(), "{}", to);
+        }
+
+        // Elements overlapping each other
+        let json = quad_json(None, &QUAD_ACCESSORS.replace(r#""count": 6"#, r#""count": 1e18"#))
+            .replace(r#""byteOffset": 80, "byteLength": 12"#, r#""byteOffset": 80, "byteLength": 12, "byteStride": 0"#);
+        assert!(load("zero_stride.glb", &glb(&json, &buffer)).is_err());
+    }
+}
@@ -0,0 +1,455 @@
+use image::{Image, Color};
+use geo::Rect;
+use exr::{self, ExrCompression};
+use tonemap::ToneMapper;
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::io::{Error, ErrorKind};
+use error::{LoadError, read_file};
+
+// Linear floating point color, not limited to 0 to 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HdrColor {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl HdrColor {
+    pub fn new(r: f32, g: f32, b: f32, a: f32) -> Self {
+        HdrColor {
+            r: r,
+            g: g,
+            b: b,
+            a: a,
+        }
+    }
+
+    pub fn black() -> Self {
+        HdrColor::new(0.0, 0.0, 0.0, 1.0)
+    }
+
+    // Straight conversion, 255 maps to 1.0
+    pub fn from_color(c: Color) -> Self {
+        HdrColor::new(c.2 as f32 / 255.0, c.1 as f32 / 255.0, c.0 as f32 / 255.0, c.3 as f32 / 255.0)
+    }
+
+    // Anything outside of 0 to 1 gets clipped
+    pub fn to_color(self) -> Color {
+        let ch = |v: f32| (v.max(0.0).min(1.0) * 255.0).round() as u8;
+        Color(ch(self.b), ch(self.g), ch(self.r), ch(self.a))
+    }
+
+    pub fn scale(self, s: f32) -> HdrColor {
+        HdrColor::new(self.r * s, self.g * s, self.b * s, self.a)
+    }
+
+    pub fn to_unit(self) -> [f64; 4] {
+        [self.r as f64, self.g as f64, self.b as f64, self.a as f64]
+    }
+
+    pub fn from_unit(v: [f64; 4]) -> HdrColor {
+        HdrColor::new(v[0] as f32, v[1] as f32, v[2] as f32, v[3] as f32)
+    }
+}
+
+// Float framebuffer for accumulating light without clipping. Rows are stored bottom first,
+// same as Image. It has its own zbuffer so models can be drawn straight into it.
+pub struct HdrImage {
+    pub width: i32,
+    pub height: i32,
+    data: Vec<HdrColor>,
+    zbuffer: Vec<i32>,
+}
+
+impl HdrImage {
+    pub fn new(width: i32, height: i32) -> Self {
+        HdrImage {
+            width: width,
+            height: height,
+            data: vec![HdrColor::black(); (width * height) as usize],
+            zbuffer: vec![i32::min_value(); (width * height) as usize],
+        }
+    }
+
+    pub fn from_image(image: &Image) -> Self {
+        let mut hdr = HdrImage::new(image.width, image.height);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                hdr.set_pixel(x, y, HdrColor::from_color(image.get_pixel(x, y)));
+            }
+        }
+        hdr
+    }
+
+    // Anything outside of 0 to 1 is clipped
+    pub fn to_image(&self) -> Image {
+        let mut image = Image::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                image.set_pixel(x, y, self.get_pixel(x, y).to_color());
+            }
+        }
+        image
+    }
+
+    // For display - exposure, tone curve and sRGB encoding all come from the mapper
+    pub fn tonemap(&self, mapper: &ToneMapper) -> Image {
+        mapper.apply(self)
+    }
+
+    pub fn rect(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    fn in_bounds(&self, x: i32, y: i32) -> bool {
+        !(x < 0 || y < 0 || x >= self.width || y >= self.height)
+    }
+
+    pub fn set_pixel(&mut self, x: i32, y: i32, c: HdrColor) {
+        if self.in_bounds(x, y) {
+            self.data[((y * self.width) + x) as usize] = c;
+        }
+    }
+
+    pub fn get_pixel(&self, x: i32, y: i32) -> HdrColor {
+        self.data[((y * self.width) + x) as usize]
+    }
+
+    // Accumulate light into a pixel, the alpha is left alone
+    pub fn add_pixel(&mut self, x: i32, y: i32, c: HdrColor) {
+        if self.in_bounds(x, y) {
+            let p = &mut self.data[((y * self.width) + x) as usize];
+            p.r += c.r;
+            p.g += c.g;
+            p.b += c.b;
+        }
+    }
+
+    // Add a whole image in, weighted - handy for averaging passes together
+    pub fn accumulate(&mut self, other: &HdrImage, weight: f32) {
+        for y in 0..self.height.min(other.height) {
+            for x in 0..self.width.min(other.width) {
+                self.add_pixel(x, y, other.get_pixel(x, y).scale(weight));
+            }
+        }
+    }
+
+    pub fn set_depth(&mut self, x: i32, y: i32, d: f64) {
+        if self.in_bounds(x, y) {
+            self.zbuffer[((y * self.width) + x) as usize] = d as i32;
+        }
+    }
+
+    pub fn get_depth(&self, x: i32, y: i32) -> i32 {
+        self.zbuffer[((y * self.width) + x) as usize]
+    }
+
+    pub fn write_hdr_file(&self, filename: &str) -> Result<(), Error> {
+        write_hdr_file(self, filename)
+    }
+
+    pub fn write_exr_file(&self, filename: &str, compression: ExrCompression) -> Result<(), Error> {
+        exr::write_exr_file(self, filename, compression)
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+// Radiance shares one exponent between the three channels
+fn to_rgbe(c: HdrColor) -> [u8; 4] {
+    let v = c.r.max(c.g).max(c.b);
+    if v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+
+    // Split v into a mantissa in [0.5, 1) and an exponent, like C's frexp
+    let mut e = v.log2().floor() as i32 + 1;
+    let mut m = v / 2f32.powi(e);
+    if m >= 1.0 {
+        m /= 2.0;
+        e += 1;
+    }
+
+    let scale = m * 256.0 / v;
+    let ch = |x: f32| (x.max(0.0) * scale) as u8;
+    [ch(c.r), ch(c.g), ch(c.b), (e + 128) as u8]
+}
+
+fn from_rgbe(rgbe: &[u8]) -> HdrColor {
+    if rgbe[3] == 0 {
+        return HdrColor::black();
+    }
+    let f = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    HdrColor::new((rgbe[0] as f32 + 0.5) * f, (rgbe[1] as f32 + 0.5) * f, (rgbe[2] as f32 + 0.5) * f, 1.0)
+}
+
+pub fn read_hdr_file(filename: &str) -> Result<HdrImage, LoadError> {
+    let data = try!(read_file(filename));
+    decode_hdr(&data).map_err(|e| LoadError::io(filename, e))
+}
+
+pub fn decode_hdr(data: &[u8]) -> Result<HdrImage, Error> {
+    // The header is text lines, ending with a blank line and then the resolution line
+    let mut pos = 0;
+    let next_line = |pos: &mut usize| -> Option<String> {
+        if *pos >= data.len() {
+            return None;
+        }
+        let start = *pos;
+        while *pos < data.len() && data[*pos] != b'\n' {
+            *pos += 1;
+        }
+        let line = String::from_utf8_lossy(&data[start..*pos]).into_owned();
+        *pos += 1;
+        Some(line)
+    };
+
+    match next_line(&mut pos) {
+        Some(ref magic) if magic.starts_with("#?") => {},
+        _ => return Err(invalid("Not a Radiance HDR file")),
+    }
+
+    loop {
+        match next_line(&mut pos) {
+            Some(ref line) if line.is_empty() => break,
+            Some(ref line) if line.starts_with("FORMAT=") && line.trim() != "FORMAT=32-bit_rle_rgbe" => {
+                return Err(invalid("Only RGBE Radiance files are supported"));
+            },
+            Some(_) => {},
+            None => return Err(invalid("Radiance header never ends")),
+        }
+    }
+
+    let resolution = next_line(&mut pos).unwrap_or_default();
+    let parts: Vec<&str> = resolution.split_whitespace().collect();
+    if parts.len() != 4 || parts[2] != "+X" || (parts[0] != "-Y" && parts[0] != "+Y") {
+        return Err(invalid("Unsupported Radiance resolution line"));
+    }
+    let height = try!(parts[1].parse::<i32>().map_err(|_| invalid("Bad Radiance height")));
+    let width = try!(parts[3].parse::<i32>().map_err(|_| invalid("Bad Radiance width")));
+    // -Y means the first scanline is the top one
+    let top_down = parts[0] == "-Y";
+
+    // New style runs pack at most 127 pixels into two bytes per component. Old style runs
+    // can do better, but nothing writes whole images that way, so a header claiming more
+    // pixels than that is treated as bogus rather than allocated for.
+    if width <= 0 || height <= 0 {
+        return Err(invalid("Radiance image has no pixels"));
+    }
+    match (width as usize).checked_mul(height as usize) {
+        Some(pixels) if pixels <= i32::max_value() as usize && pixels / 16 <= data.len().saturating_sub(pos) => {},
+        _ => return Err(invalid("Radiance dimensions are too large for the data")),
+    }
+
+    let mut image = HdrImage::new(width, height);
+    let mut scanline = vec![0u8; width as usize * 4];
+    for row in 0..height {
+        pos = try!(read_scanline(data, pos, &mut scanline));
+        let y = if top_down { height - 1 - row } else { row };
+        for x in 0..width {
+            let i = x as usize * 4;
+            image.set_pixel(x, y, from_rgbe(&scanline[i..i + 4]));
+        }
+    }
+
+    Ok(image)
+}
+
+// Fill one scanline of RGBE pixels, returning where the next one starts
+fn read_scanline(data: &[u8], mut pos: usize, scanline: &mut [u8]) -> Result<usize, Error> {
+    let width = scanline.len() / 4;
+    let truncated = || Error::new(ErrorKind::UnexpectedEof, "Radiance scanline is truncated");
+
+    if pos + 4 > data.len() {
+        return Err(truncated());
+    }
+
+    // New style run length encoding stores each component separately
+    let new_rle = width >= 8 && width < 32768 && data[pos] == 2 && data[pos + 1] == 2 &&
+        data[pos + 2] & 0x80 == 0;
+    if new_rle {
+        if ((data[pos + 2] as usize) << 8 | data[pos + 3] as usize) != width {
+            return Err(invalid("Radiance scanline width mismatch"));
+        }
+        pos += 4;
+
+        for component in 0..4 {
+            let mut x = 0;
+            while x < width {
+                if pos >= data.len() {
+                    return Err(truncated());
+                }
+                let count = data[pos] as usize;
+                pos += 1;
+                if count > 128 {
+                    // Run of one value
+                    let count = count - 128;
+                    if pos >= data.len() || x + count > width {
+                        return Err(invalid("Bad Radiance run"));
+                    }
+                    for _ in 0..count {
+                        scanline[x * 4 + component] = data[pos];
+                        x += 1;
+                    }
+                    pos += 1;
+                } else {
+                    if count == 0 || pos + count > data.len() || x + count > width {
+                        return Err(invalid("Bad Radiance run"));
+                    }
+                    for k in 0..count {
+                        scanline[x * 4 + component] = data[pos + k];
+                        x += 1;
+                    }
+                    pos += count;
+                }
+            }
+        }
+        return Ok(pos);
+    }
+
+    // Flat pixels, possibly with old style runs where (1, 1, 1, n) repeats the last pixel
+    let mut x = 0;
+    let mut shift = 0;
+    while x < width {
+        if pos + 4 > data.len() {
+            return Err(truncated());
+        }
+        let p = &data[pos..pos + 4];
+        pos += 4;
+        if p[0] == 1 && p[1] == 1 && p[2] == 1 && x > 0 {
+            let count = (p[3] as usize) << shift;
+            let last = [scanline[x * 4 - 4], scanline[x * 4 - 3], scanline[x * 4 - 2], scanline[x * 4 - 1]];
+            for _ in 0..count.min(width - x) {
+                scanline[x * 4..x * 4 + 4].copy_from_slice(&last);
+                x += 1;
+            }
+            shift += 8;
+        } else {
+            scanline[x * 4..x * 4 + 4].copy_from_slice(p);
+            x += 1;
+            shift = 0;
+        }
+    }
+    Ok(pos)
+}
+
+pub fn write_hdr_file(image: &HdrImage, filename: &str) -> Result<(), Error> {
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    try!(write!(f, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", image.height, image.width));
+
+    let width = image.width as usize;
+    for y in (0..image.height).rev() {
+        let pixels: Vec<[u8; 4]> = (0..image.width).map(|x| to_rgbe(image.get_pixel(x, y))).collect();
+
+        if width < 8 || width >= 32768 {
+            // Too small or too big for run length encoding
+            for p in pixels.iter() {
+                try!(f.write_all(p));
+            }
+            continue;
+        }
+
+        try!(f.write_all(&[2, 2, (width >> 8) as u8, width as u8]));
+        for component in 0..4 {
+            let values: Vec<u8> = pixels.iter().map(|p| p[component]).collect();
+            try!(f.write_all(&encode_component(&values)));
+        }
+    }
+
+    f.flush()
+}
+
+// Runs of four or more get encoded, everything else is dumped as literals
+fn encode_component(values: &[u8]) -> Vec<u8> {
+    const MIN_RUN: usize = 4;
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < values.len() {
+        // Find the next run long enough to be worth encoding
+        let mut run_start = i;
+        let mut run_len = 0;
+        while run_start < values.len() {
+            run_len = 1;
+            while run_start + run_len < values.len() && run_len < 127 &&
+                values[run_start + run_len] == values[run_start] {
+                run_len += 1;
+            }
+            if run_len >= MIN_RUN {
+                break;
+            }
+            run_start += run_len;
+        }
+
+        // Literals up to the run
+        while i < run_start.min(values.len()) {
+            let count = (run_start - i).min(128);
+            out.push(count as u8);
+            out.extend_from_slice(&values[i..i + count]);
+            i += count;
+        }
+
+        if run_start < values.len() && run_len >= MIN_RUN {
+            out.push(128 + run_len as u8);
+            out.push(values[run_start]);
+            i = run_start + run_len;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempFile;
+
+    // Bright enough that clipping would show, with a wide range of exponents
+    fn test_hdr_image(width: i32, height: i32) -> HdrImage {
+        let mut image = HdrImage::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let v = (x + 1) as f32 * 0.37 * 2f32.powi(y - 3);
+                image.set_pixel(x, y, HdrColor::new(v, v * 0.5 + 0.01, 1.0 / (v + 1.0), 0.25 * (x % 5) as f32));
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn round_trip() {
+        // Narrow images aren't run length encoded
+        for &width in [5, 40].iter() {
+            let image = test_hdr_image(width, 9);
+            let file = TempFile::new(&format!("round_trip_{}.hdr", width));
+            write_hdr_file(&image, file.name()).unwrap();
+            let read = read_hdr_file(file.name()).unwrap();
+
+            assert_eq!((read.width, read.height), (image.width, image.height));
+            for y in 0..image.height {
+                for x in 0..image.width {
+                    let (a, b) = (image.get_pixel(x, y), read.get_pixel(x, y));
+                    // RGBE keeps 8 bits of mantissa for the brightest channel
+                    let tolerance = a.r.max(a.g).max(a.b) / 128.0;
+                    assert!((a.r - b.r).abs() <= tolerance && (a.g - b.g).abs() <= tolerance &&
+                            (a.b - b.b).abs() <= tolerance, "{:?} vs {:?}", a, b);
+                    assert_eq!(b.a, 1.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bad_header() {
+        assert!(decode_hdr(b"not radiance").is_err());
+        assert!(decode_hdr(b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 1 +X 1\n\x80\x80\x80\x80").is_err());
+        assert!(decode_hdr(b"#?RADIANCE\n\n-Y 100 +X 100\n\x80\x80\x80\x80").is_err());
+        assert!(decode_hdr(b"#?RADIANCE\n\n-Y 1 +X -5\n\x80\x80\x80\x80").is_err());
+        assert!(decode_hdr(b"#?RADIANCE\n\n-Y 0 +X 5\n\x80\x80\x80\x80").is_err());
+        assert!(decode_hdr(b"#?RADIANCE\n\n-Y 100000 +X 100000\n\x80\x80\x80\x80").is_err());
+    }
+}
@@ -1,41 +1,50 @@
 use std::io;
-use std::fs::File;
-use std::io::Write;
-use std::mem;
-use std::slice;
-use geo::{Vec2};
+use geo::{Vec2, Rect};
+use blend::BlendState;
+use stencil::StencilState;
+use mipmap::{self, MipFilter};
+use tga::{self, TGAWriteOptions};
+use png;
+use netpbm::{self, Encoding};
+use bmp::{self, BMPFormat};
+use std::path::Path;
 use std::f64;
+use error::LoadError;
+use tonemap::ColorSpace;
 
-// TODO: Probably some stuff with bits per pixel, I guess 24 for now (BGR, no alpha)
+// Stored in the same order as a 32 bit TGA pixel - BGRA
 // Somewhat based on https://gist.github.com/jonvaldes/607fbc380f816d205afb
-#[derive(Clone, Copy, Debug)]
-pub struct Color(pub u8, pub u8, pub u8);
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color(pub u8, pub u8, pub u8, pub u8);
 
-pub const BLACK: Color = Color(0, 0, 0);
-pub const WHITE: Color = Color(255, 255, 255);
-pub const RED: Color = Color(0, 0, 255);
-pub const GREEN: Color = Color(0, 255, 0);
-pub const BLUE: Color = Color(255, 0, 0);
+impl Color {
+    pub fn alpha(self) -> u8 {
+        self.3
+    }
+
+    pub fn with_alpha(self, a: u8) -> Color {
+        Color(self.0, self.1, self.2, a)
+    }
+}
+
+pub const BLACK: Color = Color(0, 0, 0, 255);
+pub const WHITE: Color = Color(255, 255, 255, 255);
+pub const RED: Color = Color(0, 0, 255, 255);
+pub const GREEN: Color = Color(0, 255, 0, 255);
+pub const BLUE: Color = Color(255, 0, 0, 255);
+pub const TRANSPARENT: Color = Color(0, 0, 0, 0);
 
 pub struct Image {
     pub width: i32,
     pub height: i32,
+    // Screen position the image was authored for, only really used by TGA
+    pub origin: Vec2<i32>,
     data: Vec<Color>,
     zbuffer: Vec<i32>,
-}
-
-// Given a reference to a T, return a reference to that slice
-unsafe fn struct_to_u8_slice<T>(s: &T) -> &[u8] {
-    // Convert the pointer into a u8
-    let data_ptr: *const u8 = mem::transmute(s);
-    // Return the slice to the end of s
-    slice::from_raw_parts(data_ptr, mem::size_of::<T>())
-}
-
-// Same thing but for a reference to a slice
-unsafe fn slice_to_u8_slice<T>(s: &[T]) -> &[u8] {
-    let data_ptr: *const u8 = mem::transmute(&s[0]);
-    slice::from_raw_parts(data_ptr, mem::size_of::<T>() * s.len())
+    stencil: Vec<u8>,
+    // Successively halved copies, empty until build_mipmaps is called
+    mips: Vec<Image>,
 }
 
 impl Image {
@@ -48,19 +57,35 @@ impl Image {
         Image {
             width: width,
             height: height,
+            origin: Vec2::new(0, 0),
             data: v,
             // TODO: The zbuffer should probably be in some other object - a scene?
             zbuffer: z,
+            stencil: vec![0; (width * height) as usize],
+            mips: Vec::new(),
         }
     }
 
+    // The whole image as a rectangle, the default viewport and scissor
+    pub fn rect(self: &Image) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
     pub fn set_pixel(self: &mut Image, x: i32, y: i32, c: Color) {
-        if !(x < 0 || y < 0 || x >= self.width || y >= self.width) {
+        if !(x < 0 || y < 0 || x >= self.width || y >= self.height) {
             // The index in the vector is the width times y plus x
             self.data[((y * self.width) + x) as usize] = c;
         }
     }
 
+    // Write a fragment through the blend unit rather than overwriting the pixel
+    pub fn blend_pixel(self: &mut Image, x: i32, y: i32, c: Color, blend: &BlendState) {
+        if !(x < 0 || y < 0 || x >= self.width || y >= self.height) {
+            let idx = ((y * self.width) + x) as usize;
+            self.data[idx] = blend.blend(c, self.data[idx]);
+        }
+    }
+
     pub fn get_pixel(self: &Image, x: i32, y: i32) -> Color {
         self.data[((y * self.width) + x) as usize]
     }
@@ -71,7 +96,7 @@ impl Image {
 
     // TODO: Should the zbuffer be floats?
     pub fn set_depth(self: &mut Image, x: i32, y: i32, d: f64) {
-        if !(x < 0 || y < 0 || x >= self.width || y >= self.width) {
+        if !(x < 0 || y < 0 || x >= self.width || y >= self.height) {
             self.zbuffer[((y * self.width) + x) as usize] = d as i32;
         }
     }
@@ -80,45 +105,125 @@ impl Image {
         self.zbuffer[((y * self.width) + x) as usize]
     }
 
-    pub fn write_tga_file(self: &Image, filename: &str) -> io::Result<()> {
-        // Do what C does, also strip any padding and align the type to a byte
-        // TODO: Move this to TGA
-        #[repr(C, packed)]
-        #[derive(Default)]
-        struct Header {
-            id_length: u8,
-            color_map_type: u8,
-            data_type_code: u8,
-            color_map_origin: u16,
-            color_map_length: u16,
-            color_map_depth: u8,
-            x_origin: u16,
-            y_origin: u16,
-            width: u16,
-            height: u16,
-            bits_per_pixel: u8,
-            image_descriptor: u8,
+    pub fn set_stencil(self: &mut Image, x: i32, y: i32, s: u8) {
+        if !(x < 0 || y < 0 || x >= self.width || y >= self.height) {
+            self.stencil[((y * self.width) + x) as usize] = s;
         }
+    }
+
+    pub fn get_stencil(self: &Image, x: i32, y: i32) -> u8 {
+        self.stencil[((y * self.width) + x) as usize]
+    }
+
+    pub fn clear_stencil(self: &mut Image, s: u8) {
+        for v in self.stencil.iter_mut() {
+            *v = s;
+        }
+    }
 
-        let h = Header {
-            data_type_code: 2,
-            width: self.width as u16,
-            height: self.height as u16,
-            bits_per_pixel: 24,
-            ..Header::default()
+    // Stencil test, then depth test, running the stencil op for whichever way it went. True
+    // if a fragment at depth z passed both.
+    pub fn depth_stencil_test(self: &mut Image, x: i32, y: i32, z: f64, stencil: &StencilState) -> bool {
+        let stored = if stencil.enabled { self.get_stencil(x, y) } else { 0 };
+        let (passed, op) = if !stencil.test(stored) {
+            (false, stencil.stencil_fail)
+        } else if z as i32 > self.get_depth(x, y) {
+            (true, stencil.pass)
+        } else {
+            (false, stencil.depth_fail)
         };
 
-        let mut f = try!(File::create(filename));
-        // Write the header struct and data into the file
-        unsafe {
-            try!(f.write_all(struct_to_u8_slice(&h)));
-            try!(f.write_all(slice_to_u8_slice(&self.data[..])));
+        if stencil.enabled {
+            self.set_stencil(x, y, stencil.update(op, stored));
+        }
+        passed
+    }
+
+    pub fn build_mipmaps(self: &mut Image, filter: MipFilter, color_space: ColorSpace) {
+        self.mips = mipmap::generate(self, filter, color_space);
+    }
+
+    // Number of levels including the full size image
+    pub fn mip_levels(self: &Image) -> usize {
+        self.mips.len() + 1
+    }
+
+    pub fn mip_level(self: &Image, level: usize) -> &Image {
+        match level {
+            0 => self,
+            _ => &self.mips[(level - 1).min(self.mips.len() - 1)],
         }
-        Ok(())
+    }
+
+    pub fn write_tga_file(self: &Image, filename: &str) -> io::Result<()> {
+        tga::write_tga_file(self, filename, &TGAWriteOptions::default())
+    }
+
+    pub fn write_tga_file_with(self: &Image, filename: &str, options: &TGAWriteOptions) -> io::Result<()> {
+        tga::write_tga_file(self, filename, options)
+    }
+
+    pub fn write_png_file(self: &Image, filename: &str) -> io::Result<()> {
+        png::write_png_file(self, filename)
+    }
+
+    pub fn write_ppm_file(self: &Image, filename: &str, encoding: Encoding) -> io::Result<()> {
+        netpbm::write_ppm_file(self, filename, encoding)
+    }
+
+    pub fn write_pgm_file(self: &Image, filename: &str, encoding: Encoding) -> io::Result<()> {
+        netpbm::write_pgm_file(self, filename, encoding)
+    }
+
+    pub fn write_pam_file(self: &Image, filename: &str) -> io::Result<()> {
+        netpbm::write_pam_file(self, filename)
+    }
+
+    pub fn write_depth_pgm_file(self: &Image, filename: &str, encoding: Encoding) -> io::Result<()> {
+        netpbm::write_depth_pgm_file(self, filename, encoding)
+    }
+
+    pub fn write_bmp_file(self: &Image, filename: &str, format: BMPFormat) -> io::Result<()> {
+        bmp::write_bmp_file(self, filename, format)
+    }
+}
+
+// Pick the reader based on the file extension
+pub fn read_image_file(filename: &str) -> Result<Image, LoadError> {
+    let extension = Path::new(filename).extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    match extension.as_ref().map(|e| e.as_str()) {
+        Some("tga") => tga::read_tga_file(filename),
+        Some("png") => png::read_png_file(filename),
+        Some("bmp") => bmp::read_bmp_file(filename),
+        Some("ppm") | Some("pgm") | Some("pnm") | Some("pam") => netpbm::read_netpbm_file(filename),
+        _ => Err(LoadError::unsupported(filename, "Don't know how to read this kind of image")),
+    }
+}
+
+// For images that aren't in a file of their own, e.g. embedded in a model. TGA has no
+// signature to go by so it can't be detected.
+pub fn decode_image(data: &[u8]) -> io::Result<Image> {
+    if data.starts_with(b"\x89PNG") {
+        png::decode_png(data)
+    } else if data.starts_with(b"BM") {
+        bmp::decode_bmp(data)
+    } else if data.len() > 1 && data[0] == b'P' && data[1] >= b'1' && data[1] <= b'7' {
+        netpbm::decode_netpbm(data)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "Unrecognized image data"))
     }
 }
 
 pub fn line(point1: Vec2<i32>, point2: Vec2<i32>, image: &mut Image, color: Color) {
+    let bounds = image.rect();
+    scissored_line(point1, point2, image, color, &bounds);
+}
+
+// Same as line, but only pixels inside the scissor rectangle get drawn
+pub fn scissored_line(point1: Vec2<i32>, point2: Vec2<i32>, image: &mut Image, color: Color, scissor: &Rect) {
     // We need to work in floats, then output in i32
     let p1 = point1.to_f64();
     let p2 = point2.to_f64();
@@ -153,10 +258,14 @@ pub fn line(point1: Vec2<i32>, point2: Vec2<i32>, image: &mut Image, color: Colo
     while x <= x1 {
         match steep {
             true => {
-                image.set_pixel(y as i32, x as i32, color);
+                if scissor.contains(y as i32, x as i32) {
+                    image.set_pixel(y as i32, x as i32, color);
+                }
             },
             false => {
-                image.set_pixel(x as i32, y as i32, color);
+                if scissor.contains(x as i32, y as i32) {
+                    image.set_pixel(x as i32, y as i32, color);
+                }
             }
         }
 
@@ -0,0 +1,308 @@
+use std::io::{Error, ErrorKind};
+
+// Just enough JSON for reading asset files like glTF. Objects keep their keys in order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+static NULL: Json = Json::Null;
+
+impl Json {
+    // Missing keys and wrong types come back as Null so lookups can be chained
+    pub fn get(&self, key: &str) -> &Json {
+        match *self {
+            Json::Object(ref members) => {
+                members.iter().find(|m| m.0 == key).map(|m| &m.1).unwrap_or(&NULL)
+            },
+            _ => &NULL,
+        }
+    }
+
+    pub fn at(&self, index: usize) -> &Json {
+        match *self {
+            Json::Array(ref items) => items.get(index).unwrap_or(&NULL),
+            _ => &NULL,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // Empty for anything that isn't an array
+    pub fn members(&self) -> &[Json] {
+        match *self {
+            Json::Array(ref items) => items,
+            _ => &[],
+        }
+    }
+
+    // All the numbers in an array, None if anything else is in there
+    pub fn as_f64_vec(&self) -> Option<Vec<f64>> {
+        match *self {
+            Json::Array(ref items) => items.iter().map(|i| i.as_f64()).collect(),
+            _ => None,
+        }
+    }
+}
+
+fn invalid(msg: &str, pos: usize) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{} in JSON at byte {}", msg, pos))
+}
+
+// Deeper than any real file goes, and shallow enough that recursing that far can't
+// overflow the stack
+const MAX_DEPTH: usize = 512;
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+pub fn parse(data: &[u8]) -> Result<Json, Error> {
+    let mut parser = Parser { data: data, pos: 0 };
+    let value = try!(parser.value(0));
+    parser.skip_whitespace();
+    if parser.pos != data.len() {
+        return Err(invalid("Trailing characters", parser.pos));
+    }
+    Ok(value)
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.data.len() && (self.data[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), Error> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(invalid(&format!("Expected '{}'", c as char), self.pos))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, Error> {
+        if self.data[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(invalid("Unexpected word", self.pos))
+        }
+    }
+
+    // depth is how many objects and arrays the value is inside
+    fn value(&mut self, depth: usize) -> Result<Json, Error> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') | Some(b'[') if depth >= MAX_DEPTH => Err(invalid("Nesting too deep", self.pos)),
+            Some(b'{') => self.object(depth + 1),
+            Some(b'[') => self.array(depth + 1),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(invalid("Unexpected character", self.pos)),
+            None => Err(invalid("Unexpected end", self.pos)),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, Error> {
+        try!(self.expect(b'{'));
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key = try!(self.string());
+            try!(self.expect(b':'));
+            let value = try!(self.value(depth));
+            members.push((key, value));
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                },
+                _ => return Err(invalid("Expected ',' or '}'", self.pos)),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, Error> {
+        try!(self.expect(b'['));
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(try!(self.value(depth)));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                },
+                _ => return Err(invalid("Expected ',' or ']'", self.pos)),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, Error> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            match c {
+                b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9' => self.pos += 1,
+                _ => break,
+            }
+        }
+        ::std::str::from_utf8(&self.data[start..self.pos]).ok()
+            .and_then(|s| s.parse::<f64>().ok())
+            .map(Json::Number)
+            .ok_or_else(|| invalid("Bad number", start))
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let digits = self.data.get(self.pos..self.pos + 4)
+            .and_then(|d| ::std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok());
+        match digits {
+            Some(v) => {
+                self.pos += 4;
+                Ok(v)
+            },
+            None => Err(invalid("Bad \\u escape", self.pos)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        try!(self.expect(b'"'));
+        let mut bytes: Vec<u8> = Vec::new();
+
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(invalid("Unterminated string", self.pos)),
+            };
+            self.pos += 1;
+
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.peek();
+                    self.pos += 1;
+                    let ch = match escape {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let mut code = try!(self.hex4());
+                            // Characters outside the BMP come as a surrogate pair
+                            if code >= 0xd800 && code < 0xdc00 && self.data[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = try!(self.hex4());
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            ::std::char::from_u32(code).unwrap_or('\u{fffd}')
+                        },
+                        _ => return Err(invalid("Bad escape", self.pos)),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend(ch.encode_utf8(&mut buf).as_bytes());
+                },
+                _ => bytes.push(c),
+            }
+        }
+
+        String::from_utf8(bytes).map_err(|_| invalid("Invalid UTF-8 in string", self.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let json = parse(br#" {"a": [1, -2.5e2, true, false, null], "b": {"c": "x\"\\\/\n\u00e9\ud83d\ude00"}, "a": 3} "#).unwrap();
+        assert_eq!(json.get("a").members().len(), 5);
+        assert_eq!(json.get("a").at(1).as_f64(), Some(-250.0));
+        assert_eq!(json.get("a").at(2).as_bool(), Some(true));
+        assert!(json.get("a").at(4).is_null());
+        assert_eq!(json.get("b").get("c").as_str(), Some("x\"\\/\n\u{e9}\u{1f600}"));
+        assert_eq!(json.get("a").as_f64_vec(), None);
+        assert!(json.get("missing").get("deeper").is_null());
+        assert_eq!(parse(b"[]").unwrap(), Json::Array(Vec::new()));
+    }
+
+    #[test]
+    fn malformed() {
+        for text in [&b""[..], b"{", b"[1, 2", b"{\"a\" 1}", b"[1,]", b"\"unterminated", b"nul", b"1 2", b"\"\\q\""].iter() {
+            assert!(parse(text).is_err(), "{}", String::from_utf8_lossy(text));
+        }
+
+        // Deep nesting is an error rather than a stack overflow
+        let deep = |depth: usize| format!("{}1{}", "[{\"a\": ".repeat(depth), "}]".repeat(depth)).into_bytes();
+        assert!(parse(&deep(200)).is_ok());
+        assert!(parse(&deep(300)).is_err());
+        assert!(parse(&vec![b'['; 1_000_000]).is_err());
+    }
+}
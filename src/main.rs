@@ -2,21 +2,50 @@ mod image;
 mod model;
 mod geo;
 mod tga;
+mod blend;
+mod state;
+mod oit;
+mod stencil;
+mod sampler;
+mod mipmap;
+mod zlib;
+mod png;
+mod netpbm;
+mod bmp;
+mod hdr;
+mod exr;
+mod tonemap;
+mod material;
+mod triangulate;
+mod stl;
+mod ply;
+mod json;
+mod gltf;
+mod obj;
+mod mesh;
+mod meshcache;
+mod error;
+#[cfg(test)]
+mod testutil;
 
-use image::*;
 use model::*;
 use geo::*;
+use hdr::HdrImage;
+use state::DrawState;
+use tonemap::ToneMapper;
 
 extern crate num;
 extern crate byteorder;
 
 fn main() {
     let (width, height) = (800, 800);
-    let mut image = Image::new(width, height);
+    // Shade in linear light, then tone map and encode to sRGB for the 8 bit output
+    let mut frame = HdrImage::new(width, height);
     // TODO: Command line argument for the object file
     let model = Model::new("obj/african_head/african_head.obj");
     // let model = Model::new("obj/diablo3_pose/diablo3_pose.obj");
-    let light_dir = Vec3{x: 0, y: 0, z: 1};
-    model.draw(&mut image, light_dir);
+    let light_dir = Vec3{x: 0.0, y: 0.0, z: 1.0};
+    model.draw_hdr(&mut frame, light_dir, &DrawState::default());
+    let image = frame.tonemap(&ToneMapper::default());
     image.write_tga_file("output.tga");
 }
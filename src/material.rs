@@ -0,0 +1,254 @@
+use std::fs::File;
+use std::io::{BufReader, BufRead};
+use std::path::{Path, PathBuf};
+
+use image::{Image, read_image_file};
+use hdr::HdrColor;
+use mipmap::MipFilter;
+use tonemap::ColorSpace;
+use error::LoadError;
+
+// A texture referenced by a material, along with where it came from
+pub struct TextureMap {
+    pub path: PathBuf,
+    pub image: Image,
+}
+
+// One newmtl block of a Wavefront material library. Colors are linear, like the file.
+pub struct Material {
+    pub name: String,
+    // Ka
+    pub ambient: HdrColor,
+    // Kd
+    pub diffuse: HdrColor,
+    // Ks
+    pub specular: HdrColor,
+    // Ns, the specular exponent
+    pub shininess: f64,
+    // d, 1.0 is fully opaque
+    pub dissolve: f64,
+    pub illum: u32,
+    pub diffuse_map: Option<TextureMap>,
+    pub specular_map: Option<TextureMap>,
+    pub bump_map: Option<TextureMap>,
+    pub alpha_map: Option<TextureMap>,
+    // Metallic-roughness PBR parameters, from glTF
+    pub metallic: f64,
+    pub roughness: f64,
+    pub emissive: HdrColor,
+    // Metalness in blue and roughness in green, like glTF packs them
+    pub metallic_roughness_map: Option<TextureMap>,
+    pub normal_map: Option<TextureMap>,
+    pub occlusion_map: Option<TextureMap>,
+    pub emissive_map: Option<TextureMap>,
+    // What actually gets drawn: the diffuse map tinted by Kd, or just Kd when there isn't one,
+    // with the alpha map baked into its alpha channel
+    texture: Image,
+}
+
+impl Material {
+    pub fn new(name: &str) -> Self {
+        let mut material = Material {
+            name: name.to_string(),
+            ambient: HdrColor::new(0.0, 0.0, 0.0, 1.0),
+            diffuse: HdrColor::new(1.0, 1.0, 1.0, 1.0),
+            specular: HdrColor::new(0.0, 0.0, 0.0, 1.0),
+            shininess: 0.0,
+            dissolve: 1.0,
+            illum: 1,
+            diffuse_map: None,
+            specular_map: None,
+            bump_map: None,
+            alpha_map: None,
+            metallic: 0.0,
+            roughness: 1.0,
+            emissive: HdrColor::new(0.0, 0.0, 0.0, 1.0),
+            metallic_roughness_map: None,
+            normal_map: None,
+            occlusion_map: None,
+            emissive_map: None,
+            texture: Image::new(1, 1),
+        };
+        material.build_texture();
+        material
+    }
+
+    // A plain material using the given diffuse texture, for models without a material library
+    pub fn with_texture(name: &str, path: PathBuf, image: Image) -> Self {
+        let mut material = Material::new(name);
+        material.diffuse_map = Some(TextureMap { path: path, image: image });
+        material.build_texture();
+        material
+    }
+
+    pub fn texture(&self) -> &Image {
+        &self.texture
+    }
+
+    // Call after changing any of the maps or Kd
+    pub fn build_texture(&mut self) {
+        let mut texture = match self.diffuse_map {
+            Some(ref map) => {
+                // Kd tints the map, skipped when it's white so the texels stay exact
+                let mut texture = copy_image(&map.image);
+                let kd = self.diffuse;
+                if kd.r != 1.0 || kd.g != 1.0 || kd.b != 1.0 {
+                    for y in 0..texture.height {
+                        for x in 0..texture.width {
+                            let c = ColorSpace::Srgb.decode(texture.get_pixel(x, y));
+                            let tinted = HdrColor::new(c.r * kd.r, c.g * kd.g, c.b * kd.b, c.a);
+                            texture.set_pixel(x, y, ColorSpace::Srgb.encode(tinted));
+                        }
+                    }
+                }
+                texture
+            },
+            None => {
+                // Encoded the same as a texture file would be so shading treats both the same
+                let size = self.alpha_map.as_ref().map_or((1, 1), |m| (m.image.width, m.image.height));
+                let mut solid = Image::new(size.0, size.1);
+                let kd = ColorSpace::Srgb.encode(self.diffuse);
+                for y in 0..size.1 {
+                    for x in 0..size.0 {
+                        solid.set_pixel(x, y, kd);
+                    }
+                }
+                solid
+            },
+        };
+
+        if let Some(ref map) = self.alpha_map {
+            // The alpha map doesn't have to be the same size as the texture
+            for y in 0..texture.height {
+                for x in 0..texture.width {
+                    let ax = x * map.image.width / texture.width;
+                    let ay = y * map.image.height / texture.height;
+                    let a = map.image.get_pixel(ax, ay);
+                    // Grayscale maps keep it in the color, others in the alpha
+                    let mask = if a.alpha() == 255 { a.2 } else { a.alpha() };
+                    let c = texture.get_pixel(x, y);
+                    texture.set_pixel(x, y, c.with_alpha((c.alpha() as u32 * mask as u32 / 255) as u8));
+                }
+            }
+        }
+
+        texture.build_mipmaps(MipFilter::Box, ColorSpace::Srgb);
+        self.texture = texture;
+    }
+}
+
+fn copy_image(image: &Image) -> Image {
+    let mut copy = Image::new(image.width, image.height);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            copy.set_pixel(x, y, image.get_pixel(x, y));
+        }
+    }
+    copy
+}
+
+fn parse_color(values: &[&str]) -> HdrColor {
+    let v: Vec<f32> = values.iter().filter_map(|s| s.parse::<f32>().ok()).collect();
+    match v.len() {
+        0 => HdrColor::black(),
+        // A single value is used for all three channels
+        1 | 2 => HdrColor::new(v[0], v[0], v[0], 1.0),
+        _ => HdrColor::new(v[0], v[1], v[2], 1.0),
+    }
+}
+
+// Texture statements can have options like "-bm 0.5" or "-o 0 0 0" before the filename
+fn map_filename(values: &[&str]) -> Option<String> {
+    let mut i = 0;
+    while i < values.len() && values[i].starts_with('-') {
+        let takes_name = values[i] == "-imfchan" || values[i] == "-type";
+        i += 1;
+        if takes_name {
+            i += 1;
+            continue;
+        }
+        while i < values.len() - 1 &&
+            (values[i].parse::<f64>().is_ok() || values[i] == "on" || values[i] == "off") {
+            i += 1;
+        }
+    }
+
+    if i < values.len() {
+        // Filenames can contain spaces, and exporters on Windows use backslashes
+        Some(values[i..].join(" ").replace('\\', "/"))
+    } else {
+        None
+    }
+}
+
+// A texture that can't be loaded leaves the material without that map
+fn load_map(dir: &Path, values: &[&str], warnings: &mut Vec<LoadError>) -> Option<TextureMap> {
+    let path = match map_filename(values) {
+        Some(name) => dir.join(name),
+        None => return None,
+    };
+
+    match read_image_file(path.to_str().unwrap_or("")) {
+        Ok(image) => Some(TextureMap { path: path, image: image }),
+        Err(e) => {
+            warnings.push(e);
+            None
+        }
+    }
+}
+
+// Texture paths in the library are relative to the library itself. Textures that can't be
+// loaded are added to warnings rather than failing the whole library.
+pub fn read_mtl_file(filename: &str, warnings: &mut Vec<LoadError>) -> Result<Vec<Material>, LoadError> {
+    let dir = Path::new(filename).parent().unwrap_or(Path::new("")).to_path_buf();
+    let f = BufReader::new(try!(File::open(filename).map_err(|e| LoadError::io(filename, e))));
+    let mut materials: Vec<Material> = Vec::new();
+
+    for line in f.lines() {
+        let line = try!(line.map_err(|e| LoadError::io(filename, e)));
+        let mut split_line = line.split_whitespace();
+        let keyword = split_line.next();
+        let values: Vec<&str> = split_line.collect();
+
+        if keyword == Some("newmtl") {
+            materials.push(Material::new(&values.join(" ")));
+            continue;
+        }
+
+        // Anything before the first newmtl doesn't belong to a material
+        let material = match materials.last_mut() {
+            Some(m) => m,
+            None => continue,
+        };
+        // Options like "d -halo 0.5" come before the value
+        let number = values.iter().filter_map(|s| s.parse::<f64>().ok()).last();
+
+        match keyword {
+            Some("Ka") => material.ambient = parse_color(&values),
+            Some("Kd") => material.diffuse = parse_color(&values),
+            Some("Ks") => material.specular = parse_color(&values),
+            Some("Ns") => material.shininess = number.unwrap_or(0.0),
+            Some("d") => material.dissolve = number.unwrap_or(1.0),
+            // Transparency, the opposite of dissolve
+            Some("Tr") => material.dissolve = 1.0 - number.unwrap_or(0.0),
+            Some("illum") => material.illum = number.unwrap_or(1.0) as u32,
+            Some("map_Kd") => material.diffuse_map = load_map(&dir, &values, warnings),
+            Some("map_Ks") => material.specular_map = load_map(&dir, &values, warnings),
+            Some("map_Bump") | Some("map_bump") | Some("bump") => material.bump_map = load_map(&dir, &values, warnings),
+            Some("map_d") => material.alpha_map = load_map(&dir, &values, warnings),
+            // The PBR extension to the format
+            Some("Ke") => material.emissive = parse_color(&values),
+            Some("Pm") => material.metallic = number.unwrap_or(0.0),
+            Some("Pr") => material.roughness = number.unwrap_or(1.0),
+            Some("map_Ke") => material.emissive_map = load_map(&dir, &values, warnings),
+            Some("norm") => material.normal_map = load_map(&dir, &values, warnings),
+            _ => {},
+        }
+    }
+
+    for material in materials.iter_mut() {
+        material.build_texture();
+    }
+
+    Ok(materials)
+}
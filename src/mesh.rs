@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use geo::{Vertex, Vec2, Vec3, Triangle, Rect, viewport_transform};
+use image::{Color, WHITE};
+
+// Triangles as indices into shared vertex attribute arrays, so corners shared between faces
+// are only stored once, and only transformed once when drawing. The attribute arrays are
+// either empty or have one entry per position. Vertices that didn't have an attribute when
+// others did get a zero normal or tangent, which reads back as none, a UV of 0, 0, which is
+// what drawing uses without one, or white, which leaves the texture as it is.
+pub struct Mesh {
+    pub positions: Vec<Vec3<f64>>,
+    pub normals: Vec<Vec3<f64>>,
+    pub uvs: Vec<Vec2<f64>>,
+    pub colors: Vec<Color>,
+    // Tangent and the sign of the bitangent
+    pub tangents: Vec<(Vec3<f64>, f64)>,
+    // Three per triangle, in the winding of the faces they came from
+    pub indices: Vec<u32>,
+}
+
+fn is_zero(v: Vec3<f64>) -> bool {
+    v.x == 0.0 && v.y == 0.0 && v.z == 0.0
+}
+
+// Adds the attribute of the vertex at index count, filling in for the ones before it if it's
+// the first to have one
+fn push_attribute<T: Copy>(values: &mut Vec<T>, count: usize, value: Option<T>, missing: T) {
+    match value {
+        Some(value) => {
+            while values.len() < count {
+                values.push(missing);
+            }
+            values.push(value);
+        },
+        None if !values.is_empty() => values.push(missing),
+        None => {},
+    }
+}
+
+impl Mesh {
+    pub fn new() -> Self {
+        Mesh {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            tangents: Vec::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    pub fn from_triangles(triangles: &[Triangle]) -> Self {
+        let mut builder = MeshBuilder::new();
+        for tri in triangles.iter() {
+            builder.add_triangle(&tri.vertices);
+        }
+        builder.build()
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    // Adds the vertex as it is, without looking for an identical one
+    pub fn push_vertex(&mut self, vertex: &Vertex) -> u32 {
+        let count = self.positions.len();
+        push_attribute(&mut self.normals, count, vertex.normal(), Vec3::new(0.0, 0.0, 0.0));
+        push_attribute(&mut self.uvs, count, vertex.texture(), Vec2::new(0.0, 0.0));
+        push_attribute(&mut self.colors, count, vertex.color(), WHITE);
+        push_attribute(&mut self.tangents, count, vertex.tangent(), (Vec3::new(0.0, 0.0, 0.0), 1.0));
+        self.positions.push(vertex.coords);
+        count as u32
+    }
+
+    pub fn corners(&self, i: usize) -> [usize; 3] {
+        [self.indices[i * 3] as usize, self.indices[i * 3 + 1] as usize, self.indices[i * 3 + 2] as usize]
+    }
+
+    // All the attributes of one vertex put back together
+    pub fn vertex(&self, i: usize) -> Vertex {
+        let mut v = Vertex::new(self.positions[i]);
+        if let Some(&n) = self.normals.get(i) {
+            if !is_zero(n) {
+                v.set_normal(n);
+            }
+        }
+        if let Some(&uv) = self.uvs.get(i) {
+            v.set_uv(uv);
+        }
+        if let Some(&c) = self.colors.get(i) {
+            v.set_color(c);
+        }
+        if let Some(&(t, w)) = self.tangents.get(i) {
+            if !is_zero(t) {
+                v.set_tangent(t, w);
+            }
+        }
+        v
+    }
+
+    // A copy of one triangle, for code that works on whole faces
+    pub fn triangle(&self, i: usize) -> Triangle {
+        let c = self.corners(i);
+        Triangle::new([self.vertex(c[0]), self.vertex(c[1]), self.vertex(c[2])])
+    }
+}
+
+// Everything that makes a vertex different from another, floats by their bits
+type VertexKey = ([u64; 3], Option<[u64; 2]>, Option<[u64; 3]>, Option<[u8; 4]>, Option<[u64; 4]>);
+
+fn vertex_key(v: &Vertex) -> VertexKey {
+    (
+        [v.coords.x.to_bits(), v.coords.y.to_bits(), v.coords.z.to_bits()],
+        v.texture().map(|t| [t.x.to_bits(), t.y.to_bits()]),
+        v.normal().map(|n| [n.x.to_bits(), n.y.to_bits(), n.z.to_bits()]),
+        v.color().map(|c| [c.0, c.1, c.2, c.3]),
+        v.tangent().map(|(t, w)| [t.x.to_bits(), t.y.to_bits(), t.z.to_bits(), w.to_bits()]),
+    )
+}
+
+// Builds a mesh a triangle at a time, sharing corners that are exactly the same
+pub struct MeshBuilder {
+    mesh: Mesh,
+    lookup: HashMap<VertexKey, u32>,
+}
+
+impl MeshBuilder {
+    pub fn new() -> Self {
+        MeshBuilder {
+            mesh: Mesh::new(),
+            lookup: HashMap::new(),
+        }
+    }
+
+    pub fn add_vertex(&mut self, vertex: &Vertex) -> u32 {
+        let mesh = &mut self.mesh;
+        *self.lookup.entry(vertex_key(vertex)).or_insert_with(|| mesh.push_vertex(vertex))
+    }
+
+    pub fn add_triangle(&mut self, corners: &[Vertex; 3]) {
+        for v in corners.iter() {
+            let index = self.add_vertex(v);
+            self.mesh.indices.push(index);
+        }
+    }
+
+    pub fn build(self) -> Mesh {
+        self.mesh
+    }
+}
+
+// Screen positions of the vertices of a mesh for one draw. Each vertex is transformed the
+// first time a triangle uses it and reused after that, so hidden parts cost nothing.
+pub struct VertexCache<'a> {
+    mesh: &'a Mesh,
+    viewport: Rect,
+    screen: Vec<Option<Vec3<f64>>>,
+}
+
+impl<'a> VertexCache<'a> {
+    pub fn new(mesh: &'a Mesh, viewport: Rect) -> Self {
+        VertexCache {
+            mesh: mesh,
+            viewport: viewport,
+            screen: vec![None; mesh.vertex_count()],
+        }
+    }
+
+    fn screen_position(&mut self, i: usize) -> Vec3<f64> {
+        if let Some(p) = self.screen[i] {
+            return p;
+        }
+        let p = viewport_transform(self.mesh.positions[i], &self.viewport);
+        self.screen[i] = Some(p);
+        p
+    }
+
+    // The triangle ready to rasterize, put together on the stack from the attribute arrays
+    pub fn triangle(&mut self, i: usize) -> Triangle {
+        let corners = self.mesh.corners(i);
+        let mut tri = self.mesh.triangle(i);
+        for (v, &c) in tri.vertices.iter_mut().zip(corners.iter()) {
+            v.screen_coords = self.screen_position(c);
+        }
+        tri
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_identical_corners() {
+        let mut a = Vertex::new(Vec3::new(0.0, 0.0, 0.0));
+        let b = Vertex::new(Vec3::new(1.0, 0.0, 0.0));
+        let c = Vertex::new(Vec3::new(0.0, 1.0, 0.0));
+        let mut d = Vertex::new(Vec3::new(1.0, 1.0, 0.0));
+        d.set_color(Color(1, 2, 3, 4));
+
+        let mut builder = MeshBuilder::new();
+        builder.add_triangle(&[a, b, c]);
+        builder.add_triangle(&[b, d, c]);
+        // Same spot, different texture coordinates
+        a.set_uv(Vec2::new(0.5, 0.5));
+        builder.add_triangle(&[a, b, c]);
+        let mesh = builder.build();
+
+        assert_eq!(mesh.vertex_count(), 5);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 1, 3, 2, 4, 1, 2]);
+        // Attributes only some vertices have are filled in for the rest
+        assert_eq!(mesh.colors.len(), 5);
+        assert_eq!(mesh.vertex(3).color(), Some(Color(1, 2, 3, 4)));
+        assert_eq!(mesh.vertex(0).color(), Some(WHITE));
+        assert_eq!(mesh.uvs.len(), 5);
+        assert!(mesh.vertex(0).normal().is_none());
+        assert_eq!(mesh.triangle(1).vertices[1].color(), Some(Color(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn transforms_each_vertex_once() {
+        let mut builder = MeshBuilder::new();
+        let corners = [Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 1.0)];
+        builder.add_triangle(&[Vertex::new(corners[0]), Vertex::new(corners[1]), Vertex::new(corners[2])]);
+        builder.add_triangle(&[Vertex::new(corners[2]), Vertex::new(corners[1]), Vertex::new(corners[0])]);
+        let mesh = builder.build();
+
+        let mut cache = VertexCache::new(&mesh, Rect::new(10, 20, 100, 50));
+        let tri = cache.triangle(0);
+        assert_eq!(cache.screen.iter().filter(|s| s.is_some()).count(), 3);
+        let s = tri.vertices[2].screen_coords;
+        assert_eq!((s.x, s.y), (110.0, 70.0));
+        let s = cache.triangle(1).vertices[0].screen_coords;
+        assert_eq!((s.x, s.y), (110.0, 70.0));
+    }
+}
@@ -0,0 +1,647 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::borrow::Cow;
+use std::io::{Read, Write, BufWriter, Cursor};
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::slice;
+use std::time::UNIX_EPOCH;
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, LittleEndian};
+
+use error::LoadError;
+use geo::{Vec2, Vec3};
+use image::Color;
+use mesh::Mesh;
+use model::{Model, Part, obj_materials};
+use png::crc32;
+
+// A model as it was after loading, so big OBJ files only have to be parsed once. All
+// numbers are little endian:
+//
+//   magic "RMSH", u32 version
+//   u64 seconds and u32 nanoseconds of the source file's modification time
+//   u32 CRC-32 of everything after the header
+//   u32 length and the names of libraries, materials, parts and attributes, then the
+//   warnings about the OBJ file itself
+//   f64 opacity
+//   the mesh vertices, see write_vertices
+//   u32 index count, then the u32 indices, material of each triangle and part of each triangle
+//   the points, see write_vertices
+//   u32 count and the f64 values of each attribute, in the order of their names
+//
+// Floats are stored as the f64s they were parsed into, so a cached load gives exactly what
+// parsing the file again would. Sections are padded out to 8 bytes, which keeps the big
+// arrays aligned so they can be read in one go.
+const MAGIC: &'static [u8] = b"RMSH";
+// Bump whenever the layout changes, older caches are then rebuilt
+const VERSION: u32 = 4;
+const HEADER_SIZE: usize = 24;
+
+// Which of the optional vertex attribute arrays follow the positions
+const HAS_TEXTURE: u32 = 1;
+const HAS_NORMAL: u32 = 2;
+const HAS_COLOR: u32 = 4;
+const HAS_TANGENT: u32 = 8;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+// Where the cache for a model lives, right next to it
+pub fn cache_path(filename: &str) -> PathBuf {
+    PathBuf::from(format!("{}.meshcache", filename))
+}
+
+// Seconds and nanoseconds since the epoch, None where the platform doesn't know
+pub fn modified_time(filename: &str) -> Option<(u64, u32)> {
+    fs::metadata(filename).and_then(|m| m.modified()).ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| (d.as_secs(), d.subsec_nanos()))
+}
+
+// The contents of a file, mapped into memory where we know how and read otherwise
+enum FileData {
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    Mapped(mmap::Mapping),
+    Read(Vec<u8>),
+}
+
+impl Deref for FileData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            #[cfg(all(unix, target_pointer_width = "64"))]
+            FileData::Mapped(ref m) => m,
+            FileData::Read(ref data) => data,
+        }
+    }
+}
+
+fn load_file(filename: &Path) -> Result<FileData, Error> {
+    let mut f = try!(File::open(filename));
+
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    {
+        if let Some(mapping) = mmap::Mapping::new(&f) {
+            return Ok(FileData::Mapped(mapping));
+        }
+    }
+
+    let mut data = Vec::new();
+    try!(f.read_to_end(&mut data));
+    Ok(FileData::Read(data))
+}
+
+// Just the two calls we need, not worth a dependency
+#[cfg(all(unix, target_pointer_width = "64"))]
+mod mmap {
+    use std::fs::File;
+    use std::ops::Deref;
+    use std::os::raw::{c_int, c_void};
+    use std::os::unix::io::AsRawFd;
+    use std::slice;
+
+    const PROT_READ: c_int = 1;
+    const MAP_PRIVATE: c_int = 2;
+
+    extern "C" {
+        fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+
+    pub struct Mapping {
+        ptr: *mut c_void,
+        len: usize,
+    }
+
+    impl Mapping {
+        // None if the file can't be mapped, e.g. because it's empty
+        pub fn new(file: &File) -> Option<Mapping> {
+            let len = match file.metadata() {
+                Ok(m) if m.len() > 0 => m.len() as usize,
+                _ => return None,
+            };
+            let ptr = unsafe { mmap(0 as *mut c_void, len, PROT_READ, MAP_PRIVATE, file.as_raw_fd(), 0) };
+            // MAP_FAILED is -1
+            if ptr as isize == -1 {
+                None
+            } else {
+                Some(Mapping { ptr: ptr, len: len })
+            }
+        }
+    }
+
+    impl Deref for Mapping {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            unsafe {
+                munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+// Written next to the cache and then renamed over it. Another process may have the old
+// cache mapped, and truncating a mapped file out from under it would crash that process.
+pub fn write_mesh_cache(model: &Model, filename: &Path, model_filename: &str, source_time: (u64, u32))
+                        -> Result<(), Error> {
+    let mut body = Vec::new();
+    try!(write_model(&mut body, model, model_filename));
+
+    let mut temp = filename.as_os_str().to_owned();
+    temp.push(format!(".tmp{}", process::id()));
+    let temp = PathBuf::from(temp);
+
+    let written = File::create(&temp).and_then(|f| {
+        let mut f = BufWriter::new(f);
+        try!(f.write_all(MAGIC));
+        try!(f.write_u32::<LittleEndian>(VERSION));
+        try!(f.write_u64::<LittleEndian>(source_time.0));
+        try!(f.write_u32::<LittleEndian>(source_time.1));
+        try!(f.write_u32::<LittleEndian>(crc32(&body)));
+        try!(f.write_all(&body));
+        f.flush()
+    }).and_then(|_| fs::rename(&temp, filename));
+
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+// Fails if the cache is damaged, from another version, or older than the source file.
+// model_filename is where the model itself is, for finding its textures.
+pub fn read_mesh_cache(filename: &Path, model_filename: &str, source_time: (u64, u32)) -> Result<Model, Error> {
+    let data = try!(load_file(filename));
+    if data.len() < HEADER_SIZE || !data.starts_with(MAGIC) {
+        return Err(invalid("Not a mesh cache"));
+    }
+
+    let mut header = Cursor::new(&data[MAGIC.len()..HEADER_SIZE]);
+    if try!(header.read_u32::<LittleEndian>()) != VERSION {
+        return Err(invalid("Mesh cache is from another version"));
+    }
+    let time = (try!(header.read_u64::<LittleEndian>()), try!(header.read_u32::<LittleEndian>()));
+    if time != source_time {
+        return Err(invalid("Mesh cache is out of date"));
+    }
+    let body = &data[HEADER_SIZE..];
+    if try!(header.read_u32::<LittleEndian>()) != crc32(body) {
+        return Err(invalid("Mesh cache checksum doesn't match"));
+    }
+
+    read_model(body, model_filename)
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> Result<(), Error> {
+    try!(w.write_u32::<LittleEndian>(s.len() as u32));
+    w.write_all(s.as_bytes())
+}
+
+fn write_f64s<W: Write>(w: &mut W, values: &[f64]) -> Result<(), Error> {
+    for &v in values.iter() {
+        try!(w.write_f64::<LittleEndian>(v));
+    }
+    Ok(())
+}
+
+// The body starts 8 byte aligned in the file, so this keeps whatever comes next aligned too
+fn pad(w: &mut Vec<u8>) {
+    while w.len() % 8 != 0 {
+        w.push(0);
+    }
+}
+
+// The attributes of a set of vertices, each present one as its own array. The colors go
+// last as they're the only ones that aren't 8 bytes a value.
+fn write_vertices(w: &mut Vec<u8>, vertices: &Mesh) -> Result<(), Error> {
+    let flags = (if vertices.uvs.is_empty() { 0 } else { HAS_TEXTURE }) |
+                (if vertices.normals.is_empty() { 0 } else { HAS_NORMAL }) |
+                (if vertices.colors.is_empty() { 0 } else { HAS_COLOR }) |
+                (if vertices.tangents.is_empty() { 0 } else { HAS_TANGENT });
+    try!(w.write_u32::<LittleEndian>(vertices.vertex_count() as u32));
+    try!(w.write_u32::<LittleEndian>(flags));
+    for p in vertices.positions.iter() {
+        try!(write_f64s(w, &[p.x, p.y, p.z]));
+    }
+    for n in vertices.normals.iter() {
+        try!(write_f64s(w, &[n.x, n.y, n.z]));
+    }
+    for t in vertices.uvs.iter() {
+        try!(write_f64s(w, &[t.x, t.y]));
+    }
+    for &(t, handedness) in vertices.tangents.iter() {
+        try!(write_f64s(w, &[t.x, t.y, t.z, handedness]));
+    }
+    for c in vertices.colors.iter() {
+        try!(w.write_all(&[c.0, c.1, c.2, c.3]));
+    }
+    pad(w);
+    Ok(())
+}
+
+// Materials aren't stored, only where they came from. They're loaded again from the
+// libraries, which are quick to read and may have changed on their own. The same goes for
+// warnings about them, only the ones about the OBJ file are kept.
+fn write_model(w: &mut Vec<u8>, model: &Model, model_filename: &str) -> Result<(), Error> {
+    let mut names = Vec::new();
+    try!(names.write_u32::<LittleEndian>(model.material_libraries.len() as u32));
+    for library in model.material_libraries.iter() {
+        try!(write_string(&mut names, &library.to_string_lossy()));
+    }
+    try!(names.write_u32::<LittleEndian>(model.materials.len() as u32));
+    for material in model.materials.iter() {
+        try!(write_string(&mut names, &material.name));
+    }
+    try!(names.write_u32::<LittleEndian>(model.parts.len() as u32));
+    for part in model.parts.iter() {
+        try!(write_string(&mut names, &part.name));
+        try!(write_string(&mut names, &part.object));
+        try!(names.write_u8(part.visible as u8));
+    }
+    // Sorted so the same model always makes the same cache
+    let mut attributes: Vec<(&String, &Vec<f64>)> = model.attributes.iter().collect();
+    attributes.sort_by(|a, b| a.0.cmp(b.0));
+    try!(names.write_u32::<LittleEndian>(attributes.len() as u32));
+    for &(name, _) in attributes.iter() {
+        try!(write_string(&mut names, name));
+    }
+    let obj_warnings: Vec<(usize, &String, &String)> = model.warnings.iter().filter_map(|w| match *w {
+        LoadError::Parse { ref path, line, ref token, ref message } if path == Path::new(model_filename) =>
+            Some((line, token, message)),
+        _ => None,
+    }).collect();
+    try!(names.write_u32::<LittleEndian>(obj_warnings.len() as u32));
+    for &(line, token, message) in obj_warnings.iter() {
+        try!(names.write_u32::<LittleEndian>(line as u32));
+        try!(write_string(&mut names, token));
+        try!(write_string(&mut names, message));
+    }
+    try!(w.write_u32::<LittleEndian>(names.len() as u32));
+    try!(w.write_all(&names));
+    pad(w);
+
+    try!(w.write_f64::<LittleEndian>(model.opacity));
+    try!(write_vertices(w, &model.mesh));
+    try!(w.write_u32::<LittleEndian>(model.mesh.indices.len() as u32));
+    for &i in model.mesh.indices.iter() {
+        try!(w.write_u32::<LittleEndian>(i));
+    }
+    // One of each per triangle
+    for &material in model.face_materials.iter() {
+        try!(w.write_u32::<LittleEndian>(material as u32));
+    }
+    for &part in model.face_parts.iter() {
+        try!(w.write_u32::<LittleEndian>(part as u32));
+    }
+    pad(w);
+
+    let mut points = Mesh::new();
+    for v in model.points.iter() {
+        points.push_vertex(v);
+    }
+    try!(write_vertices(w, &points));
+
+    for &(_, values) in attributes.iter() {
+        try!(w.write_u32::<LittleEndian>(values.len() as u32));
+        pad(w);
+        try!(write_f64s(w, values));
+    }
+    Ok(())
+}
+
+// Only for plain numbers. The arrays are little endian and aligned in the file, and mapped
+// files start on a page, so on little endian machines they can be used right where they
+// are. Otherwise they're None and have to be copied out.
+fn borrow_array<T: Copy>(bytes: &[u8]) -> Option<&[T]> {
+    if cfg!(target_endian = "little") && bytes.as_ptr() as usize % mem::align_of::<T>() == 0 {
+        Some(unsafe { slice::from_raw_parts(bytes.as_ptr() as *const T, bytes.len() / mem::size_of::<T>()) })
+    } else {
+        None
+    }
+}
+
+// Walks the body of a cache. Counts come from the file, so everything is checked against
+// what's actually there before it's used.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], Error> {
+        match self.pos.checked_add(count) {
+            Some(end) if end <= self.data.len() => {
+                let bytes = &self.data[self.pos..end];
+                self.pos = end;
+                Ok(bytes)
+            },
+            _ => Err(Error::new(ErrorKind::UnexpectedEof, "Mesh cache is truncated")),
+        }
+    }
+
+    // Room for count items of the given size
+    fn array(&mut self, count: usize, size: usize) -> Result<&'a [u8], Error> {
+        match count.checked_mul(size) {
+            Some(len) => self.bytes(len),
+            None => Err(invalid("Bad count in mesh cache")),
+        }
+    }
+
+    // Skip the padding after a section
+    fn align(&mut self) -> Result<(), Error> {
+        let padding = (8 - self.pos % 8) % 8;
+        self.bytes(padding).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(try!(self.bytes(1))[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(LittleEndian::read_u32(try!(self.bytes(4))))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = try!(self.u32()) as usize;
+        let bytes = try!(self.bytes(len));
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("Bad string in mesh cache"))
+    }
+
+    // count items of components floats each
+    fn f64s(&mut self, count: usize, components: usize) -> Result<Cow<'a, [f64]>, Error> {
+        let bytes = try!(self.array(count, components * 8));
+        Ok(match borrow_array(bytes) {
+            Some(values) => Cow::Borrowed(values),
+            None => Cow::Owned(bytes.chunks(8).map(LittleEndian::read_f64).collect()),
+        })
+    }
+
+    fn u32s(&mut self, count: usize) -> Result<Cow<'a, [u32]>, Error> {
+        let bytes = try!(self.array(count, 4));
+        Ok(match borrow_array(bytes) {
+            Some(values) => Cow::Borrowed(values),
+            None => Cow::Owned(bytes.chunks(4).map(LittleEndian::read_u32).collect()),
+        })
+    }
+
+    fn vertices(&mut self) -> Result<Mesh, Error> {
+        let count = try!(self.u32()) as usize;
+        let flags = try!(self.u32());
+        let mut vertices = Mesh::new();
+        vertices.positions = try!(self.f64s(count, 3)).chunks(3).map(|p| Vec3::new(p[0], p[1], p[2])).collect();
+        if flags & HAS_NORMAL != 0 {
+            vertices.normals = try!(self.f64s(count, 3)).chunks(3).map(|n| Vec3::new(n[0], n[1], n[2])).collect();
+        }
+        if flags & HAS_TEXTURE != 0 {
+            vertices.uvs = try!(self.f64s(count, 2)).chunks(2).map(|t| Vec2::new(t[0], t[1])).collect();
+        }
+        if flags & HAS_TANGENT != 0 {
+            vertices.tangents = try!(self.f64s(count, 4)).chunks(4)
+                .map(|t| (Vec3::new(t[0], t[1], t[2]), t[3])).collect();
+        }
+        if flags & HAS_COLOR != 0 {
+            vertices.colors = try!(self.array(count, 4)).chunks(4)
+                .map(|c| Color(c[0], c[1], c[2], c[3])).collect();
+        }
+        try!(self.align());
+        Ok(vertices)
+    }
+}
+
+// Everything up to the vertex arrays is read a field at a time, the arrays themselves are
+// copied out in one go
+fn read_model(data: &[u8], model_filename: &str) -> Result<Model, Error> {
+    let mut r = Reader { data: data, pos: 0 };
+    let names_len = try!(r.u32()) as usize;
+    let mut names = Reader { data: try!(r.bytes(names_len)), pos: 0 };
+    try!(r.align());
+
+    let mut libraries = Vec::new();
+    for _ in 0..try!(names.u32()) {
+        libraries.push(PathBuf::from(try!(names.string())));
+    }
+    let mut material_names = Vec::new();
+    for _ in 0..try!(names.u32()) {
+        material_names.push(try!(names.string()));
+    }
+
+    // A library that changed enough to move the materials around needs a fresh parse
+    let mut warnings = Vec::new();
+    let materials = obj_materials(model_filename, &libraries, &mut warnings);
+    if materials.len() != material_names.len() ||
+        materials.iter().zip(material_names.iter()).any(|(m, n)| m.name != *n) {
+        return Err(invalid("Material libraries have changed since the mesh cache was made"));
+    }
+
+    let mut parts = Vec::new();
+    for _ in 0..try!(names.u32()) {
+        let name = try!(names.string());
+        let object = try!(names.string());
+        let visible = try!(names.u8()) != 0;
+        parts.push(Part { name: name, object: object, visible: visible });
+    }
+    let mut attribute_names = Vec::new();
+    for _ in 0..try!(names.u32()) {
+        attribute_names.push(try!(names.string()));
+    }
+    for _ in 0..try!(names.u32()) {
+        let line = try!(names.u32()) as usize;
+        let token = try!(names.string());
+        let message = try!(names.string());
+        warnings.push(LoadError::parse(model_filename, line, &token, &message));
+    }
+
+    let opacity = LittleEndian::read_f64(try!(r.bytes(8)));
+    let mut mesh = try!(r.vertices());
+    let index_count = try!(r.u32()) as usize;
+    let indices = try!(r.u32s(index_count));
+    if index_count % 3 != 0 || indices.iter().any(|&i| i as usize >= mesh.vertex_count()) {
+        return Err(invalid("Bad vertex index in mesh cache"));
+    }
+    mesh.indices = indices.into_owned();
+
+    let face_materials: Vec<usize> = try!(r.u32s(index_count / 3)).iter().map(|&m| m as usize).collect();
+    let face_parts: Vec<usize> = try!(r.u32s(index_count / 3)).iter().map(|&p| p as usize).collect();
+    if face_materials.iter().any(|&m| m >= materials.len()) || face_parts.iter().any(|&p| p >= parts.len()) {
+        return Err(invalid("Bad material or part in mesh cache"));
+    }
+    try!(r.align());
+
+    let point_attributes = try!(r.vertices());
+    let points = (0..point_attributes.vertex_count()).map(|i| point_attributes.vertex(i)).collect();
+
+    let mut attributes = HashMap::new();
+    for name in attribute_names.into_iter() {
+        let count = try!(r.u32()) as usize;
+        try!(r.align());
+        let values = try!(r.f64s(count, 1)).into_owned();
+        attributes.insert(name, values);
+    }
+
+    Ok(Model {
+        mesh: mesh,
+        face_materials: face_materials,
+        materials: materials,
+        material_libraries: libraries,
+        face_parts: face_parts,
+        parts: parts,
+        points: points,
+        attributes: attributes,
+        opacity: opacity,
+        warnings: warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::WHITE;
+    use testutil::TempDir;
+
+    const SOURCE: &'static str = "mtllib source.mtl
+v -1 -1 0 1 0 0
+v 1 -1 0 0 1 0
+v 1 1 0.1234567891 0 0 1
+v -1 1 0
+vt 0 0
+vt 1 0
+vt 0.1234567891 1
+vn 0 0 1
+g front
+usemtl red
+f 1/1/1 2/2/1 3/3/1
+g back
+f 1 3 4
+f 1 3 9
+";
+
+    const TIME: (u64, u32) = (1500000000, 123);
+
+    fn setup(dir: &TempDir) -> (Model, String) {
+        let obj = dir.file("source.obj");
+        fs::write(&obj, SOURCE).unwrap();
+        fs::write(dir.file("source.mtl"), "newmtl red\nKd 1 0 0\n").unwrap();
+        (Model::from_obj(&obj).unwrap(), obj)
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("meshcache_round_trip");
+        let (model, obj) = setup(&dir);
+        let cache = cache_path(&obj);
+        write_mesh_cache(&model, &cache, &obj, TIME).unwrap();
+        let cached = read_mesh_cache(&cache, &obj, TIME).unwrap();
+
+        // Numbers come back exactly, including ones an f32 can't hold and the normals
+        // worked out from them
+        assert_eq!(format!("{:?}", model.mesh.positions), format!("{:?}", cached.mesh.positions));
+        assert!(cached.mesh.positions.iter().any(|p| p.z == 0.1234567891));
+        assert_eq!(format!("{:?}", model.mesh.normals), format!("{:?}", cached.mesh.normals));
+        assert_eq!(format!("{:?}", model.mesh.uvs), format!("{:?}", cached.mesh.uvs));
+        assert!(cached.mesh.uvs.iter().any(|t| t.x == 0.1234567891));
+        assert_eq!(model.mesh.colors, cached.mesh.colors);
+        assert_eq!(model.mesh.indices, cached.mesh.indices);
+        assert_eq!(model.face_materials, cached.face_materials);
+        assert_eq!(model.face_parts, cached.face_parts);
+        assert_eq!(model.parts.iter().map(|p| &p.name).collect::<Vec<_>>(),
+                   cached.parts.iter().map(|p| &p.name).collect::<Vec<_>>());
+        assert_eq!(model.materials.iter().map(|m| &m.name).collect::<Vec<_>>(),
+                   cached.materials.iter().map(|m| &m.name).collect::<Vec<_>>());
+        // Like the mesh, points without a color come back white when others have one,
+        // which is how they're drawn anyway
+        assert_eq!(model.points.len(), cached.points.len());
+        for (p, q) in model.points.iter().zip(cached.points.iter()) {
+            assert_eq!(format!("{:?}", p.coords), format!("{:?}", q.coords));
+            assert_eq!(p.color().unwrap_or(WHITE), q.color().unwrap());
+        }
+        assert_eq!(model.opacity, cached.opacity);
+
+        // Warnings about the OBJ file come back along with the ones about its materials
+        let messages = |m: &Model| {
+            let mut messages: Vec<String> = m.warnings.iter().map(|w| w.to_string()).collect();
+            messages.sort();
+            messages
+        };
+        assert!(model.warnings.iter().any(|w| w.path() == Path::new(&obj)));
+        assert_eq!(messages(&model), messages(&cached));
+    }
+
+    #[test]
+    fn rejects_damaged_or_stale_caches() {
+        let dir = TempDir::new("meshcache_damaged");
+        let (model, obj) = setup(&dir);
+        let cache = cache_path(&obj);
+        write_mesh_cache(&model, &cache, &obj, TIME).unwrap();
+        let data = fs::read(&cache).unwrap();
+
+        assert!(read_mesh_cache(&cache, &obj, (TIME.0 + 1, TIME.1)).is_err());
+
+        let mut corrupted = data.clone();
+        let last = corrupted.len() - 5;
+        corrupted[last] ^= 0x40;
+        fs::write(&cache, &corrupted).unwrap();
+        let error = read_mesh_cache(&cache, &obj, TIME).err().unwrap();
+        assert!(error.to_string().contains("checksum"));
+
+        fs::write(&cache, &data[..data.len() - 8]).unwrap();
+        assert!(read_mesh_cache(&cache, &obj, TIME).is_err());
+        fs::write(&cache, &data[..10]).unwrap();
+        assert!(read_mesh_cache(&cache, &obj, TIME).is_err());
+
+        // A library with different materials makes the cache useless
+        fs::write(&cache, &data).unwrap();
+        assert!(read_mesh_cache(&cache, &obj, TIME).is_ok());
+        fs::write(dir.file("source.mtl"), "newmtl blue\nKd 0 0 1\n").unwrap();
+        assert!(read_mesh_cache(&cache, &obj, TIME).is_err());
+    }
+
+    #[test]
+    fn replaces_caches_in_use() {
+        let dir = TempDir::new("meshcache_replace");
+        let (model, obj) = setup(&dir);
+        let cache = cache_path(&obj);
+        write_mesh_cache(&model, &cache, &obj, TIME).unwrap();
+        let data = fs::read(&cache).unwrap();
+
+        // Whoever has the old cache open keeps seeing it whole, and nothing is left behind
+        let in_use = load_file(&cache).unwrap();
+        write_mesh_cache(&model, &cache, &obj, (TIME.0 + 1, TIME.1)).unwrap();
+        assert_eq!(&in_use[..], &data[..]);
+        assert!(read_mesh_cache(&cache, &obj, (TIME.0 + 1, TIME.1)).is_ok());
+        assert_eq!(fs::read_dir(&dir.path).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn rebuilds_with_a_warning() {
+        let dir = TempDir::new("meshcache_rebuild");
+        let (model, obj) = setup(&dir);
+        let cache = cache_path(&obj);
+
+        let first = Model::from_obj_cached(&obj).unwrap();
+        assert!(cache.exists());
+        assert_eq!(first.mesh.indices, model.mesh.indices);
+
+        let mut data = fs::read(&cache).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&cache, &data).unwrap();
+        let rebuilt = Model::from_obj_cached(&obj).unwrap();
+        assert_eq!(rebuilt.mesh.indices, model.mesh.indices);
+        assert!(rebuilt.warnings.iter().any(|w| w.path() == cache.as_path()));
+
+        // The rebuilt cache is fine again
+        assert!(Model::from_obj_cached(&obj).unwrap().warnings.iter().all(|w| w.path() != cache.as_path()));
+    }
+}
@@ -0,0 +1,184 @@
+use image::{Image, Color};
+use hdr::HdrColor;
+use tonemap::{ColorSpace, srgb_to_linear};
+use std::f64::consts::PI;
+
+// How each level is filtered down from the one above it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MipFilter {
+    // Plain average of the texels covered, cheap but a bit blurry
+    Box,
+    // Kaiser windowed sinc, sharper levels with less aliasing
+    Kaiser,
+}
+
+// Width of the Kaiser window (in texels of the smaller level) and its shape parameter,
+// these are the values most texture tools default to
+const KAISER_RADIUS: f64 = 1.5;
+const KAISER_ALPHA: f64 = 4.0;
+
+// Every level below the image itself, down to 1x1. Texels are filtered in linear light,
+// averaging sRGB bytes directly would make every level darker than the one above.
+pub fn generate(image: &Image, filter: MipFilter, color_space: ColorSpace) -> Vec<Image> {
+    let decode = decode_table(color_space);
+    let mut levels: Vec<Image> = Vec::new();
+    let (mut width, mut height) = (image.width, image.height);
+
+    while width > 1 || height > 1 {
+        width = (width / 2).max(1);
+        height = (height / 2).max(1);
+
+        let level = {
+            let src = levels.last().unwrap_or(image);
+            match filter {
+                MipFilter::Box => box_downsample(src, width, height, &decode, color_space),
+                MipFilter::Kaiser => kaiser_downsample(src, width, height, &decode, color_space),
+            }
+        };
+        levels.push(level);
+    }
+
+    levels
+}
+
+// Light for every byte value of a color channel, alpha is always linear
+fn decode_table(color_space: ColorSpace) -> Vec<f64> {
+    (0..256).map(|v| {
+        let v = v as f32 / 255.0;
+        match color_space {
+            ColorSpace::Srgb => srgb_to_linear(v) as f64,
+            ColorSpace::Linear => v as f64,
+        }
+    }).collect()
+}
+
+fn decode(c: Color, table: &[f64]) -> [f64; 4] {
+    [table[c.0 as usize], table[c.1 as usize], table[c.2 as usize], c.3 as f64 / 255.0]
+}
+
+// Back to bytes from BGRA in linear light
+fn encode(c: [f64; 4], color_space: ColorSpace) -> Color {
+    color_space.encode(HdrColor::new(c[2] as f32, c[1] as f32, c[0] as f32, c[3].max(0.0).min(1.0) as f32))
+}
+
+fn box_downsample(src: &Image, width: i32, height: i32, table: &[f64], color_space: ColorSpace) -> Image {
+    let sx = src.width as f64 / width as f64;
+    let sy = src.height as f64 / height as f64;
+    let mut dst = Image::new(width, height);
+
+    for y in 0..height {
+        let y0 = (y as f64 * sy).floor() as i32;
+        let y1 = (((y + 1) as f64 * sy).ceil() as i32).min(src.height);
+        for x in 0..width {
+            let x0 = (x as f64 * sx).floor() as i32;
+            let x1 = (((x + 1) as f64 * sx).ceil() as i32).min(src.width);
+
+            let mut sum = [0.0; 4];
+            for j in y0..y1 {
+                for i in x0..x1 {
+                    let c = decode(src.get_pixel(i, j), table);
+                    for k in 0..4 {
+                        sum[k] += c[k];
+                    }
+                }
+            }
+
+            let n = ((x1 - x0) * (y1 - y0)).max(1) as f64;
+            dst.set_pixel(x, y, encode([sum[0] / n, sum[1] / n, sum[2] / n, sum[3] / n], color_space));
+        }
+    }
+
+    dst
+}
+
+// Separable - filter the rows into a float buffer, then the columns of that
+fn kaiser_downsample(src: &Image, width: i32, height: i32, table: &[f64], color_space: ColorSpace) -> Image {
+    let horizontal = kaiser_weights(src.width, width);
+    let vertical = kaiser_weights(src.height, height);
+
+    let mut rows = vec![[0.0f64; 4]; (width * src.height) as usize];
+    for y in 0..src.height {
+        for x in 0..width {
+            let mut sum = [0.0; 4];
+            for &(i, w) in horizontal[x as usize].iter() {
+                let c = decode(src.get_pixel(i, y), table);
+                for k in 0..4 {
+                    sum[k] += c[k] * w;
+                }
+            }
+            rows[(y * width + x) as usize] = sum;
+        }
+    }
+
+    let mut dst = Image::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = [0.0; 4];
+            for &(j, w) in vertical[y as usize].iter() {
+                let c = rows[(j * width + x) as usize];
+                for k in 0..4 {
+                    sum[k] += c[k] * w;
+                }
+            }
+            // The negative lobes of the sinc can ring past the valid range, encoding clips it
+            dst.set_pixel(x, y, encode(sum, color_space));
+        }
+    }
+
+    dst
+}
+
+// For every destination texel, the source texels it draws from and their normalized weights
+fn kaiser_weights(src_size: i32, dst_size: i32) -> Vec<Vec<(i32, f64)>> {
+    let scale = src_size as f64 / dst_size as f64;
+    let reach = (KAISER_RADIUS * scale).ceil() as i32;
+
+    (0..dst_size).map(|i| {
+        // Center of the destination texel in source texel coordinates
+        let center = (i as f64 + 0.5) * scale - 0.5;
+        let first = center.floor() as i32 - reach;
+
+        let mut taps: Vec<(i32, f64)> = Vec::new();
+        for j in first..(first + reach * 2 + 2) {
+            let t = (j as f64 - center) / scale;
+            let w = kaiser(t);
+            if w != 0.0 {
+                // Clamp to the edge
+                taps.push((j.max(0).min(src_size - 1), w));
+            }
+        }
+
+        let total: f64 = taps.iter().map(|&(_, w)| w).sum();
+        taps.iter().map(|&(j, w)| (j, w / total)).collect()
+    }).collect()
+}
+
+fn kaiser(t: f64) -> f64 {
+    if t.abs() >= KAISER_RADIUS {
+        return 0.0;
+    }
+    let r = t / KAISER_RADIUS;
+    sinc(t) * bessel_i0(KAISER_ALPHA * (1.0 - r * r).sqrt()) / bessel_i0(KAISER_ALPHA)
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// Zeroth order modified Bessel function of the first kind, by its power series
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (half / k) * (half / k);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
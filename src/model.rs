@@ -1,12 +1,23 @@
 use std::fs::File;
-use std::error::Error;
-use std::io::{BufReader, BufRead};
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufRead};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
 
 use image::*;
-use geo::{Vertex, Vec3, Triangle};
+use geo::{Vertex, Vec3, Triangle, FaceLight, cross_product};
 use num::ToPrimitive;
-use tga::read_tga_file;
+use state::DrawState;
+use oit::OitBuffer;
+use hdr::{HdrImage, HdrColor};
+use material::{Material, read_mtl_file};
+use triangulate::ear_clip;
+use stl::{StlFormat, read_stl_file, write_stl_file};
+use ply::read_ply_file;
+use mesh::{Mesh, MeshBuilder, VertexCache};
+use meshcache::{cache_path, modified_time, read_mesh_cache, write_mesh_cache};
+use gltf::read_gltf_file;
+use obj::write_obj_file;
+use error::LoadError;
 
 fn find_relative_file(origin: &str, relation: &str) -> PathBuf {
     let mut path = PathBuf::from(origin);
@@ -25,67 +36,329 @@ fn find_relative_file(origin: &str, relation: &str) -> PathBuf {
     path
 }
 
+// Texture formats we'll look for next to a model, in order of preference
+const TEXTURE_EXTENSIONS: [&'static str; 3] = ["tga", "png", "bmp"];
+
+// The first texture with the given suffix that exists next to the model
+fn find_texture(origin: &str, suffix: &str) -> PathBuf {
+    let candidates: Vec<PathBuf> = TEXTURE_EXTENSIONS.iter()
+        .map(|ext| find_relative_file(origin, &format!("{}.{}", suffix, ext)))
+        .collect();
+
+    match candidates.iter().find(|p| p.exists()) {
+        Some(path) => path.clone(),
+        None => candidates[0].clone(),
+    }
+}
+
+// Without a material library we'll assume that the texture is in a file in the same
+// directory. Without one it's left untextured, with a warning saying where we looked.
+fn default_material(filename: &str, warnings: &mut Vec<LoadError>) -> Material {
+    let texture_file = find_texture(filename, "_diffuse");
+    match read_image_file(texture_file.to_str().unwrap()) {
+        Ok(texture) => Material::with_texture("default", texture_file, texture),
+        Err(e) => {
+            warnings.push(e);
+            Material::new("default")
+        }
+    }
+}
+
+// A missing or broken library just means the faces using it get the default material
+fn read_material_library(path: &Path, warnings: &mut Vec<LoadError>) -> Vec<Material> {
+    match read_mtl_file(path.to_str().unwrap(), warnings) {
+        Ok(library) => library,
+        Err(e) => {
+            warnings.push(e);
+            Vec::new()
+        }
+    }
+}
+
+// The materials of an OBJ file with the given libraries, the same as from_obj ends up with
+pub fn obj_materials(filename: &str, libraries: &[PathBuf], warnings: &mut Vec<LoadError>) -> Vec<Material> {
+    let mut materials = vec![default_material(filename, warnings)];
+    for library in libraries.iter() {
+        materials.extend(read_material_library(library, warnings));
+    }
+    materials
+}
+
+// Index of the part with the given name, added if it's new
+fn find_part(parts: &mut Vec<Part>, name: &str, object: &str) -> usize {
+    match parts.iter().position(|p| p.name == name && p.object == object) {
+        Some(i) => i,
+        None => {
+            parts.push(Part { name: name.to_string(), object: object.to_string(), visible: true });
+            parts.len() - 1
+        }
+    }
+}
+
+// The numbers after the keyword of a v, vt or vn line, up to a trailing comment
+fn parse_numbers<'a, I: Iterator<Item = &'a str>>(tokens: I, filename: &str, line: usize) -> Result<Vec<f64>, LoadError> {
+    let mut numbers = Vec::new();
+    for token in tokens.take_while(|t| !t.starts_with('#')) {
+        match token.parse::<f64>() {
+            Ok(n) => numbers.push(n),
+            Err(_) => return Err(LoadError::parse(filename, line, token, "expected a number")),
+        }
+    }
+    Ok(numbers)
+}
+
+// OBJ indices start at one, negative ones count back from the last element read so far
+fn resolve_index(token: &str, count: usize) -> Option<usize> {
+    match token.parse::<isize>() {
+        Ok(i) if i > 0 && i as usize <= count => Some(i as usize - 1),
+        Ok(i) if i < 0 && (-i) as usize <= count => Some(count - (-i) as usize),
+        _ => None,
+    }
+}
+
+// A face as read from the file, before triangulation
+struct ObjFace {
+    // Vertex, texture coordinate and normal index for every corner
+    corners: Vec<(usize, Option<usize>, Option<usize>)>,
+    material: usize,
+    part: usize,
+    // 0 is off, the face stays flat
+    smoothing: u32,
+}
+
+// A named piece of the model from an o or g statement, e.g. the eyes of a character
+pub struct Part {
+    pub name: String,
+    // The o statement the part belongs to, if any
+    pub object: String,
+    pub visible: bool,
+}
+
+// Light reaching every face from all around, Ka says how much of it a material reflects
+const AMBIENT_LIGHT: f32 = 0.1;
+
 pub struct Model {
     // TODO: Not sure if these should be public
-    pub faces: Vec<Triangle>,
-    texture: Option<Image>,
+    pub mesh: Mesh,
+    // Index into materials for every face
+    pub face_materials: Vec<usize>,
+    // The first one is the default, for faces without a usemtl
+    pub materials: Vec<Material>,
+    // The mtllib files the materials after the default came from, in order
+    pub material_libraries: Vec<PathBuf>,
+    // Index into parts for every face
+    pub face_parts: Vec<usize>,
+    // The first one holds anything before the first o or g
+    pub parts: Vec<Part>,
+    // Every vertex in the file, whether a face uses it or not - all there is for point clouds
+    pub points: Vec<Vertex>,
+    // Extra per point values from the file by name, in the same order as points
+    pub attributes: HashMap<String, Vec<f64>>,
+    // Multiplied into the alpha of every fragment, 1.0 is fully opaque
+    pub opacity: f64,
+    // Problems that didn't stop the model loading, like a missing texture
+    pub warnings: Vec<LoadError>,
 }
 
 impl Model {
+    // Like load, for when there's nothing better to do than stop. Warnings are printed.
     pub fn new(filename: &str) -> Self {
-        let mut verts: Vec<Vertex> = Vec::new();
-        let mut face_idxs: Vec<Vec<usize>> = Vec::new();
-        let mut face_text: Vec<Vec<usize>> = Vec::new();
-        let mut text_coords = Vec::new();
+        match Model::load(filename) {
+            Ok(model) => {
+                for warning in model.warnings.iter() {
+                    eprintln!("Warning: {}", warning);
+                }
+                model
+            },
+            Err(why) => panic!("Couldn't load model: {}", why),
+        }
+    }
 
-        // We'll assume that the texture is in a file in the same directory
-        let texture_file = find_relative_file(filename, "_diffuse.tga");
+    // Picks the loader from the file extension, anything unknown is read as OBJ
+    pub fn load(filename: &str) -> Result<Model, LoadError> {
+        let extension = Path::new(filename).extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
 
-        let model_file = match File::open(&filename) {
-            Err(why) => panic!("Couldn't open {}: {}", filename,
-                               why.description()),
-            Ok(model_file) => model_file,
+        match extension.as_ref().map(|e| e.as_str()) {
+            Some("stl") => read_stl_file(filename),
+            Some("ply") => read_ply_file(filename),
+            Some("gltf") | Some("glb") => read_gltf_file(filename),
+            _ => Model::from_obj_cached(filename),
+        }
+    }
+
+    // Parsing a big OBJ file takes a while, so the result is kept in a binary file next to
+    // it. The cache is made again whenever the OBJ file changes. A cache that can't be read
+    // or written is only a warning, the OBJ file is still there.
+    pub fn from_obj_cached(filename: &str) -> Result<Model, LoadError> {
+        let source_time = match modified_time(filename) {
+            Some(time) => time,
+            None => return Model::from_obj(filename),
         };
 
+        let cache = cache_path(filename);
+        let mut cache_warnings = Vec::new();
+        if cache.exists() {
+            match read_mesh_cache(&cache, filename, source_time) {
+                Ok(model) => return Ok(model),
+                Err(e) => cache_warnings.push(LoadError::io(&cache, e)),
+            }
+        }
+
+        let mut model = try!(Model::from_obj(filename));
+        if let Err(e) = write_mesh_cache(&model, &cache, filename, source_time) {
+            cache_warnings.push(LoadError::io(&cache, e));
+        }
+        model.warnings.extend(cache_warnings);
+        Ok(model)
+    }
+
+    // A model with a single untextured material and part, for formats that only have geometry
+    pub fn from_faces(faces: Vec<Triangle>) -> Self {
+        Model::from_mesh(Mesh::from_triangles(&faces))
+    }
+
+    pub fn from_mesh(mesh: Mesh) -> Self {
+        Model {
+            face_materials: vec![0; mesh.triangle_count()],
+            materials: vec![Material::new("default")],
+            material_libraries: Vec::new(),
+            face_parts: vec![0; mesh.triangle_count()],
+            parts: vec![Part { name: "default".to_string(), object: String::new(), visible: true }],
+            mesh: mesh,
+            points: Vec::new(),
+            attributes: HashMap::new(),
+            opacity: 1.0,
+            warnings: Vec::new(),
+        }
+    }
+
+    // Numbers that don't parse are an error, faces pointing at vertices that don't exist
+    // are skipped with a warning
+    pub fn from_obj(filename: &str) -> Result<Model, LoadError> {
+        let mut verts: Vec<Vertex> = Vec::new();
+        let mut obj_faces: Vec<ObjFace> = Vec::new();
+        let mut text_coords: Vec<Vec<f64>> = Vec::new();
+        let mut normals: Vec<Vec3<f64>> = Vec::new();
+        let mut current_material = 0;
+        let mut parts = vec![Part { name: "default".to_string(), object: String::new(), visible: true }];
+        let mut current_part = 0;
+        let mut current_object = String::new();
+        let mut smoothing = 0;
+
+        let mut warnings: Vec<LoadError> = Vec::new();
+        let mut materials = vec![default_material(filename, &mut warnings)];
+        let mut libraries: Vec<PathBuf> = Vec::new();
+
+        let model_file = try!(File::open(&filename).map_err(|e| LoadError::io(filename, e)));
         let model_file = BufReader::new(model_file);
-        let texture = read_tga_file(texture_file.to_str().unwrap());
 
-        for line in model_file.lines() {
-            let line = line.unwrap();
+        for (index, line) in model_file.lines().enumerate() {
+            let line = try!(line.map_err(|e| LoadError::io(filename, e)));
+            let line_number = index + 1;
             if !line.is_empty() {
                 // Check the first character to figure out what we're looking at
                 let mut split_line = line.split_whitespace();
                 match split_line.next() {
                     Some("v") => {
                         // Parse vertices into a Vertex and add to the model struct
-                        let vertices = split_line.filter_map(|s| s.parse::<f64>().ok()).collect::<Vec<_>>();
-                        let vertex = Vertex::from_vec(vertices);
+                        let mut vertices = try!(parse_numbers(split_line, filename, line_number));
+                        while vertices.len() < 3 {
+                            vertices.push(0.0);
+                        }
+                        // Some programs put a 0 to 1 color after the position
+                        let color = if vertices.len() >= 6 {
+                            let channel = |v: f64| (v * 255.0).round().max(0.0).min(255.0) as u8;
+                            Some(Color(channel(vertices[5]), channel(vertices[4]), channel(vertices[3]), 255))
+                        } else {
+                            None
+                        };
+                        let mut vertex = Vertex::from_vec(vertices);
+                        if let Some(color) = color {
+                            vertex.set_color(color);
+                        }
                         verts.push(vertex);
                     },
                     Some("f") => {
-                        // The first number has the idxs of the corners for the face
-                        // The second number has the coords of the texture
-                        let mut face = Vec::new();
-                        let mut texture = Vec::new();
-
-                        for block in split_line {
-                            let content = block.split('/').map(|s| s.parse::<usize>().ok()).collect::<Vec<_>>();
-                            face.push(content[0].unwrap());
-                            texture.push(content[1].unwrap());
-                        }
+                        // Each corner is v, v/vt, v//vn or v/vt/vn
+                        let mut corners = Vec::new();
+
+                        for block in split_line.take_while(|b| !b.starts_with('#')) {
+                            let content = block.split('/').collect::<Vec<_>>();
+                            if content.iter().any(|s| !s.is_empty() && s.parse::<isize>().is_err()) {
+                                return Err(LoadError::parse(filename, line_number, block, "expected an index"));
+                            }
+                            let vertex = resolve_index(content[0], verts.len());
+                            let texture = content.get(1).and_then(|s| resolve_index(s, text_coords.len()));
+                            let normal = content.get(2).and_then(|s| resolve_index(s, normals.len()));
 
-                        // Subtract one because they don't zero index :(
-                        let face = face.iter().map(|f| (f - 1)).collect();
-                        let texture = texture.iter().map(|f| (f - 1)).collect();
+                            match vertex {
+                                Some(v) => corners.push((v, texture, normal)),
+                                None => {
+                                    warnings.push(LoadError::parse(filename, line_number, block,
+                                                                   "skipped face with a vertex that doesn't exist"));
+                                    corners.clear();
+                                    break;
+                                }
+                            }
+                        }
 
-                        face_idxs.push(face);
-                        face_text.push(texture);
+                        if corners.len() >= 3 {
+                            obj_faces.push(ObjFace {
+                                corners: corners,
+                                material: current_material,
+                                part: current_part,
+                                smoothing: smoothing,
+                            });
+                        }
                     },
                     Some("vt") => {
                         // Parse texture coordinates
-                        let coords = split_line.filter_map(|s| s.parse::<f64>().ok()).collect::<Vec<_>>();
+                        let mut coords = try!(parse_numbers(split_line, filename, line_number));
+                        if coords.is_empty() {
+                            coords.push(0.0);
+                        }
                         text_coords.push(coords);
-                    }
+                    },
+                    Some("vn") => {
+                        let mut coords = try!(parse_numbers(split_line, filename, line_number));
+                        while coords.len() < 3 {
+                            coords.push(0.0);
+                        }
+                        normals.push(Vec3::from_vec(coords));
+                    },
+                    Some("mtllib") => {
+                        // Library paths are relative to the model
+                        let name = split_line.collect::<Vec<_>>().join(" ");
+                        let mut path = PathBuf::from(filename);
+                        path.set_file_name(name);
+
+                        materials.extend(read_material_library(&path, &mut warnings));
+                        libraries.push(path);
+                    },
+                    Some("usemtl") => {
+                        // Later definitions win if a name is used twice, unknown names get
+                        // the default
+                        let name = split_line.collect::<Vec<_>>().join(" ");
+                        current_material = materials.iter().rposition(|m| m.name == name).unwrap_or(0);
+                    },
+                    Some("o") => {
+                        current_object = split_line.collect::<Vec<_>>().join(" ");
+                        current_part = find_part(&mut parts, &current_object, &current_object);
+                    },
+                    Some("g") => {
+                        // A bare g goes back to the object itself
+                        let name = split_line.collect::<Vec<_>>().join(" ");
+                        let name = if name.is_empty() { current_object.clone() } else { name };
+                        current_part = find_part(&mut parts, &name, &current_object);
+                    },
+                    Some("s") => {
+                        smoothing = match split_line.next() {
+                            Some("off") | None => 0,
+                            Some(group) => group.parse::<u32>().unwrap_or(0),
+                        };
+                    },
                     _ => {
 
                     }
@@ -93,36 +366,102 @@ impl Model {
             }
         }
 
-        println!("vertices: {}, faces: {}", verts.len(), face_idxs.len());
+        // Anything bigger than a triangle gets split up
+        let mut triangles: Vec<([(usize, Option<usize>, Option<usize>); 3], &ObjFace)> = Vec::new();
+        for face in obj_faces.iter() {
+            let points: Vec<Vec3<f64>> = face.corners.iter().map(|c| verts[c.0].coords).collect();
+            for tri in ear_clip(&points) {
+                triangles.push(([face.corners[tri[0]], face.corners[tri[1]], face.corners[tri[2]]], face));
+            }
+        }
 
-        let mut faces: Vec<Triangle> = Vec::new();
+        // Corners without a normal in the file get one averaged from the faces around their
+        // vertex in the same smoothing group, weighted by area
+        let face_normal = |tri: &[(usize, Option<usize>, Option<usize>); 3]| {
+            let a = verts[tri[0].0].coords;
+            cross_product(verts[tri[1].0].coords - a, verts[tri[2].0].coords - a)
+        };
+        let mut smooth_normals: HashMap<(usize, u32), Vec3<f64>> = HashMap::new();
+        for &(ref tri, face) in triangles.iter() {
+            if face.smoothing != 0 {
+                let n = face_normal(tri);
+                for corner in tri.iter() {
+                    let sum = smooth_normals.entry((corner.0, face.smoothing)).or_insert(Vec3::new(0.0, 0.0, 0.0));
+                    *sum = *sum + n;
+                }
+            }
+        }
 
-        for (face_idx, text_idx) in face_idxs.iter().zip(face_text.iter()) {
-            let mut vertices: Vec<Vertex> = Vec::with_capacity(3);
+        let mut mesh = MeshBuilder::new();
+        let mut face_materials: Vec<usize> = Vec::with_capacity(triangles.len());
+        let mut face_parts: Vec<usize> = Vec::with_capacity(triangles.len());
 
-            for (idx, f) in face_idx.iter().enumerate() {
-                let mut vert = verts[*f];
-                vert.set_texture(vec![text_coords[text_idx[idx]][0],
-                                 text_coords[text_idx[idx]][1]]);
-                vertices.push(vert);
-            }
+        for &(ref tri, face) in triangles.iter() {
+            let flat = face_normal(tri);
+            let corner = |&(v, vt, vn): &(usize, Option<usize>, Option<usize>)| {
+                let mut vert = verts[v];
+                if let Some(t) = vt {
+                    vert.set_texture(text_coords[t].clone());
+                }
+                let normal = match vn {
+                    Some(n) => normals[n],
+                    None if face.smoothing != 0 => smooth_normals[&(v, face.smoothing)],
+                    None => flat,
+                };
+                // Degenerate faces don't have a direction to give
+                if normal.x != 0.0 || normal.y != 0.0 || normal.z != 0.0 {
+                    vert.set_normal(normal.normalize());
+                }
+                vert
+            };
 
-            let tri = Triangle::new(vertices);
-            faces.push(tri);
+            mesh.add_triangle(&[corner(&tri[0]), corner(&tri[1]), corner(&tri[2])]);
+            face_materials.push(face.material);
+            face_parts.push(face.part);
         }
 
-        Model {
-            faces: faces,
-            texture: texture.ok()
-        }
+        Ok(Model {
+            mesh: mesh.build(),
+            face_materials: face_materials,
+            materials: materials,
+            material_libraries: libraries,
+            face_parts: face_parts,
+            parts: parts,
+            points: verts,
+            attributes: HashMap::new(),
+            opacity: 1.0,
+            warnings: warnings,
+        })
     }
 
-    pub fn draw(&self, mut image: &mut Image, light_dir: Vec3<i32>) {
-        // Iterate over the faces in the model and draw the triangles
-        for face in self.faces.iter() {
+    pub fn draw(&self, image: &mut Image, light_dir: Vec3<i32>) {
+        self.draw_with_state(image, light_dir, &DrawState::default());
+    }
 
-            // Scale the triangle to the screen size
-            let tri = &face.scale_to_image(image.width, image.height);
+    // Hidden parts are skipped
+    pub fn draw_with_state(&self, image: &mut Image, light_dir: Vec3<i32>, state: &DrawState) {
+        self.draw_faces(image, light_dir, state, &|face| self.is_visible(face));
+    }
+
+    // Draw only the parts with the given name, or belonging to the object with that name,
+    // whether they're hidden or not
+    pub fn draw_part(&self, image: &mut Image, name: &str, light_dir: Vec3<i32>, state: &DrawState) {
+        let parts = self.parts_named(name);
+        self.draw_faces(image, light_dir, state, &|face| parts.contains(&self.face_parts[face]));
+    }
+
+    fn draw_faces(&self, mut image: &mut Image, light_dir: Vec3<i32>, state: &DrawState,
+                  wanted: &Fn(usize) -> bool) {
+        // Vertices shared between faces only get scaled to the screen once
+        let mut cache = VertexCache::new(&self.mesh, state.viewport_rect(image));
+
+        // Iterate over the faces in the model and draw the triangles
+        for i in 0..self.mesh.triangle_count() {
+            if !wanted(i) {
+                continue;
+            }
+            let material = self.material(i);
+            let tri = &cache.triangle(i);
 
             // Calculate the surface normal
             let norm = tri.surface_normal();
@@ -132,11 +471,8 @@ impl Model {
                 // let shade = Color((intensity * 255.0).to_u8().unwrap(),
                 //                   (intensity * 255.0).to_u8().unwrap(),
                 //                   (intensity * 255.0).to_u8().unwrap());
-                let shade = Color((intensity * 255.0).to_u8().unwrap(),
-                                  (intensity * 255.0).to_u8().unwrap(),
-                                  (intensity * 255.0).to_u8().unwrap());
-                let texture = &self.texture;
-                tri.draw(&mut image, shade, texture.as_ref().unwrap());
+                let shade = self.shade(intensity, material);
+                tri.draw(&mut image, shade, material.texture(), state);
                 // bb_triangle(tri.vertices[0].screen_coords.to_i32(),
                 //             tri.vertices[1].screen_coords.to_i32(),
                 //             tri.vertices[2].screen_coords.to_i32(),
@@ -146,4 +482,381 @@ impl Model {
             }
         }
     }
+
+    // Accumulate the model into the OIT buffer instead of the image - call after all the
+    // opaque models have been drawn, then resolve the buffer into the image
+    pub fn draw_translucent(&self, image: &mut Image, oit: &mut OitBuffer, light_dir: Vec3<i32>,
+                            state: &DrawState) {
+        let mut cache = VertexCache::new(&self.mesh, state.viewport_rect(image));
+        for i in 0..self.mesh.triangle_count() {
+            if !self.is_visible(i) {
+                continue;
+            }
+            let material = self.material(i);
+            let tri = &cache.triangle(i);
+
+            let norm = tri.surface_normal();
+            let intensity = norm * light_dir.to_f64();
+
+            if intensity > 0.0 {
+                let shade = self.shade(intensity, material);
+                tri.draw_translucent(image, oit, shade, material.texture(), state);
+            }
+        }
+    }
+
+    // Render into a float framebuffer. Light intensity isn't clipped at 1.0 so the light
+    // direction can be scaled up for lights brighter than white.
+    pub fn draw_hdr(&self, image: &mut HdrImage, light_dir: Vec3<f64>, state: &DrawState) {
+        let mut cache = VertexCache::new(&self.mesh, state.viewport_within(image.rect()));
+        for i in 0..self.mesh.triangle_count() {
+            if !self.is_visible(i) {
+                continue;
+            }
+            let material = self.material(i);
+            let tri = &cache.triangle(i);
+
+            let norm = tri.surface_normal();
+            let intensity = norm * light_dir;
+
+            if intensity > 0.0 {
+                let shade = self.shade(intensity, material);
+                tri.draw_hdr(image, &self.light(norm, light_dir, material), shade, material.texture(), state);
+            }
+        }
+    }
+
+    // Draw every point as a square of size pixels, in its vertex color or white. Depth and
+    // stencil tested like triangles so clouds can be mixed with meshes.
+    pub fn draw_points(&self, image: &mut Image, size: i32, state: &DrawState) {
+        let viewport = state.viewport_rect(image);
+        let scissor = state.scissor_rect(image);
+        let alpha = (self.opacity.max(0.0).min(1.0) * 255.0).round() as u8;
+
+        for point in self.points.iter() {
+            let p = point.scale_to_viewport(&viewport).screen_coords;
+            let color = point.color().unwrap_or(WHITE);
+            let color = color.with_alpha((color.alpha() as u32 * alpha as u32 / 255) as u8);
+
+            let (x0, y0) = (p.x as i32 - size / 2, p.y as i32 - size / 2);
+            for y in y0..y0 + size.max(1) {
+                for x in x0..x0 + size.max(1) {
+                    if scissor.contains(x, y) && image.depth_stencil_test(x, y, p.z, &state.stencil) {
+                        image.set_depth(x, y, p.z);
+                        image.blend_pixel(x, y, color, &state.blend);
+                    }
+                }
+            }
+        }
+    }
+
+    // Draw the edges of every face, honoring the viewport and scissor of the state
+    pub fn draw_wireframe(&self, image: &mut Image, color: Color, state: &DrawState) {
+        let viewport = state.viewport_rect(image);
+        let scissor = state.scissor_rect(image);
+
+        let mut cache = VertexCache::new(&self.mesh, viewport);
+        for i in 0..self.mesh.triangle_count() {
+            if !self.is_visible(i) {
+                continue;
+            }
+            let tri = cache.triangle(i);
+            for i in 0..tri.vertices.len() {
+                let p1 = tri.vertices[i].screen_coords.xy().to_i32();
+                let p2 = tri.vertices[(i + 1) % tri.vertices.len()].screen_coords.xy().to_i32();
+                scissored_line(p1, p2, image, color, &scissor);
+            }
+        }
+    }
+
+    pub fn write_stl_file(&self, filename: &str, format: StlFormat) -> io::Result<()> {
+        write_stl_file(self, filename, format)
+    }
+
+    // Also writes a material library next to it, with the same name
+    pub fn write_obj(&self, filename: &str) -> io::Result<()> {
+        write_obj_file(self, filename)
+    }
+
+    // Center the model and scale it to fill -1 to 1, which is what the renderer draws.
+    // Handy for formats like STL that are in real world units.
+    pub fn fit_to_unit(&mut self) {
+        let mut min = Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        let mut max = Vec3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for p in self.mesh.positions.iter() {
+            min = Vec3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+            max = Vec3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+        }
+
+        let size = (max.x - min.x).max(max.y - min.y).max(max.z - min.z);
+        if !(size > 0.0) {
+            return;
+        }
+        let center = Vec3::new((min.x + max.x) / 2.0, (min.y + max.y) / 2.0, (min.z + max.z) / 2.0);
+        for p in self.mesh.positions.iter_mut() {
+            *p = (*p - center) * (2.0 / size);
+        }
+    }
+
+    pub fn material(&self, face: usize) -> &Material {
+        &self.materials[self.face_materials[face]]
+    }
+
+    // Show or hide parts by name or object name, false if there weren't any
+    pub fn set_part_visible(&mut self, name: &str, visible: bool) -> bool {
+        let parts = self.parts_named(name);
+        for &p in parts.iter() {
+            self.parts[p].visible = visible;
+        }
+        !parts.is_empty()
+    }
+
+    // Switch every face of the named parts to another material, false if either the
+    // part or the material doesn't exist
+    pub fn set_part_material(&mut self, name: &str, material: &str) -> bool {
+        let parts = self.parts_named(name);
+        let material = match self.materials.iter().rposition(|m| m.name == material) {
+            Some(m) => m,
+            None => return false,
+        };
+
+        for (face_material, part) in self.face_materials.iter_mut().zip(self.face_parts.iter()) {
+            if parts.contains(part) {
+                *face_material = material;
+            }
+        }
+        !parts.is_empty()
+    }
+
+    fn parts_named(&self, name: &str) -> Vec<usize> {
+        (0..self.parts.len()).filter(|&i| self.parts[i].name == name || self.parts[i].object == name).collect()
+    }
+
+    fn is_visible(&self, face: usize) -> bool {
+        self.parts[self.face_parts[face]].visible
+    }
+
+    // Blinn-Phong lighting of a face with the material, following the MTL illumination
+    // models: 0 is just the diffuse color, 1 adds ambient and diffuse lighting, 2 and up add
+    // a specular highlight. The length of light_dir is the brightness of the light, and the
+    // viewer looks down the z axis.
+    fn light(&self, normal: Vec3<f64>, light_dir: Vec3<f64>, material: &Material) -> FaceLight {
+        let black = HdrColor::new(0.0, 0.0, 0.0, 1.0);
+        if material.illum == 0 {
+            return FaceLight { diffuse: HdrColor::new(1.0, 1.0, 1.0, 1.0), specular: black };
+        }
+
+        let intensity = (normal * light_dir).max(0.0) as f32;
+        let ka = material.ambient.scale(AMBIENT_LIGHT);
+        let diffuse = HdrColor::new(ka.r + intensity, ka.g + intensity, ka.b + intensity, 1.0);
+        if material.illum < 2 {
+            return FaceLight { diffuse: diffuse, specular: black };
+        }
+
+        let brightness = (light_dir * light_dir).sqrt();
+        let half = (light_dir.normalize() + Vec3::new(0.0, 0.0, 1.0)).normalize();
+        let highlight = (normal * half).max(0.0).powf(material.shininess) * brightness;
+        FaceLight { diffuse: diffuse, specular: material.specular.scale(highlight as f32) }
+    }
+
+    // Gray shade for the light intensity, clipped since a Color can't go past white. Only
+    // the alpha is used when drawing, the 8 bit path draws textures unlit.
+    fn shade(&self, intensity: f64, material: &Material) -> Color {
+        let v = (intensity.max(0.0).min(1.0) * 255.0).to_u8().unwrap();
+        Color(v, v, v, self.alpha(material))
+    }
+
+    fn alpha(&self, material: &Material) -> u8 {
+        (self.opacity.max(0.0).min(1.0) * material.dissolve.max(0.0).min(1.0) * 255.0).round() as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use byteorder::{WriteBytesExt, LittleEndian};
+    use hdr::read_hdr_file;
+    use image::read_image_file;
+    use testutil::TempDir;
+
+    #[test]
+    fn malformed_obj() {
+        let dir = TempDir::new("malformed_obj");
+        let obj = dir.file("bad.obj");
+        fs::write(&obj, "v 0 0 0\nv 1 0 0\nv 0 x 0\nf 1 2 3\n").unwrap();
+        match Model::from_obj(&obj) {
+            Err(LoadError::Parse { line, ref token, .. }) => assert_eq!((line, token.as_str()), (3, "x")),
+            other => panic!("expected a parse error, got {:?}", other.err()),
+        }
+
+        fs::write(&obj, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1/q 2 3\n").unwrap();
+        assert!(Model::from_obj(&obj).is_err());
+
+        match Model::load(&dir.file("missing.obj")) {
+            Err(LoadError::Io { .. }) => {},
+            other => panic!("expected an io error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn face_syntax() {
+        let dir = TempDir::new("face_syntax");
+        let obj = dir.file("faces.obj");
+        let header = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nvn 0 0 1\n";
+        let load = |faces: &str| {
+            fs::write(&obj, format!("{}{}", header, faces)).unwrap();
+            let model = Model::from_obj(&obj).unwrap();
+            // The only warning should be about the missing default texture
+            assert_eq!(model.warnings.len(), 1, "{}", faces);
+            (0..model.mesh.triangle_count()).map(|i| model.mesh.triangle(i)).collect::<Vec<_>>()
+        };
+
+        let xyz = |v: Vec3<f64>| (v.x, v.y, v.z);
+        let plain = load("f 1 2 3\n");
+        assert_eq!(plain.len(), 1);
+        let positions: Vec<_> = plain[0].vertices.iter().map(|v| xyz(v.coords)).collect();
+        assert_eq!(positions, vec![(0.0, 0.0, 0.0), (1.0, 0.0, 0.0), (1.0, 1.0, 0.0)]);
+        assert_eq!(plain[0].vertices[0].normal().map(xyz), Some((0.0, 0.0, 1.0)));
+
+        // Normals without texture coordinates, and negative indices counting back from the
+        // last one read so far
+        let quads = [("f 1//1 2//1 3//1 4//1\n", false), ("f -4/-4 -3/-3 -2/-2 -1/-1\n", true),
+                     ("f 1/1/1 2/2/-1 3/3/1 4/4/1\n", true)];
+        for &(faces, textured) in quads.iter() {
+            let quad = load(faces);
+            assert_eq!(quad.len(), 2, "{}", faces);
+            for v in quad.iter().flat_map(|tri| tri.vertices.iter()) {
+                assert_eq!(v.normal().map(xyz), Some((0.0, 0.0, 1.0)), "{}", faces);
+                let uv = if textured { Some((v.coords.x, v.coords.y)) } else { None };
+                assert_eq!(v.texture().map(|t| (t.x, t.y)), uv, "{}", faces);
+            }
+        }
+
+        // A concave face comes out as triangles covering it with the same winding
+        fs::write(&obj, "v 0 0 0\nv 4 2 0\nv 1 2 0\nv 0 5 0\nf 1 2 3 4\n").unwrap();
+        let model = Model::from_obj(&obj).unwrap();
+        let mut area = 0.0;
+        for i in 0..model.mesh.triangle_count() {
+            let p: Vec<Vec3<f64>> = model.mesh.triangle(i).vertices.iter().map(|v| v.coords).collect();
+            let z = cross_product(p[1] - p[0], p[2] - p[0]).z;
+            assert!(z > 0.0);
+            area += z / 2.0;
+        }
+        assert!((area - 5.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn skipped_faces_and_missing_files_are_warnings() {
+        let dir = TempDir::new("obj_warnings");
+        let obj = dir.file("warn.obj");
+        fs::write(&obj, "mtllib missing.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\nf 1 2 9\n").unwrap();
+        let model = Model::from_obj(&obj).unwrap();
+        assert_eq!(model.mesh.triangle_count(), 1);
+
+        let paths: Vec<&Path> = model.warnings.iter().map(|w| w.path()).collect();
+        assert!(paths.contains(&Path::new(&dir.file("missing.mtl"))));
+        assert!(paths.contains(&Path::new(&dir.file("warn_diffuse.tga"))));
+        assert!(model.warnings.iter().any(|w| match *w {
+            LoadError::Parse { line: 6, .. } => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn garbage_files_are_errors() {
+        let dir = TempDir::new("garbage");
+        let garbage: Vec<u8> = (0..2000).map(|i| (i * 7919 % 251) as u8).collect();
+
+        for extension in ["stl", "ply", "gltf", "glb"].iter() {
+            let file = dir.file(&format!("garbage.{}", extension));
+            fs::write(&file, &garbage).unwrap();
+            assert!(Model::load(&file).is_err(), "{}", extension);
+            fs::write(&file, b"").unwrap();
+            assert!(Model::load(&file).is_err(), "empty {}", extension);
+        }
+
+        for extension in ["tga", "png", "bmp", "ppm", "pam"].iter() {
+            let file = dir.file(&format!("garbage.{}", extension));
+            fs::write(&file, &garbage).unwrap();
+            assert!(read_image_file(&file).is_err(), "{}", extension);
+        }
+
+        let file = dir.file("image.xyz");
+        fs::write(&file, &garbage).unwrap();
+        match read_image_file(&file) {
+            Err(LoadError::Unsupported { .. }) => {},
+            other => panic!("expected unsupported, got {:?}", other.err()),
+        }
+    }
+
+    // Files with believable headers whose sizes and types used to crash the loaders
+    #[test]
+    fn crafted_headers_are_errors() {
+        let dir = TempDir::new("crafted");
+        let check = |name: &str, data: &[u8]| {
+            let file = dir.file(name);
+            fs::write(&file, data).unwrap();
+            let result = match Path::new(name).extension().and_then(|e| e.to_str()) {
+                Some("hdr") => read_hdr_file(&file).map(|_| ()),
+                Some("tga") | Some("bmp") => read_image_file(&file).map(|_| ()),
+                _ => Model::load(&file).map(|_| ()),
+            };
+            assert!(result.is_err(), "{}", name);
+        };
+
+        // Zero bits per pixel, a color map with zero bit entries, and a flipped image with
+        // no width
+        check("zero_bits.tga", &[0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 0, 0, 1, 2, 3, 4]);
+        check("zero_map_depth.tga", &[0, 1, 1, 0, 0, 2, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 8, 0, 1, 2, 3, 4]);
+        check("zero_width.tga", &[0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 24, 0x30]);
+
+        let bmp = |header_size: u32, width: i32, height: i32, compression: u32| {
+            let mut data = b"BM".to_vec();
+            for &v in [0, 0, 54, header_size].iter() {
+                data.write_u32::<LittleEndian>(v).unwrap();
+            }
+            data.write_i32::<LittleEndian>(width).unwrap();
+            data.write_i32::<LittleEndian>(height).unwrap();
+            data.write_u16::<LittleEndian>(1).unwrap();
+            data.write_u16::<LittleEndian>(8).unwrap();
+            for &v in [compression, 0, 0, 0, 0, 0].iter() {
+                data.write_u32::<LittleEndian>(v).unwrap();
+            }
+            data.extend_from_slice(&[0; 20]);
+            data
+        };
+        check("min_height.bmp", &bmp(40, 1, i32::min_value(), 0));
+        check("huge_header.bmp", &bmp(0xffffffff, 1, 1, 0));
+        check("huge_rle.bmp", &bmp(40, 100000, 100000, 1));
+
+        check("negative_width.hdr", b"#?RADIANCE\n\n-Y 1 +X -5\n\x80\x80\x80\x80");
+        check("huge.hdr", b"#?RADIANCE\n\n-Y 100000 +X 100000\n\x80\x80\x80\x80");
+
+        check("huge_list.ply", b"ply\nformat ascii 1.0\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n1e30 0 1 2\n");
+
+        // Three positions of zeros, and accessors that don't fit them
+        let gltf = |accessors: &str, attributes: &str| format!(r#"{{"asset": {{"version": "2.0"}},
+            "buffers": [{{"byteLength": 36, "uri": "data:application/octet-stream;base64,{}"}}],
+            "bufferViews": [{{"buffer": 0, "byteLength": 36}}],
+            "accessors": [{{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"}}, {}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0{}}}}}]}}],
+            "nodes": [{{"mesh": 0}}], "scenes": [{{"nodes": [0]}}], "scene": 0}}"#,
+            "A".repeat(48), accessors, attributes);
+        let float = r#""bufferView": 0, "componentType": 5126"#;
+        check("short_normals.gltf", gltf(&format!(r#"{{{}, "count": 1, "type": "VEC3"}}"#, float),
+                                         r#", "NORMAL": 1"#).as_bytes());
+        check("scalar_uvs.gltf", gltf(&format!(r#"{{{}, "count": 3, "type": "SCALAR"}}"#, float),
+                                      r#", "TEXCOORD_0": 1"#).as_bytes());
+        check("flat_positions.gltf", gltf(&format!(r#"{{{}, "count": 3, "type": "VEC2"}}"#, float), "")
+              .replace(r#""POSITION": 0"#, r#""POSITION": 1"#).as_bytes());
+        check("huge_offset.gltf", gltf(&format!(r#"{{{}, "byteOffset": 1e30, "count": 1, "type": "VEC3"}}"#, float),
+                                       r#", "NORMAL": 1"#).as_bytes());
+        check("huge_view.gltf", gltf(r#"{"componentType": 5126, "count": 1, "type": "VEC3"}"#, "")
+              .replace(r#""byteLength": 36}]"#, r#""byteOffset": 1e30, "byteLength": 36}]"#).as_bytes());
+        check("huge_sparse.gltf", gltf(r#"{"componentType": 5126, "count": 1e18, "type": "VEC3",
+            "sparse": {"count": 1, "indices": {"bufferView": 0, "componentType": 5125}, "values": {"bufferView": 0}}}"#,
+                                       r#", "NORMAL": 1"#).as_bytes());
+        check("deep.gltf", &vec![b'['; 1000000]);
+    }
 }
@@ -0,0 +1,333 @@
+use image::{Image, Color};
+use geo::DEPTH;
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::io::{Error, ErrorKind};
+use error::{LoadError, read_file};
+
+// Netpbm calls the ASCII formats (P2/P3) plain and the binary ones (P5/P6) raw
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Plain,
+    Raw,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+// Walks the whitespace separated tokens of a header, skipping # comments
+struct Tokens<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.data.len() {
+            match self.data[self.pos] {
+                b'#' => {
+                    while self.pos < self.data.len() && self.data[self.pos] != b'\n' {
+                        self.pos += 1;
+                    }
+                },
+                b' ' | b'\t' | b'\r' | b'\n' => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.pos < self.data.len() && !(self.data[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            None
+        } else {
+            ::std::str::from_utf8(&self.data[start..self.pos]).ok()
+        }
+    }
+
+    fn number(&mut self) -> Result<u32, Error> {
+        match self.next().and_then(|t| t.parse::<u32>().ok()) {
+            Some(n) => Ok(n),
+            None => Err(invalid("Expected a number in netpbm data")),
+        }
+    }
+
+    // Binary data starts after exactly one whitespace character following the header
+    fn rest(&self) -> &'a [u8] {
+        &self.data[(self.pos + 1).min(self.data.len())..]
+    }
+}
+
+pub fn read_netpbm_file(filename: &str) -> Result<Image, LoadError> {
+    let data = try!(read_file(filename));
+    decode_netpbm(&data).map_err(|e| LoadError::io(filename, e))
+}
+
+pub fn decode_netpbm(data: &[u8]) -> Result<Image, Error> {
+    let mut tokens = Tokens { data: data, pos: 0 };
+    let magic = tokens.next().unwrap_or("");
+
+    // Depth is the number of channels per pixel, alpha says whether the last one is alpha
+    let (width, height, depth, maxval, alpha, plain) = match magic {
+        "P2" | "P3" | "P5" | "P6" => {
+            let width = try!(tokens.number());
+            let height = try!(tokens.number());
+            let maxval = try!(tokens.number());
+            let depth = if magic == "P2" || magic == "P5" { 1 } else { 3 };
+            (width, height, depth, maxval, false, magic == "P2" || magic == "P3")
+        },
+        "P7" => try!(read_pam_header(&mut tokens)),
+        _ => return Err(invalid("Not a PPM, PGM or PAM file")),
+    };
+
+    if maxval == 0 || maxval > 65535 {
+        return Err(invalid("Invalid netpbm maxval"));
+    }
+
+    // The header can claim any size, so check it against the data that's actually there
+    // before allocating anything for it
+    let count = match (width as usize).checked_mul(height as usize) {
+        Some(pixels) if pixels <= i32::max_value() as usize => pixels.checked_mul(depth as usize),
+        _ => None,
+    };
+    let count = match count {
+        Some(count) => count,
+        None => return Err(invalid("Netpbm dimensions are too large")),
+    };
+    let truncated = || Error::new(ErrorKind::UnexpectedEof, "Netpbm image data is truncated");
+
+    let mut samples: Vec<u32> = Vec::new();
+    if plain {
+        // Every sample takes at least one digit
+        if count > data.len() - tokens.pos {
+            return Err(truncated());
+        }
+        samples.reserve(count);
+        for _ in 0..count {
+            samples.push(try!(tokens.number()));
+        }
+    } else {
+        let raw = tokens.rest();
+        let bytes = if maxval > 255 { 2 } else { 1 };
+        if count.checked_mul(bytes).map_or(true, |n| raw.len() < n) {
+            return Err(truncated());
+        }
+        samples.reserve(count);
+        for i in 0..count {
+            samples.push(match bytes {
+                2 => (raw[i * 2] as u32) << 8 | raw[i * 2 + 1] as u32,
+                _ => raw[i] as u32,
+            });
+        }
+    }
+
+    let scale = |v: u32| (v.min(maxval) * 255 / maxval) as u8;
+    let pixels: Vec<Color> = samples.chunks(depth as usize).map(|s| {
+        let a = if alpha { scale(s[s.len() - 1]) } else { 255 };
+        if depth >= 3 {
+            Color(scale(s[2]), scale(s[1]), scale(s[0]), a)
+        } else {
+            Color(scale(s[0]), scale(s[0]), scale(s[0]), a)
+        }
+    }).collect();
+
+    // Netpbm rows go top to bottom, the renderer wants the bottom row first
+    let width = width as usize;
+    let pixels: Vec<Color> = pixels.chunks(width.max(1)).rev().flat_map(|row| row.iter().cloned()).collect();
+
+    let mut image = Image::new(width as i32, height as i32);
+    image.set_data_buffer(pixels);
+    Ok(image)
+}
+
+fn read_pam_header(tokens: &mut Tokens) -> Result<(u32, u32, u32, u32, bool, bool), Error> {
+    let (mut width, mut height, mut depth, mut maxval) = (0, 0, 0, 0);
+    let mut tuple_type = String::new();
+
+    loop {
+        match tokens.next() {
+            Some("WIDTH") => width = try!(tokens.number()),
+            Some("HEIGHT") => height = try!(tokens.number()),
+            Some("DEPTH") => depth = try!(tokens.number()),
+            Some("MAXVAL") => maxval = try!(tokens.number()),
+            Some("TUPLTYPE") => tuple_type = tokens.next().unwrap_or("").to_string(),
+            Some("ENDHDR") => break,
+            Some(_) => {},
+            None => return Err(invalid("PAM header is missing ENDHDR")),
+        }
+    }
+
+    if depth == 0 || depth > 4 {
+        return Err(invalid("Unsupported PAM depth"));
+    }
+
+    // GRAYSCALE_ALPHA and RGB_ALPHA carry alpha as their last channel
+    let alpha = tuple_type.ends_with("_ALPHA") || depth == 2 || depth == 4;
+    Ok((width, height, depth, maxval, alpha, false))
+}
+
+// Top row first, as netpbm wants
+fn rows_top_down<F>(image: &Image, mut f: F) where F: FnMut(Color) {
+    for y in (0..image.height).rev() {
+        for x in 0..image.width {
+            f(image.get_pixel(x, y));
+        }
+    }
+}
+
+fn write_samples(w: &mut Write, samples: &[u16], maxval: u16, encoding: Encoding, per_row: usize) -> Result<(), Error> {
+    match encoding {
+        Encoding::Plain => {
+            // Plain lines shouldn't go past 70 characters, otherwise start each row on a new line
+            let mut text = String::new();
+            for row in samples.chunks(per_row.max(1)) {
+                let mut line_len = 0;
+                for s in row {
+                    let sample = s.to_string();
+                    if line_len > 0 && line_len + 1 + sample.len() > 70 {
+                        text.push('\n');
+                        line_len = 0;
+                    } else if line_len > 0 {
+                        text.push(' ');
+                        line_len += 1;
+                    }
+                    line_len += sample.len();
+                    text.push_str(&sample);
+                }
+                text.push('\n');
+            }
+            w.write_all(text.as_bytes())
+        },
+        Encoding::Raw => {
+            let bytes: Vec<u8> = if maxval > 255 {
+                samples.iter().flat_map(|&s| vec![(s >> 8) as u8, s as u8]).collect()
+            } else {
+                samples.iter().map(|&s| s as u8).collect()
+            };
+            w.write_all(&bytes)
+        }
+    }
+}
+
+pub fn write_ppm_file(image: &Image, filename: &str, encoding: Encoding) -> Result<(), Error> {
+    let mut samples: Vec<u16> = Vec::with_capacity((image.width * image.height * 3) as usize);
+    rows_top_down(image, |c| samples.extend(&[c.2 as u16, c.1 as u16, c.0 as u16]));
+
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    let magic = if encoding == Encoding::Plain { "P3" } else { "P6" };
+    try!(write!(f, "{}\n{} {}\n255\n", magic, image.width, image.height));
+    try!(write_samples(&mut f, &samples, 255, encoding, image.width as usize * 3));
+    f.flush()
+}
+
+// Gray is the Rec. 601 luma of the color, same as netpbm's own ppmtopgm
+pub fn write_pgm_file(image: &Image, filename: &str, encoding: Encoding) -> Result<(), Error> {
+    let mut samples: Vec<u16> = Vec::with_capacity((image.width * image.height) as usize);
+    rows_top_down(image, |c| {
+        let luma = 0.299 * c.2 as f64 + 0.587 * c.1 as f64 + 0.114 * c.0 as f64;
+        samples.push(luma.round() as u16);
+    });
+
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    let magic = if encoding == Encoding::Plain { "P2" } else { "P5" };
+    try!(write!(f, "{}\n{} {}\n255\n", magic, image.width, image.height));
+    try!(write_samples(&mut f, &samples, 255, encoding, image.width as usize));
+    f.flush()
+}
+
+pub fn write_pam_file(image: &Image, filename: &str) -> Result<(), Error> {
+    let mut samples: Vec<u16> = Vec::with_capacity((image.width * image.height * 4) as usize);
+    rows_top_down(image, |c| samples.extend(&[c.2 as u16, c.1 as u16, c.0 as u16, c.3 as u16]));
+
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    try!(write!(f, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n",
+                image.width, image.height));
+    try!(write_samples(&mut f, &samples, 255, Encoding::Raw, image.width as usize * 4));
+    f.flush()
+}
+
+// The zbuffer as a 16 bit PGM - brighter is closer, pixels nothing was drawn to are black.
+// Depth isn't normalized per image so frames can be compared against each other.
+pub fn write_depth_pgm_file(image: &Image, filename: &str, encoding: Encoding) -> Result<(), Error> {
+    let mut samples: Vec<u16> = Vec::with_capacity((image.width * image.height) as usize);
+    for y in (0..image.height).rev() {
+        for x in 0..image.width {
+            let z = image.get_depth(x, y);
+            let v = if z == i32::min_value() {
+                0
+            } else {
+                (z as f64 / DEPTH * 65535.0).round().max(0.0).min(65535.0) as u16
+            };
+            samples.push(v);
+        }
+    }
+
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    let magic = if encoding == Encoding::Plain { "P2" } else { "P5" };
+    try!(write!(f, "{}\n{} {}\n65535\n", magic, image.width, image.height));
+    try!(write_samples(&mut f, &samples, 65535, encoding, image.width as usize));
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use testutil::{TempFile, test_image, assert_same_pixels};
+
+    fn read_back(file: &TempFile) -> Image {
+        decode_netpbm(&fs::read(&file.path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = test_image(31, 12);
+        for &encoding in [Encoding::Plain, Encoding::Raw].iter() {
+            let file = TempFile::new(&format!("round_trip_{:?}.ppm", encoding));
+            write_ppm_file(&image, file.name(), encoding).unwrap();
+            assert_same_pixels(&image, &read_back(&file), false);
+        }
+
+        let file = TempFile::new("round_trip.pam");
+        write_pam_file(&image, file.name()).unwrap();
+        assert_same_pixels(&image, &read_back(&file), true);
+    }
+
+    #[test]
+    fn gray_round_trip() {
+        let mut image = Image::new(9, 4);
+        for y in 0..4 {
+            for x in 0..9 {
+                let v = (x * 25 + y) as u8;
+                image.set_pixel(x, y, Color(v, v, v, 255));
+            }
+        }
+        for &encoding in [Encoding::Plain, Encoding::Raw].iter() {
+            let file = TempFile::new(&format!("gray_round_trip_{:?}.pgm", encoding));
+            write_pgm_file(&image, file.name(), encoding).unwrap();
+            assert_same_pixels(&image, &read_back(&file), false);
+        }
+    }
+
+    #[test]
+    fn sixteen_bit() {
+        // Samples are scaled down to bytes, big endian in the raw format
+        let image = decode_netpbm(b"P5\n2 1\n65535\n\xff\xff\x80\x00").unwrap();
+        assert_eq!(image.get_pixel(0, 0), Color(255, 255, 255, 255));
+        assert_eq!(image.get_pixel(1, 0), Color(127, 127, 127, 255));
+    }
+
+    #[test]
+    fn bad_sizes() {
+        assert!(decode_netpbm(b"P6\n4294967295 4294967295\n255\n\x00\x00\x00").is_err());
+        assert!(decode_netpbm(b"P6\n1000 1000\n255\n\x00\x00\x00").is_err());
+        assert!(decode_netpbm(b"P3\n1000 1000\n255\n0 0 0").is_err());
+        assert!(decode_netpbm(b"P7\nWIDTH 2\nHEIGHT 2\nDEPTH 9\nMAXVAL 255\nENDHDR\n").is_err());
+        assert!(decode_netpbm(b"P6\n1 1\n0\n\x00\x00\x00").is_err());
+    }
+}
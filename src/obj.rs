@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Write, BufWriter};
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use geo::{Vertex, Vec3};
+use image::Color;
+use material::{Material, TextureMap};
+use model::Model;
+
+// Image formats the MTL reader can load back, anything else gets saved again as PNG
+const MAP_EXTENSIONS: [&'static str; 7] = ["tga", "png", "bmp", "ppm", "pgm", "pnm", "pam"];
+
+// Floats are compared by their bits so identical values end up sharing an index.
+// Adding zero turns -0 into 0, which would otherwise get an index of its own.
+fn key2(x: f64, y: f64) -> [u64; 2] {
+    [(x + 0.0).to_bits(), (y + 0.0).to_bits()]
+}
+
+fn key3(x: f64, y: f64, z: f64) -> [u64; 3] {
+    [(x + 0.0).to_bits(), (y + 0.0).to_bits(), (z + 0.0).to_bits()]
+}
+
+// The index of the value in the list, added if it's new
+fn intern<K: ::std::hash::Hash + Eq, V>(lookup: &mut HashMap<K, usize>, values: &mut Vec<V>, key: K, value: V) -> usize {
+    *lookup.entry(key).or_insert_with(|| {
+        values.push(value);
+        values.len() - 1
+    })
+}
+
+// Materials are looked up by name when the file is read back, so they have to be unique
+fn unique_names(materials: &[Material]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::with_capacity(materials.len());
+    for (i, material) in materials.iter().enumerate() {
+        let name = if material.name.is_empty() { format!("material{}", i) } else { material.name.clone() };
+        let name = if names.contains(&name) { format!("{}_{}", name, i) } else { name };
+        names.push(name);
+    }
+    names
+}
+
+// Writes the model and a material library with the same name next to it. Positions,
+// texture coordinates and normals are shared between faces wherever they're identical.
+pub fn write_obj_file(model: &Model, filename: &str) -> Result<(), Error> {
+    let mut mtl_path = PathBuf::from(filename);
+    mtl_path.set_extension("mtl");
+    let names = unique_names(&model.materials);
+    try!(write_mtl_file(&model.materials, &names, mtl_path.to_str().unwrap_or("")));
+
+    // Vertices with a color are kept apart from uncolored ones in the same spot
+    let mut positions: Vec<(Vec3<f64>, Option<Color>)> = Vec::new();
+    let mut position_lookup: HashMap<([u64; 3], Option<[u8; 4]>), usize> = HashMap::new();
+    let mut uvs: Vec<(f64, f64)> = Vec::new();
+    let mut uv_lookup: HashMap<[u64; 2], usize> = HashMap::new();
+    let mut normals: Vec<(f64, f64, f64)> = Vec::new();
+    let mut normal_lookup: HashMap<[u64; 3], usize> = HashMap::new();
+
+    let mut position_index = |v: &Vertex| {
+        let c = v.coords;
+        let key = (key3(c.x, c.y, c.z), v.color().map(|c| [c.0, c.1, c.2, c.3]));
+        intern(&mut position_lookup, &mut positions, key, (c, v.color()))
+    };
+
+    // Every point goes in, so unused vertices and point clouds survive
+    for v in model.points.iter() {
+        position_index(v);
+    }
+
+    let mut corners: Vec<Vec<(usize, Option<usize>, Option<usize>)>> = Vec::with_capacity(model.mesh.triangle_count());
+    for i in 0..model.mesh.triangle_count() {
+        let face = model.mesh.triangle(i);
+        corners.push(face.vertices.iter().map(|v| {
+            let position = position_index(v);
+            let uv = v.texture().map(|t| intern(&mut uv_lookup, &mut uvs, key2(t.x, t.y), (t.x, t.y)));
+            let normal = v.normal().map(|n| intern(&mut normal_lookup, &mut normals, key3(n.x, n.y, n.z), (n.x, n.y, n.z)));
+            (position, uv, normal)
+        }).collect());
+    }
+
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    try!(write!(f, "# {} vertices, {} faces\n", positions.len(), model.mesh.triangle_count()));
+    try!(write!(f, "mtllib {}\n", mtl_path.file_name().and_then(|n| n.to_str()).unwrap_or("")));
+
+    // Plain Display output is the shortest text that reads back as the same float
+    for &(c, color) in positions.iter() {
+        match color {
+            Some(color) => try!(write!(f, "v {} {} {} {} {} {}\n", c.x, c.y, c.z, color.2 as f64 / 255.0,
+                                       color.1 as f64 / 255.0, color.0 as f64 / 255.0)),
+            None => try!(write!(f, "v {} {} {}\n", c.x, c.y, c.z)),
+        }
+    }
+    for &(u, v) in uvs.iter() {
+        try!(write!(f, "vt {} {}\n", u, v));
+    }
+    for &(x, y, z) in normals.iter() {
+        try!(write!(f, "vn {} {} {}\n", x, y, z));
+    }
+
+    // Faces of a part are kept together so each group is only started once
+    let mut order: Vec<usize> = (0..model.mesh.triangle_count()).collect();
+    order.sort_by_key(|&i| model.face_parts[i]);
+
+    let mut current_part = None;
+    let mut current_object = String::new();
+    let mut current_material = None;
+
+    for &i in order.iter() {
+        let part_index = model.face_parts[i];
+        if current_part != Some(part_index) {
+            let part = &model.parts[part_index];
+            let mut started_object = false;
+            if part.object != current_object && !part.object.is_empty() {
+                try!(write!(f, "o {}\n", part.object));
+                current_object = part.object.clone();
+                started_object = true;
+            }
+            if part.name != part.object {
+                try!(write!(f, "g {}\n", part.name));
+            } else if !started_object {
+                // A bare g goes back to the object itself
+                try!(write!(f, "g\n"));
+            }
+            current_part = Some(part_index);
+        }
+
+        let material = model.face_materials[i];
+        if current_material != Some(material) {
+            try!(write!(f, "usemtl {}\n", names[material]));
+            current_material = Some(material);
+        }
+
+        try!(write!(f, "f"));
+        for &(v, vt, vn) in corners[i].iter() {
+            try!(match (vt, vn) {
+                (Some(t), Some(n)) => write!(f, " {}/{}/{}", v + 1, t + 1, n + 1),
+                (Some(t), None) => write!(f, " {}/{}", v + 1, t + 1),
+                (None, Some(n)) => write!(f, " {}//{}", v + 1, n + 1),
+                (None, None) => write!(f, " {}", v + 1),
+            });
+        }
+        try!(write!(f, "\n"));
+    }
+
+    f.flush()
+}
+
+// Where a texture can be found from the library: relative to it if possible. Textures that
+// didn't come from an image file of their own, e.g. ones embedded in a glTF model, are
+// saved next to the library.
+fn map_path(map: &TextureMap, dir: &Path, stem: &str) -> Result<String, Error> {
+    let readable = map.path.extension()
+        .and_then(|e| e.to_str())
+        .map_or(false, |e| MAP_EXTENSIONS.contains(&e.to_lowercase().as_str()));
+
+    if readable && map.path.is_file() {
+        let path = fs::canonicalize(&map.path).unwrap_or(map.path.clone());
+        let dir = fs::canonicalize(dir).unwrap_or(dir.to_path_buf());
+        let relative = path.strip_prefix(&dir).map(|p| p.to_path_buf()).unwrap_or(path.clone());
+        return Ok(relative.to_string_lossy().into_owned());
+    }
+
+    let name = format!("{}.png", stem);
+    try!(map.image.write_png_file(dir.join(&name).to_str().unwrap_or("")));
+    Ok(name)
+}
+
+pub fn write_mtl_file(materials: &[Material], names: &[String], filename: &str) -> Result<(), Error> {
+    let path = Path::new(filename);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("material");
+    let mut f = BufWriter::new(try!(File::create(filename)));
+
+    for (material, name) in materials.iter().zip(names.iter()) {
+        let (ka, kd, ks, ke) = (material.ambient, material.diffuse, material.specular, material.emissive);
+        try!(write!(f, "newmtl {}\n", name));
+        try!(write!(f, "Ka {} {} {}\n", ka.r, ka.g, ka.b));
+        try!(write!(f, "Kd {} {} {}\n", kd.r, kd.g, kd.b));
+        try!(write!(f, "Ks {} {} {}\n", ks.r, ks.g, ks.b));
+        try!(write!(f, "Ke {} {} {}\n", ke.r, ke.g, ke.b));
+        try!(write!(f, "Ns {}\n", material.shininess));
+        try!(write!(f, "d {}\n", material.dissolve));
+        try!(write!(f, "illum {}\n", material.illum));
+        try!(write!(f, "Pm {}\nPr {}\n", material.metallic, material.roughness));
+
+        let maps = [("map_Kd", "diffuse", &material.diffuse_map),
+                    ("map_Ks", "specular", &material.specular_map),
+                    ("map_Bump", "bump", &material.bump_map),
+                    ("map_d", "alpha", &material.alpha_map),
+                    ("map_Ke", "emissive", &material.emissive_map),
+                    ("norm", "normal", &material.normal_map)];
+        for &(keyword, kind, map) in maps.iter() {
+            if let Some(ref map) = *map {
+                let filename = try!(map_path(map, dir, &format!("{}_{}_{}", stem, name, kind)));
+                try!(write!(f, "{} {}\n", keyword, filename));
+            }
+        }
+        try!(write!(f, "\n"));
+    }
+
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{TempDir, test_image};
+
+    // Colored vertices, normals, a negative index, an n-gon, groups and two materials,
+    // one of them textured
+    const SOURCE: &'static str = "mtllib source.mtl
+v -1 -1 0 1 0 0
+v 1 -1 0 0 1 0
+v 1 1 0 0 0 1
+v -1 1 0.5 0.5 0.5 0.5
+v 0 2 0.25 1 1 1
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+vn 0 0.6 0.8
+o thing
+g front
+usemtl textured
+f 1/1/1 2/2/1 3/3/1
+f -5/1/2 -3/3/2 -2/4/2
+g back
+usemtl plain
+f 3//1 4//1 5//1 2//1
+f 1 2 5
+";
+
+    const SOURCE_MTL: &'static str = "newmtl textured
+Kd 0.8 0.7 0.6
+map_Kd texture.tga
+newmtl plain
+Ka 0.1 0.1 0.1
+Kd 1 0 0
+Ks 0.5 0.5 0.5
+Ns 20
+d 0.75
+illum 2
+";
+
+    fn assert_equivalent(a: &Model, b: &Model) {
+        assert_eq!(a.mesh.triangle_count(), b.mesh.triangle_count());
+        for i in 0..a.mesh.triangle_count() {
+            for (u, v) in a.mesh.triangle(i).vertices.iter().zip(b.mesh.triangle(i).vertices.iter()) {
+                assert_eq!(format!("{:?}", u.coords), format!("{:?}", v.coords));
+                assert_eq!(format!("{:?}", u.texture()), format!("{:?}", v.texture()));
+                assert_eq!(u.color(), v.color());
+                match (u.normal(), v.normal()) {
+                    (Some(n), Some(m)) => assert!((n - m) * (n - m) < 1e-20),
+                    (n, m) => assert_eq!(n.is_some(), m.is_some()),
+                }
+            }
+
+            let (p, q) = (&a.parts[a.face_parts[i]], &b.parts[b.face_parts[i]]);
+            assert_eq!((&p.name, &p.object), (&q.name, &q.object));
+
+            let (m, n) = (a.material(i), b.material(i));
+            assert_eq!(m.name, n.name);
+            assert_eq!((m.ambient, m.diffuse, m.specular), (n.ambient, n.diffuse, n.specular));
+            assert_eq!((m.shininess, m.dissolve, m.illum), (n.shininess, n.dissolve, n.illum));
+            assert_eq!(m.diffuse_map.is_some(), n.diffuse_map.is_some());
+            let (s, t) = (m.texture(), n.texture());
+            assert_eq!((s.width, s.height), (t.width, t.height));
+            for y in 0..s.height {
+                for x in 0..s.width {
+                    assert_eq!(s.get_pixel(x, y), t.get_pixel(x, y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn export_and_load() {
+        let dir = TempDir::new("obj_export");
+        fs::write(dir.file("source.obj"), SOURCE).unwrap();
+        fs::write(dir.file("source.mtl"), SOURCE_MTL).unwrap();
+        test_image(6, 4).write_tga_file(&dir.file("texture.tga")).unwrap();
+
+        let model = Model::from_obj(&dir.file("source.obj")).unwrap();
+        assert_eq!(model.mesh.triangle_count(), 5);
+        write_obj_file(&model, &dir.file("export.obj")).unwrap();
+        let exported = Model::from_obj(&dir.file("export.obj")).unwrap();
+        assert_equivalent(&model, &exported);
+
+        // And again, now that it's been through the writer once
+        write_obj_file(&exported, &dir.file("again.obj")).unwrap();
+        assert_equivalent(&model, &Model::from_obj(&dir.file("again.obj")).unwrap());
+    }
+}
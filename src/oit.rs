@@ -0,0 +1,84 @@
+use image::{Image, Color};
+use geo::DEPTH;
+
+// Weighted blended order independent transparency (McGuire & Bavoil 2013)
+// Translucent fragments are summed into an accumulation buffer with a weight that falls
+// off with distance, and the product of their transparencies is kept as the revealage.
+// Neither depends on the order the fragments arrive in, so faces can be drawn in any order
+// and composited over the opaque image in one go afterwards.
+pub struct OitBuffer {
+    pub width: i32,
+    pub height: i32,
+    // Premultiplied, weighted BGR with the weighted alpha in the last slot
+    accum: Vec<[f64; 4]>,
+    revealage: Vec<f64>,
+}
+
+impl OitBuffer {
+    pub fn new(width: i32, height: i32) -> Self {
+        OitBuffer {
+            width: width,
+            height: height,
+            accum: vec![[0.0; 4]; (width * height) as usize],
+            revealage: vec![1.0; (width * height) as usize],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for a in self.accum.iter_mut() {
+            *a = [0.0; 4];
+        }
+        for r in self.revealage.iter_mut() {
+            *r = 1.0;
+        }
+    }
+
+    pub fn add_fragment(&mut self, x: i32, y: i32, c: Color, z: f64) {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return;
+        }
+
+        let alpha = c.3 as f64 / 255.0;
+        if alpha <= 0.0 {
+            return;
+        }
+
+        let idx = ((y * self.width) + x) as usize;
+        let w = alpha * weight(z);
+        let acc = &mut self.accum[idx];
+        acc[0] += c.0 as f64 / 255.0 * w;
+        acc[1] += c.1 as f64 / 255.0 * w;
+        acc[2] += c.2 as f64 / 255.0 * w;
+        acc[3] += w;
+        self.revealage[idx] *= 1.0 - alpha;
+    }
+
+    // Composite the accumulated translucent layer over the opaque image
+    pub fn resolve(&self, image: &mut Image) {
+        for y in 0..self.height.min(image.height) {
+            for x in 0..self.width.min(image.width) {
+                let idx = ((y * self.width) + x) as usize;
+                let revealage = self.revealage[idx];
+                if revealage >= 1.0 {
+                    continue;
+                }
+
+                let acc = self.accum[idx];
+                let total = acc[3].max(1e-5);
+                let dst = image.get_pixel(x, y);
+                let mix = |avg: f64, d: u8| {
+                    let v = avg / total * (1.0 - revealage) + (d as f64 / 255.0) * revealage;
+                    (v.max(0.0).min(1.0) * 255.0).round() as u8
+                };
+
+                image.set_pixel(x, y, Color(mix(acc[0], dst.0), mix(acc[1], dst.1), mix(acc[2], dst.2), dst.3));
+            }
+        }
+    }
+}
+
+// Depth weight from equation 10 of the paper - z is zbuffer depth where larger is closer
+fn weight(z: f64) -> f64 {
+    let d = 1.0 - (z / DEPTH).max(0.0).min(1.0);
+    (3e3 * (1.0 - d).powi(3)).max(1e-2)
+}
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use byteorder::{ByteOrder, LittleEndian, BigEndian};
+
+use geo::{Vertex, Vec3, Triangle};
+use image::Color;
+use model::Model;
+use triangulate::ear_clip;
+use error::{LoadError, read_file};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn from_name(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    fn is_float(&self) -> bool {
+        *self == Scalar::F32 || *self == Scalar::F64
+    }
+}
+
+#[derive(Debug)]
+struct Property {
+    name: String,
+    // Lists have the type of their length as well as their items
+    list_count: Option<Scalar>,
+    kind: Scalar,
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+// Vertex properties the loader understands itself, everything else becomes an attribute
+const KNOWN_PROPERTIES: [&'static str; 16] = ["x", "y", "z", "nx", "ny", "nz", "red", "green", "blue",
+                                              "alpha", "s", "t", "u", "v", "texture_u", "texture_v"];
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::UnexpectedEof, "PLY data is truncated")
+}
+
+// Hands out the values of the body one at a time, whatever the format
+struct Values<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: Format,
+}
+
+impl<'a> Values<'a> {
+    fn next(&mut self, kind: Scalar) -> Result<f64, Error> {
+        if self.format == Format::Ascii {
+            // Skip to the next token
+            while self.pos < self.data.len() && (self.data[self.pos] as char).is_whitespace() {
+                self.pos += 1;
+            }
+            let start = self.pos;
+            while self.pos < self.data.len() && !(self.data[self.pos] as char).is_whitespace() {
+                self.pos += 1;
+            }
+            if start == self.pos {
+                return Err(truncated());
+            }
+            return ::std::str::from_utf8(&self.data[start..self.pos]).ok()
+                .and_then(|t| t.parse::<f64>().ok())
+                .ok_or_else(|| invalid("Expected a number in PLY data"));
+        }
+
+        let size = kind.size();
+        if self.pos + size > self.data.len() {
+            return Err(truncated());
+        }
+        let b = &self.data[self.pos..self.pos + size];
+        self.pos += size;
+
+        Ok(match self.format {
+            Format::BinaryBigEndian => read_binary::<BigEndian>(kind, b),
+            _ => read_binary::<LittleEndian>(kind, b),
+        })
+    }
+
+    // The length of a list, which has to fit in what's left of the data. Every ASCII value
+    // takes at least a byte.
+    fn list_count(&mut self, count_kind: Scalar, item_kind: Scalar) -> Result<usize, Error> {
+        let count = try!(self.next(count_kind));
+        if count < 0.0 || count.fract() != 0.0 {
+            return Err(invalid("Bad PLY list length"));
+        }
+        let item_size = if self.format == Format::Ascii { 1 } else { item_kind.size() };
+        if count > ((self.data.len() - self.pos) / item_size) as f64 {
+            return Err(truncated());
+        }
+        Ok(count as usize)
+    }
+}
+
+fn read_binary<B: ByteOrder>(kind: Scalar, b: &[u8]) -> f64 {
+    match kind {
+        Scalar::I8 => b[0] as i8 as f64,
+        Scalar::U8 => b[0] as f64,
+        Scalar::I16 => B::read_i16(b) as f64,
+        Scalar::U16 => B::read_u16(b) as f64,
+        Scalar::I32 => B::read_i32(b) as f64,
+        Scalar::U32 => B::read_u32(b) as f64,
+        Scalar::F32 => B::read_f32(b) as f64,
+        Scalar::F64 => B::read_f64(b),
+    }
+}
+
+pub fn read_ply_file(filename: &str) -> Result<Model, LoadError> {
+    let data = try!(read_file(filename));
+    decode_ply(&data).map_err(|e| LoadError::io(filename, e))
+}
+
+fn read_header(data: &[u8]) -> Result<(Format, Vec<Element>, usize), Error> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut pos = 0;
+    let mut first = true;
+
+    loop {
+        if pos >= data.len() {
+            return Err(invalid("PLY header is missing end_header"));
+        }
+        let start = pos;
+        while pos < data.len() && data[pos] != b'\n' {
+            pos += 1;
+        }
+        let line = String::from_utf8_lossy(&data[start..pos]).into_owned();
+        pos += 1;
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if first {
+            if words.first() != Some(&"ply") {
+                return Err(invalid("Not a PLY file"));
+            }
+            first = false;
+            continue;
+        }
+
+        match words.first() {
+            Some(&"format") => {
+                format = match words.get(1) {
+                    Some(&"ascii") => Some(Format::Ascii),
+                    Some(&"binary_little_endian") => Some(Format::BinaryLittleEndian),
+                    Some(&"binary_big_endian") => Some(Format::BinaryBigEndian),
+                    _ => return Err(invalid("Unknown PLY format")),
+                };
+            },
+            Some(&"element") => {
+                let count = try!(words.get(2).and_then(|c| c.parse::<usize>().ok())
+                                 .ok_or_else(|| invalid("Bad PLY element count")));
+                elements.push(Element {
+                    name: words.get(1).unwrap_or(&"").to_string(),
+                    count: count,
+                    properties: Vec::new(),
+                });
+            },
+            Some(&"property") => {
+                let property = if words.get(1) == Some(&"list") && words.len() >= 5 {
+                    Property {
+                        name: words[4].to_string(),
+                        list_count: Some(try!(Scalar::from_name(words[2]).ok_or_else(|| invalid("Unknown PLY type")))),
+                        kind: try!(Scalar::from_name(words[3]).ok_or_else(|| invalid("Unknown PLY type"))),
+                    }
+                } else if words.len() >= 3 {
+                    Property {
+                        name: words[2].to_string(),
+                        list_count: None,
+                        kind: try!(Scalar::from_name(words[1]).ok_or_else(|| invalid("Unknown PLY type"))),
+                    }
+                } else {
+                    return Err(invalid("Bad PLY property"));
+                };
+
+                match elements.last_mut() {
+                    Some(element) => element.properties.push(property),
+                    None => return Err(invalid("PLY property outside of an element")),
+                }
+            },
+            Some(&"end_header") => break,
+            _ => {},
+        }
+    }
+
+    match format {
+        Some(format) => Ok((format, elements, pos.min(data.len()))),
+        None => Err(invalid("PLY header has no format")),
+    }
+}
+
+pub fn decode_ply(data: &[u8]) -> Result<Model, Error> {
+    let (format, elements, body) = try!(read_header(data));
+    let mut values = Values { data: &data[body..], pos: 0, format: format };
+
+    let mut points: Vec<Vertex> = Vec::new();
+    let mut attributes: HashMap<String, Vec<f64>> = HashMap::new();
+    let mut polygons: Vec<Vec<usize>> = Vec::new();
+
+    // Elements have to be read in order, even the ones we don't care about
+    for element in elements.iter() {
+        for _ in 0..element.count {
+            let mut scalars: HashMap<&str, (f64, Scalar)> = HashMap::new();
+            let mut indices: Option<Vec<usize>> = None;
+
+            for property in element.properties.iter() {
+                match property.list_count {
+                    Some(count_kind) => {
+                        let count = try!(values.list_count(count_kind, property.kind));
+                        let mut items = Vec::new();
+                        for _ in 0..count {
+                            items.push(try!(values.next(property.kind)));
+                        }
+                        if property.name == "vertex_indices" || property.name == "vertex_index" {
+                            indices = Some(items.iter().map(|&i| i as usize).collect());
+                        }
+                    },
+                    None => {
+                        let value = try!(values.next(property.kind));
+                        scalars.insert(&property.name, (value, property.kind));
+                    },
+                }
+            }
+
+            match element.name.as_str() {
+                "vertex" => {
+                    let get = |name: &str| scalars.get(name).map(|v| v.0);
+                    let mut vertex = Vertex::new(Vec3::new(get("x").unwrap_or(0.0), get("y").unwrap_or(0.0),
+                                                           get("z").unwrap_or(0.0)));
+
+                    if let (Some(x), Some(y), Some(z)) = (get("nx"), get("ny"), get("nz")) {
+                        vertex.set_normal(Vec3::new(x, y, z));
+                    }
+
+                    // Colors are bytes, or 0 to 1 when stored as floats
+                    let channel = |name: &str| scalars.get(name).map(|&(v, kind)| {
+                        let v = if kind.is_float() { v * 255.0 } else { v };
+                        v.round().max(0.0).min(255.0) as u8
+                    });
+                    if let (Some(r), Some(g), Some(b)) = (channel("red"), channel("green"), channel("blue")) {
+                        vertex.set_color(Color(b, g, r, channel("alpha").unwrap_or(255)));
+                    }
+
+                    let u = get("s").or(get("u")).or(get("texture_u"));
+                    let v = get("t").or(get("v")).or(get("texture_v"));
+                    if let (Some(u), Some(v)) = (u, v) {
+                        vertex.set_texture(vec![u, v]);
+                    }
+
+                    for property in element.properties.iter() {
+                        if property.list_count.is_none() && !KNOWN_PROPERTIES.contains(&property.name.as_str()) {
+                            attributes.entry(property.name.clone()).or_insert_with(Vec::new)
+                                .push(scalars[property.name.as_str()].0);
+                        }
+                    }
+
+                    points.push(vertex);
+                },
+                "face" => {
+                    if let Some(indices) = indices {
+                        polygons.push(indices);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    let mut faces = Vec::new();
+    for polygon in polygons.iter() {
+        if polygon.iter().any(|&i| i >= points.len()) {
+            return Err(invalid("PLY face refers to a missing vertex"));
+        }
+        let corners: Vec<Vec3<f64>> = polygon.iter().map(|&i| points[i].coords).collect();
+        for tri in ear_clip(&corners) {
+            faces.push(Triangle::new([points[polygon[tri[0]]], points[polygon[tri[1]]], points[polygon[tri[2]]]]));
+        }
+    }
+
+    let mut model = Model::from_faces(faces);
+    model.points = points;
+    model.attributes = attributes;
+    Ok(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    const HEADER: &'static str = "element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+property float u
+property float v
+property float confidence
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+
+    // x, y, z, r, g, b, u, v, confidence
+    const VERTICES: [[f64; 9]; 4] = [[0.0, 0.0, 0.0, 255.0, 0.0, 0.0, 0.0, 0.0, 0.5],
+                                     [1.0, 0.0, 0.0, 0.0, 255.0, 0.0, 1.0, 0.0, 0.25],
+                                     [1.0, 1.0, 0.0, 0.0, 0.0, 255.0, 1.0, 1.0, 1.0],
+                                     [0.0, 1.0, 0.5, 10.0, 20.0, 30.0, 0.0, 1.0, 0.0]];
+
+    fn ascii() -> Vec<u8> {
+        let mut data = format!("ply\nformat ascii 1.0\n{}", HEADER);
+        for v in VERTICES.iter() {
+            data += &format!("{} {} {} {} {} {} {} {} {}\n", v[0], v[1], v[2], v[3], v[4], v[5], v[6], v[7], v[8]);
+        }
+        data += "4 0 1 2 3\n";
+        data.into_bytes()
+    }
+
+    fn binary<B: ByteOrder>(format: &str) -> Vec<u8> {
+        let mut data = format!("ply\nformat {} 1.0\n{}", format, HEADER).into_bytes();
+        for v in VERTICES.iter() {
+            for (i, &x) in v.iter().enumerate() {
+                match i {
+                    3 | 4 | 5 => data.push(x as u8),
+                    _ => data.write_f32::<B>(x as f32).unwrap(),
+                }
+            }
+        }
+        data.push(4);
+        for i in 0..4 {
+            data.write_i32::<B>(i).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn formats_agree() {
+        let files = [ascii(), binary::<LittleEndian>("binary_little_endian"), binary::<BigEndian>("binary_big_endian")];
+        for data in files.iter() {
+            let model = decode_ply(data).unwrap();
+            // The quad is split in two
+            assert_eq!(model.mesh.triangle_count(), 2);
+            assert_eq!(model.points.len(), 4);
+            for (p, v) in model.points.iter().zip(VERTICES.iter()) {
+                assert_eq!((p.coords.x, p.coords.y, p.coords.z), (v[0], v[1], v[2]));
+                assert_eq!(p.color(), Some(Color(v[5] as u8, v[4] as u8, v[3] as u8, 255)));
+                let t = p.texture().unwrap();
+                assert_eq!((t.x, t.y), (v[6], v[7]));
+            }
+            assert_eq!(model.attributes["confidence"], vec![0.5, 0.25, 1.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(decode_ply(b"not a ply file").is_err());
+        assert!(decode_ply(b"ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\n").is_err());
+
+        // Truncated bodies and faces pointing past the vertices
+        let data = binary::<LittleEndian>("binary_little_endian");
+        assert!(decode_ply(&data[..data.len() - 3]).is_err());
+        let data = String::from_utf8(ascii()).unwrap().replace("4 0 1 2 3", "3 0 1 9");
+        assert!(decode_ply(data.as_bytes()).is_err());
+
+        // List lengths the data can't possibly hold
+        let data = String::from_utf8(ascii()).unwrap().replace("4 0 1 2 3", "1e30 0 1 2 3");
+        assert!(decode_ply(data.as_bytes()).is_err());
+        let data = String::from_utf8(ascii()).unwrap().replace("4 0 1 2 3", "-1 0 1 2 3");
+        assert!(decode_ply(data.as_bytes()).is_err());
+        let mut data = binary::<LittleEndian>("binary_little_endian");
+        let faces = data.len() - (1 + 4 * 4);
+        data[faces] = 0xff;
+        assert!(decode_ply(&data).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+use image::{Image, Color};
+use zlib;
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::io::{Error, ErrorKind};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use error::{LoadError, read_file};
+
+const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+// Starting column/row and column/row step of each of the seven Adam7 passes
+const ADAM7: [(usize, usize, usize, usize); 7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8),
+                                                  (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2),
+                                                  (0, 1, 1, 2)];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ColorType {
+    Gray = 0,
+    RGB = 2,
+    Palette = 3,
+    GrayAlpha = 4,
+    RGBA = 6,
+}
+
+impl ColorType {
+    fn new(color_type: u8) -> Option<ColorType> {
+        match color_type {
+            0 => Some(ColorType::Gray),
+            2 => Some(ColorType::RGB),
+            3 => Some(ColorType::Palette),
+            4 => Some(ColorType::GrayAlpha),
+            6 => Some(ColorType::RGBA),
+            _ => None,
+        }
+    }
+
+    fn channels(&self) -> usize {
+        match *self {
+            ColorType::Gray | ColorType::Palette => 1,
+            ColorType::GrayAlpha => 2,
+            ColorType::RGB => 3,
+            ColorType::RGBA => 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PNGHeader {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: ColorType,
+    interlace: u8,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0xffffffff, data) ^ 0xffffffff
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+pub fn read_png_file(filename: &str) -> Result<Image, LoadError> {
+    let data = try!(read_file(filename));
+    decode_png(&data).map_err(|e| LoadError::io(filename, e))
+}
+
+pub fn decode_png(data: &[u8]) -> Result<Image, Error> {
+    if data.len() < 8 || data[..8] != SIGNATURE {
+        return Err(invalid("Not a PNG file"));
+    }
+
+    let mut r = &data[8..];
+    let mut header: Option<PNGHeader> = None;
+    let mut palette: Vec<Color> = Vec::new();
+    let mut transparency: Vec<u8> = Vec::new();
+    let mut compressed: Vec<u8> = Vec::new();
+
+    loop {
+        let length = try!(r.read_u32::<BigEndian>()) as usize;
+        if r.len() < length + 8 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "PNG chunk is truncated"));
+        }
+
+        let chunk_type = &r[..4];
+        let chunk = &r[4..4 + length];
+        let crc = (&r[4 + length..]).read_u32::<BigEndian>().unwrap();
+        if crc32(&r[..4 + length]) != crc {
+            return Err(invalid("PNG chunk CRC mismatch"));
+        }
+
+        match chunk_type {
+            b"IHDR" => {
+                let mut c = chunk;
+                let width = try!(c.read_u32::<BigEndian>());
+                let height = try!(c.read_u32::<BigEndian>());
+                let bit_depth = try!(c.read_u8());
+                let color_type = match ColorType::new(try!(c.read_u8())) {
+                    Some(t) => t,
+                    None => return Err(invalid("Unknown PNG color type")),
+                };
+                // Compression and filter method, zero is the only defined value for both
+                let _ = try!(c.read_u8());
+                let _ = try!(c.read_u8());
+                let interlace = try!(c.read_u8());
+                header = Some(PNGHeader {
+                    width: width,
+                    height: height,
+                    bit_depth: bit_depth,
+                    color_type: color_type,
+                    interlace: interlace,
+                });
+            },
+            b"PLTE" => {
+                palette = chunk.chunks(3).filter(|c| c.len() == 3)
+                    .map(|c| Color(c[2], c[1], c[0], 255)).collect();
+            },
+            b"tRNS" => transparency = chunk.to_vec(),
+            b"IDAT" => compressed.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {
+                // Ancillary chunks have the fifth bit of their first letter set, we can
+                // skip those but not critical ones we don't understand
+                if chunk_type[0] & 0x20 == 0 {
+                    return Err(invalid("Unsupported critical PNG chunk"));
+                }
+            }
+        }
+
+        r = &r[4 + length + 4..];
+    }
+
+    let header = match header {
+        Some(h) => h,
+        None => return Err(invalid("PNG is missing its header")),
+    };
+
+    let valid_depth = match header.color_type {
+        ColorType::Gray => [1, 2, 4, 8, 16].contains(&header.bit_depth),
+        ColorType::Palette => [1, 2, 4, 8].contains(&header.bit_depth),
+        _ => header.bit_depth == 8 || header.bit_depth == 16,
+    };
+    if !valid_depth {
+        return Err(invalid("Invalid PNG bit depth"));
+    }
+
+    // Palette entries can be given alpha by the tRNS chunk
+    for (entry, &alpha) in palette.iter_mut().zip(transparency.iter()) {
+        entry.3 = alpha;
+    }
+
+    let raw = try!(zlib::decompress(&compressed));
+
+    // The header can claim any size, so check it against the data we actually have before
+    // allocating anything for it
+    let expected = match image_data_size(&header) {
+        Some(size) => size,
+        None => return Err(invalid("PNG dimensions are empty or too large")),
+    };
+    if expected > raw.len() {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "PNG image data is truncated"));
+    }
+
+    let width = header.width as usize;
+    let height = header.height as usize;
+    let mut pixels = vec![Color(0, 0, 0, 255); width * height];
+
+    if header.interlace == 1 {
+        let mut offset = 0;
+        for &(x0, y0, dx, dy) in ADAM7.iter() {
+            if x0 >= width || y0 >= height {
+                continue;
+            }
+            let pass_width = (width - x0 + dx - 1) / dx;
+            let pass_height = (height - y0 + dy - 1) / dy;
+            let rows = try!(unfilter(&raw[offset.min(raw.len())..], &header, pass_width, pass_height));
+            offset += pass_height * (1 + row_bytes(&header, pass_width));
+
+            for (j, row) in rows.iter().enumerate() {
+                for i in 0..pass_width {
+                    let color = try!(sample_color(row, i, &header, &palette, &transparency));
+                    pixels[(y0 + j * dy) * width + x0 + i * dx] = color;
+                }
+            }
+        }
+    } else {
+        let rows = try!(unfilter(&raw, &header, width, height));
+        for (j, row) in rows.iter().enumerate() {
+            for i in 0..width {
+                pixels[j * width + i] = try!(sample_color(row, i, &header, &palette, &transparency));
+            }
+        }
+    }
+
+    // PNG rows go top to bottom, the renderer wants the bottom row first
+    let pixels: Vec<Color> = pixels.chunks(width.max(1)).rev().flat_map(|row| row.iter().cloned()).collect();
+
+    let mut image = Image::new(width as i32, height as i32);
+    image.set_data_buffer(pixels);
+    Ok(image)
+}
+
+// Bytes of filtered data the header calls for, including the filter type byte of every
+// row. None if the image is empty or too big to hold.
+fn image_data_size(header: &PNGHeader) -> Option<usize> {
+    let (width, height) = (header.width as usize, header.height as usize);
+    match width.checked_mul(height) {
+        Some(pixels) if pixels > 0 && pixels <= i32::max_value() as usize => {},
+        _ => return None,
+    }
+
+    let bits = header.color_type.channels() * header.bit_depth as usize;
+    let pass_size = |w: usize, h: usize| {
+        w.checked_mul(bits)
+            .and_then(|b| b.checked_add(7))
+            .and_then(|b| (b / 8).checked_add(1))
+            .and_then(|row| row.checked_mul(h))
+    };
+
+    if header.interlace != 1 {
+        return pass_size(width, height);
+    }
+
+    ADAM7.iter()
+        .filter(|&&(x0, y0, _, _)| x0 < width && y0 < height)
+        .fold(Some(0usize), |total, &(x0, y0, dx, dy)| {
+            total.and_then(|t| pass_size((width - x0 + dx - 1) / dx, (height - y0 + dy - 1) / dy)
+                           .and_then(|size| t.checked_add(size)))
+        })
+}
+
+fn row_bytes(header: &PNGHeader, width: usize) -> usize {
+    (width * header.color_type.channels() * header.bit_depth as usize + 7) / 8
+}
+
+// Undo the per row filters, returning the rows without their filter type bytes
+fn unfilter(data: &[u8], header: &PNGHeader, width: usize, height: usize) -> Result<Vec<Vec<u8>>, Error> {
+    let stride = row_bytes(header, width);
+    // Filters work on whole pixels, or single bytes if pixels are smaller than that
+    let bpp = ((header.color_type.channels() * header.bit_depth as usize + 7) / 8).max(1);
+
+    if data.len() < height * (stride + 1) {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "PNG image data is truncated"));
+    }
+
+    let mut rows: Vec<Vec<u8>> = Vec::with_capacity(height);
+    let mut prev = vec![0u8; stride];
+
+    for y in 0..height {
+        let start = y * (stride + 1);
+        let filter = data[start];
+        let mut row = data[start + 1..start + 1 + stride].to_vec();
+
+        for i in 0..stride {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = prev[i];
+            let c = if i >= bpp { prev[i - bpp] } else { 0 };
+            let predictor = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(invalid("Unknown PNG filter type")),
+            };
+            row[i] = row[i].wrapping_add(predictor);
+        }
+
+        prev = row.clone();
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Read sample n of a row at the image bit depth, at its full precision
+fn sample(row: &[u8], n: usize, bit_depth: u8) -> u16 {
+    match bit_depth {
+        16 => (row[n * 2] as u16) << 8 | row[n * 2 + 1] as u16,
+        8 => row[n] as u16,
+        _ => {
+            let bit = n * bit_depth as usize;
+            let shift = 8 - bit_depth as usize - (bit % 8);
+            ((row[bit / 8] >> shift) & ((1u16 << bit_depth) - 1) as u8) as u16
+        }
+    }
+}
+
+// Scale a sample to eight bits
+fn to_u8(v: u16, bit_depth: u8) -> u8 {
+    match bit_depth {
+        16 => (v >> 8) as u8,
+        8 => v as u8,
+        _ => (v as u32 * 255 / ((1u32 << bit_depth) - 1)) as u8,
+    }
+}
+
+fn sample_color(row: &[u8], x: usize, header: &PNGHeader, palette: &[Color], transparency: &[u8])
+                -> Result<Color, Error> {
+    let depth = header.bit_depth;
+    let channels = header.color_type.channels();
+    let s = |c: usize| sample(row, x * channels + c, depth);
+
+    // For gray and RGB images tRNS holds a single 16 bit color that should be transparent
+    let key = |c: usize| -> Option<u16> {
+        if transparency.len() >= (c + 1) * 2 {
+            Some((transparency[c * 2] as u16) << 8 | transparency[c * 2 + 1] as u16)
+        } else {
+            None
+        }
+    };
+
+    Ok(match header.color_type {
+        ColorType::Gray => {
+            let g = s(0);
+            let alpha = if key(0) == Some(g) { 0 } else { 255 };
+            let g = to_u8(g, depth);
+            Color(g, g, g, alpha)
+        },
+        ColorType::GrayAlpha => {
+            let g = to_u8(s(0), depth);
+            Color(g, g, g, to_u8(s(1), depth))
+        },
+        ColorType::RGB => {
+            let (r, g, b) = (s(0), s(1), s(2));
+            let alpha = if key(0) == Some(r) && key(1) == Some(g) && key(2) == Some(b) { 0 } else { 255 };
+            Color(to_u8(b, depth), to_u8(g, depth), to_u8(r, depth), alpha)
+        },
+        ColorType::RGBA => Color(to_u8(s(2), depth), to_u8(s(1), depth), to_u8(s(0), depth), to_u8(s(3), depth)),
+        ColorType::Palette => {
+            match palette.get(s(0) as usize) {
+                Some(&c) => c,
+                None => return Err(invalid("PNG palette index out of range")),
+            }
+        },
+    })
+}
+
+fn write_chunk(w: &mut Write, chunk_type: &[u8], data: &[u8]) -> Result<(), Error> {
+    try!(w.write_u32::<BigEndian>(data.len() as u32));
+    try!(w.write_all(chunk_type));
+    try!(w.write_all(data));
+    let crc = crc32_update(crc32_update(0xffffffff, chunk_type), data) ^ 0xffffffff;
+    w.write_u32::<BigEndian>(crc)
+}
+
+// Writes 8 bit RGB, or RGBA if any pixel isn't fully opaque
+pub fn write_png_file(image: &Image, filename: &str) -> Result<(), Error> {
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    try!(f.write_all(&encode_png(image)));
+    f.flush()
+}
+
+pub fn encode_png(image: &Image) -> Vec<u8> {
+    let width = image.width.max(0) as usize;
+    let height = image.height.max(0) as usize;
+    let alpha = (0..image.height).any(|y| (0..image.width).any(|x| image.get_pixel(x, y).3 != 255));
+    let channels = if alpha { 4 } else { 3 };
+
+    let mut ihdr = Vec::new();
+    ihdr.write_u32::<BigEndian>(width as u32).unwrap();
+    ihdr.write_u32::<BigEndian>(height as u32).unwrap();
+    ihdr.extend(&[8, if alpha { ColorType::RGBA } else { ColorType::RGB } as u8, 0, 0, 0]);
+
+    // Top row first, each with whichever filter makes it look the most compressible
+    let stride = width * channels;
+    let mut raw = Vec::with_capacity(height * (stride + 1));
+    let mut prev = vec![0u8; stride];
+    for y in (0..image.height).rev() {
+        let mut row = Vec::with_capacity(stride);
+        for x in 0..image.width {
+            let c = image.get_pixel(x, y);
+            row.extend(&[c.2, c.1, c.0]);
+            if alpha {
+                row.push(c.3);
+            }
+        }
+
+        let (filter, filtered) = (0..5u8).map(|t| (t, filter_row(t, &row, &prev, channels)))
+            .min_by_key(|&(_, ref f)| f.iter().map(|&b| (b as i8 as i32).abs() as u32).sum::<u32>())
+            .unwrap();
+        raw.push(filter);
+        raw.extend(filtered);
+        prev = row;
+    }
+
+    let mut out = SIGNATURE.to_vec();
+    write_chunk(&mut out, b"IHDR", &ihdr).unwrap();
+    write_chunk(&mut out, b"IDAT", &zlib::compress(&raw)).unwrap();
+    write_chunk(&mut out, b"IEND", &[]).unwrap();
+    out
+}
+
+fn filter_row(filter: u8, row: &[u8], prev: &[u8], bpp: usize) -> Vec<u8> {
+    (0..row.len()).map(|i| {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        let predictor = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth(a, b, c),
+        };
+        row[i].wrapping_sub(predictor)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Image;
+    use testutil::{TempFile, test_image, assert_same_pixels};
+
+    #[test]
+    fn round_trip() {
+        let image = test_image(37, 20);
+        assert_same_pixels(&image, &decode_png(&encode_png(&image)).unwrap(), true);
+
+        // Opaque images are written as RGB
+        let mut opaque = Image::new(9, 5);
+        for y in 0..5 {
+            for x in 0..9 {
+                opaque.set_pixel(x, y, image.get_pixel(x * 3, y * 3).with_alpha(255));
+            }
+        }
+        let file = TempFile::new("round_trip.png");
+        write_png_file(&opaque, file.name()).unwrap();
+        assert_same_pixels(&opaque, &read_png_file(file.name()).unwrap(), true);
+    }
+
+    // The IHDR with a new size, and its CRC to match
+    fn with_size(png: &[u8], width: u32, height: u32) -> Vec<u8> {
+        let mut data = png.to_vec();
+        data[16..20].copy_from_slice(&[(width >> 24) as u8, (width >> 16) as u8, (width >> 8) as u8, width as u8]);
+        data[20..24].copy_from_slice(&[(height >> 24) as u8, (height >> 16) as u8, (height >> 8) as u8, height as u8]);
+        let crc = crc32(&data[12..29]);
+        data[29..33].copy_from_slice(&[(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]);
+        data
+    }
+
+    #[test]
+    fn bad_sizes() {
+        let png = encode_png(&test_image(8, 8));
+        assert!(decode_png(&with_size(&png, 0, 8)).is_err());
+        assert!(decode_png(&with_size(&png, 0x7fffffff, 0x7fffffff)).is_err());
+        assert!(decode_png(&with_size(&png, 1000, 1000)).is_err());
+    }
+
+    #[test]
+    fn truncated_file() {
+        let png = encode_png(&test_image(16, 16));
+        assert!(decode_png(&png[..png.len() / 2]).is_err());
+        assert!(decode_png(b"not a png").is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use image::{Image, Color, TRANSPARENT};
+use geo::Vec2;
+use tonemap::ColorSpace;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+}
+
+// What happens to texture coordinates outside of 0 to 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    ClampToEdge,
+    MirroredRepeat,
+    // Anything outside the texture is the sampler's border color
+    Border,
+}
+
+// How the mip chain is used, if the texture has one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MipmapMode {
+    // Always sample the full size image
+    None,
+    Nearest,
+    // Blend between the two closest levels - trilinear when combined with bilinear
+    Linear,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sampler {
+    pub filter: Filter,
+    pub wrap_u: WrapMode,
+    pub wrap_v: WrapMode,
+    pub border: Color,
+    pub mipmap: MipmapMode,
+    // 1 is isotropic, higher takes up to that many samples along the long axis of the footprint
+    pub max_anisotropy: u32,
+    // What the texels hold, used to get back to linear light when shading in float
+    pub color_space: ColorSpace,
+}
+
+// Material textures always have a mip chain, so the default uses it
+impl Default for Sampler {
+    fn default() -> Self {
+        Sampler::trilinear()
+    }
+}
+
+impl Sampler {
+    pub fn new(filter: Filter, wrap: WrapMode) -> Self {
+        Sampler {
+            filter: filter,
+            wrap_u: wrap,
+            wrap_v: wrap,
+            border: TRANSPARENT,
+            mipmap: MipmapMode::None,
+            max_anisotropy: 1,
+            color_space: ColorSpace::Srgb,
+        }
+    }
+
+    pub fn trilinear() -> Self {
+        Sampler {
+            mipmap: MipmapMode::Linear,
+            ..Sampler::bilinear()
+        }
+    }
+
+    pub fn anisotropic(max_anisotropy: u32) -> Self {
+        Sampler {
+            max_anisotropy: max_anisotropy.max(1),
+            ..Sampler::trilinear()
+        }
+    }
+
+    pub fn nearest() -> Self {
+        Sampler::new(Filter::Nearest, WrapMode::Repeat)
+    }
+
+    pub fn bilinear() -> Self {
+        Sampler::new(Filter::Bilinear, WrapMode::Repeat)
+    }
+
+    pub fn with_wrap(self, wrap_u: WrapMode, wrap_v: WrapMode) -> Self {
+        Sampler {
+            wrap_u: wrap_u,
+            wrap_v: wrap_v,
+            ..self
+        }
+    }
+
+    pub fn with_border(self, border: Color) -> Self {
+        Sampler {
+            border: border,
+            ..self
+        }
+    }
+
+    pub fn with_color_space(self, color_space: ColorSpace) -> Self {
+        Sampler {
+            color_space: color_space,
+            ..self
+        }
+    }
+
+    pub fn with_mipmap(self, mipmap: MipmapMode) -> Self {
+        Sampler {
+            mipmap: mipmap,
+            ..self
+        }
+    }
+
+    // Sample with the screen space derivatives of the texture coordinates, which pick the
+    // mip level (and the sampling axis when anisotropic)
+    pub fn sample_grad(&self, texture: &Image, uv: Vec2<f64>, ddx: Vec2<f64>, ddy: Vec2<f64>) -> Color {
+        if self.mipmap == MipmapMode::None || texture.mip_levels() == 1 {
+            return self.sample(texture, uv);
+        }
+
+        // Footprint of the pixel in texels of the full size image
+        let w = texture.width as f64;
+        let h = texture.height as f64;
+        let px = ((ddx.x * w).powi(2) + (ddx.y * h).powi(2)).sqrt();
+        let py = ((ddy.x * w).powi(2) + (ddy.y * h).powi(2)).sqrt();
+        let (major, minor, axis) = if px >= py { (px, py, ddx) } else { (py, px, ddy) };
+
+        let samples = if self.max_anisotropy > 1 && minor > 0.0 {
+            (major / minor).ceil().min(self.max_anisotropy as f64).max(1.0) as u32
+        } else {
+            1
+        };
+
+        // Taking more samples along the major axis lets us use a sharper level
+        let footprint = if samples > 1 { major / samples as f64 } else { major };
+        let lod = footprint.max(1e-8).log2().max(0.0);
+
+        if samples == 1 {
+            return self.sample_lod(texture, uv, lod);
+        }
+
+        // Spread the samples evenly along the major axis, centered on the pixel
+        let mut sum = [0.0; 4];
+        for i in 0..samples {
+            let t = (i as f64 + 0.5) / samples as f64 - 0.5;
+            let c = self.sample_lod(texture, Vec2::new(uv.x + axis.x * t, uv.y + axis.y * t), lod);
+            sum[0] += c.0 as f64;
+            sum[1] += c.1 as f64;
+            sum[2] += c.2 as f64;
+            sum[3] += c.3 as f64;
+        }
+        let n = samples as f64;
+        Color((sum[0] / n).round() as u8, (sum[1] / n).round() as u8,
+              (sum[2] / n).round() as u8, (sum[3] / n).round() as u8)
+    }
+
+    fn sample_lod(&self, texture: &Image, uv: Vec2<f64>, lod: f64) -> Color {
+        let max_level = (texture.mip_levels() - 1) as f64;
+        let lod = lod.min(max_level);
+
+        match self.mipmap {
+            MipmapMode::None => self.sample(texture, uv),
+            MipmapMode::Nearest => self.sample(texture.mip_level(lod.round() as usize), uv),
+            MipmapMode::Linear => {
+                let level = lod.floor();
+                let a = self.sample(texture.mip_level(level as usize), uv);
+                if level >= max_level {
+                    return a;
+                }
+                let b = self.sample(texture.mip_level(level as usize + 1), uv);
+                lerp_color(a, b, lod - level)
+            }
+        }
+    }
+
+    // Look up normalized texture coordinates, where texel i covers i/size to (i + 1)/size
+    pub fn sample(&self, texture: &Image, uv: Vec2<f64>) -> Color {
+        if texture.width <= 0 || texture.height <= 0 {
+            return self.border;
+        }
+
+        let x = uv.x * texture.width as f64;
+        let y = uv.y * texture.height as f64;
+
+        match self.filter {
+            Filter::Nearest => self.texel(texture, x.floor() as i32, y.floor() as i32),
+            Filter::Bilinear => {
+                // Offset by half a texel so we blend between the nearest texel centers
+                let x = x - 0.5;
+                let y = y - 0.5;
+                let x0 = x.floor();
+                let y0 = y.floor();
+                let fx = x - x0;
+                let fy = y - y0;
+                let (x0, y0) = (x0 as i32, y0 as i32);
+
+                let c00 = self.texel(texture, x0, y0);
+                let c10 = self.texel(texture, x0 + 1, y0);
+                let c01 = self.texel(texture, x0, y0 + 1);
+                let c11 = self.texel(texture, x0 + 1, y0 + 1);
+
+                let top = lerp_color(c00, c10, fx);
+                let bottom = lerp_color(c01, c11, fx);
+                lerp_color(top, bottom, fy)
+            }
+        }
+    }
+
+    // Fetch a single texel with the wrap modes applied to its integer coordinates
+    pub fn texel(&self, texture: &Image, x: i32, y: i32) -> Color {
+        match (wrap(self.wrap_u, x, texture.width), wrap(self.wrap_v, y, texture.height)) {
+            (Some(x), Some(y)) => texture.get_pixel(x, y),
+            _ => self.border,
+        }
+    }
+}
+
+fn wrap(mode: WrapMode, i: i32, size: i32) -> Option<i32> {
+    match mode {
+        WrapMode::Repeat => Some(((i % size) + size) % size),
+        WrapMode::ClampToEdge => Some(i.max(0).min(size - 1)),
+        WrapMode::MirroredRepeat => {
+            let period = size * 2;
+            let m = ((i % period) + period) % period;
+            if m < size {
+                Some(m)
+            } else {
+                Some(period - 1 - m)
+            }
+        },
+        WrapMode::Border => {
+            if i < 0 || i >= size {
+                None
+            } else {
+                Some(i)
+            }
+        }
+    }
+}
+
+pub fn lerp_color(a: Color, b: Color, t: f64) -> Color {
+    let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    Color(mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2), mix(a.3, b.3))
+}
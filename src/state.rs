@@ -0,0 +1,120 @@
+use blend::BlendState;
+use stencil::StencilState;
+use geo::Rect;
+use image::Image;
+use sampler::Sampler;
+
+// Polygon offset, like glPolygonOffset - positive values push the triangle away from the
+// viewer. The constant part is in zbuffer units, the slope part scales the steepest depth
+// change across the triangle so surfaces seen edge on get pushed further.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DepthBias {
+    pub constant: f64,
+    pub slope_scale: f64,
+    // Caps the size of the offset when non-zero
+    pub clamp: f64,
+}
+
+impl DepthBias {
+    pub fn new(constant: f64, slope_scale: f64) -> Self {
+        DepthBias {
+            constant: constant,
+            slope_scale: slope_scale,
+            clamp: 0.0,
+        }
+    }
+
+    pub fn offset(&self, max_slope: f64) -> f64 {
+        let bias = self.constant + self.slope_scale * max_slope;
+        if self.clamp > 0.0 {
+            bias.min(self.clamp)
+        } else if self.clamp < 0.0 {
+            bias.max(self.clamp)
+        } else {
+            bias
+        }
+    }
+}
+
+// Everything that can be configured for a single draw call
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrawState {
+    pub blend: BlendState,
+    pub stencil: StencilState,
+    // Where normalized coordinates land in the image, the whole image if unset
+    pub viewport: Option<Rect>,
+    // Pixels outside of this are never touched, independent of the viewport
+    pub scissor: Option<Rect>,
+    pub depth_bias: DepthBias,
+    // Used for every texture lookup
+    pub sampler: Sampler,
+}
+
+impl DrawState {
+    pub fn new() -> Self {
+        DrawState::default()
+    }
+
+    pub fn with_blend(self, blend: BlendState) -> Self {
+        DrawState {
+            blend: blend,
+            ..self
+        }
+    }
+
+    pub fn with_stencil(self, stencil: StencilState) -> Self {
+        DrawState {
+            stencil: stencil,
+            ..self
+        }
+    }
+
+    pub fn with_viewport(self, viewport: Rect) -> Self {
+        DrawState {
+            viewport: Some(viewport),
+            ..self
+        }
+    }
+
+    pub fn with_scissor(self, scissor: Rect) -> Self {
+        DrawState {
+            scissor: Some(scissor),
+            ..self
+        }
+    }
+
+    pub fn with_depth_bias(self, depth_bias: DepthBias) -> Self {
+        DrawState {
+            depth_bias: depth_bias,
+            ..self
+        }
+    }
+
+    pub fn with_sampler(self, sampler: Sampler) -> Self {
+        DrawState {
+            sampler: sampler,
+            ..self
+        }
+    }
+
+    pub fn viewport_rect(&self, image: &Image) -> Rect {
+        self.viewport_within(image.rect())
+    }
+
+    // The area fragments can actually be written to
+    pub fn scissor_rect(&self, image: &Image) -> Rect {
+        self.scissor_within(image.rect())
+    }
+
+    // Same as above for any render target covering the given bounds
+    pub fn viewport_within(&self, bounds: Rect) -> Rect {
+        self.viewport.unwrap_or(bounds)
+    }
+
+    pub fn scissor_within(&self, bounds: Rect) -> Rect {
+        match self.scissor {
+            Some(scissor) => scissor.intersect(&bounds),
+            None => bounds,
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// Comparison between the (masked) reference value and the (masked) stored stencil value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    Always,
+}
+
+impl CompareFunc {
+    pub fn compare(&self, reference: u8, stored: u8) -> bool {
+        match *self {
+            CompareFunc::Never => false,
+            CompareFunc::Less => reference < stored,
+            CompareFunc::LessEqual => reference <= stored,
+            CompareFunc::Greater => reference > stored,
+            CompareFunc::GreaterEqual => reference >= stored,
+            CompareFunc::Equal => reference == stored,
+            CompareFunc::NotEqual => reference != stored,
+            CompareFunc::Always => true,
+        }
+    }
+}
+
+// What happens to the stored value after the tests
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    Replace,
+    // Saturate at 255 and 0
+    Incr,
+    Decr,
+    // Wrap around instead
+    IncrWrap,
+    DecrWrap,
+    Invert,
+}
+
+impl StencilOp {
+    pub fn apply(&self, reference: u8, stored: u8) -> u8 {
+        match *self {
+            StencilOp::Keep => stored,
+            StencilOp::Zero => 0,
+            StencilOp::Replace => reference,
+            StencilOp::Incr => stored.saturating_add(1),
+            StencilOp::Decr => stored.saturating_sub(1),
+            StencilOp::IncrWrap => stored.wrapping_add(1),
+            StencilOp::DecrWrap => stored.wrapping_sub(1),
+            StencilOp::Invert => !stored,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StencilState {
+    pub enabled: bool,
+    pub reference: u8,
+    // Applied to both sides of the comparison
+    pub read_mask: u8,
+    // Only these bits of the stored value get updated
+    pub write_mask: u8,
+    pub func: CompareFunc,
+    pub stencil_fail: StencilOp,
+    pub depth_fail: StencilOp,
+    pub pass: StencilOp,
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        StencilState {
+            enabled: false,
+            reference: 0,
+            read_mask: 0xff,
+            write_mask: 0xff,
+            func: CompareFunc::Always,
+            stencil_fail: StencilOp::Keep,
+            depth_fail: StencilOp::Keep,
+            pass: StencilOp::Keep,
+        }
+    }
+}
+
+impl StencilState {
+    pub fn new(func: CompareFunc, reference: u8) -> Self {
+        StencilState {
+            enabled: true,
+            reference: reference,
+            func: func,
+            ..StencilState::default()
+        }
+    }
+
+    pub fn with_ops(self, stencil_fail: StencilOp, depth_fail: StencilOp, pass: StencilOp) -> Self {
+        StencilState {
+            stencil_fail: stencil_fail,
+            depth_fail: depth_fail,
+            pass: pass,
+            ..self
+        }
+    }
+
+    pub fn with_masks(self, read_mask: u8, write_mask: u8) -> Self {
+        StencilState {
+            read_mask: read_mask,
+            write_mask: write_mask,
+            ..self
+        }
+    }
+
+    pub fn test(&self, stored: u8) -> bool {
+        !self.enabled || self.func.compare(self.reference & self.read_mask, stored & self.read_mask)
+    }
+
+    // Run the op and merge the result into the stored value through the write mask
+    pub fn update(&self, op: StencilOp, stored: u8) -> u8 {
+        let value = op.apply(self.reference, stored);
+        (stored & !self.write_mask) | (value & self.write_mask)
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Write, BufWriter, Cursor};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::str::SplitWhitespace;
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+use geo::{Vertex, Vec3, cross_product};
+use mesh::Mesh;
+use model::Model;
+use error::{LoadError, read_file};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StlFormat {
+    Ascii,
+    Binary,
+}
+
+// Corners closer than this are welded together, which closes the tiny cracks exporters
+// leave between facets
+const WELD_TOLERANCE: f64 = 1e-5;
+
+const BINARY_HEADER_SIZE: usize = 80;
+const BINARY_FACET_SIZE: usize = 50;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+// Facets as read from the file, a normal and three corners each
+struct Facet {
+    normal: Vec3<f64>,
+    corners: [Vec3<f64>; 3],
+}
+
+pub fn read_stl_file(filename: &str) -> Result<Model, LoadError> {
+    let data = try!(read_file(filename));
+    decode_stl(&data).map_err(|e| LoadError::io(filename, e))
+}
+
+pub fn decode_stl(data: &[u8]) -> Result<Model, Error> {
+    let facets = if is_binary(data) {
+        try!(read_binary(data))
+    } else if data.starts_with(b"solid") {
+        try!(read_ascii(data))
+    } else {
+        return Err(invalid("Not an STL file"));
+    };
+
+    Ok(weld(&facets))
+}
+
+// Binary files are supposed to not start with "solid", but plenty do, so go by whether
+// the facet count matches the size instead
+fn is_binary(data: &[u8]) -> bool {
+    if data.len() < BINARY_HEADER_SIZE + 4 {
+        return false;
+    }
+    let count = (&data[BINARY_HEADER_SIZE..]).read_u32::<LittleEndian>().unwrap() as usize;
+    data.len() == BINARY_HEADER_SIZE + 4 + count * BINARY_FACET_SIZE
+}
+
+fn read_vec3(r: &mut Cursor<&[u8]>) -> Result<Vec3<f64>, Error> {
+    let x = try!(r.read_f32::<LittleEndian>()) as f64;
+    let y = try!(r.read_f32::<LittleEndian>()) as f64;
+    let z = try!(r.read_f32::<LittleEndian>()) as f64;
+    Ok(Vec3::new(x, y, z))
+}
+
+fn read_binary(data: &[u8]) -> Result<Vec<Facet>, Error> {
+    let mut r = Cursor::new(&data[BINARY_HEADER_SIZE..]);
+    let count = try!(r.read_u32::<LittleEndian>());
+    let mut facets = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        let normal = try!(read_vec3(&mut r));
+        let corners = [try!(read_vec3(&mut r)), try!(read_vec3(&mut r)), try!(read_vec3(&mut r))];
+        // Attribute byte count, some programs keep colors in here but nothing agrees how
+        try!(r.read_u16::<LittleEndian>());
+        facets.push(Facet { normal: normal, corners: corners });
+    }
+
+    Ok(facets)
+}
+
+fn number(tokens: &mut SplitWhitespace) -> Result<f64, Error> {
+    match tokens.next().and_then(|t| t.parse::<f64>().ok()) {
+        Some(n) => Ok(n),
+        None => Err(invalid("Expected a number in STL data")),
+    }
+}
+
+fn read_ascii(data: &[u8]) -> Result<Vec<Facet>, Error> {
+    let text = String::from_utf8_lossy(data);
+    let mut tokens = text.split_whitespace();
+    let mut facets = Vec::new();
+
+    let mut normal = Vec3::new(0.0, 0.0, 0.0);
+    let mut corners: Vec<Vec3<f64>> = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "normal" => {
+                normal = Vec3::new(try!(number(&mut tokens)), try!(number(&mut tokens)), try!(number(&mut tokens)));
+            },
+            "vertex" => {
+                corners.push(Vec3::new(try!(number(&mut tokens)), try!(number(&mut tokens)), try!(number(&mut tokens))));
+            },
+            "endloop" => {
+                // Loops are meant to be triangles, fan anything bigger
+                for i in 1..corners.len().saturating_sub(1) {
+                    facets.push(Facet { normal: normal, corners: [corners[0], corners[i], corners[i + 1]] });
+                }
+                corners.clear();
+            },
+            _ => {},
+        }
+    }
+
+    Ok(facets)
+}
+
+// Grid cell of a position, cells being as big as the weld tolerance
+fn weld_cell(p: &Vec3<f64>) -> (i64, i64, i64) {
+    ((p.x / WELD_TOLERANCE).floor() as i64,
+     (p.y / WELD_TOLERANCE).floor() as i64,
+     (p.z / WELD_TOLERANCE).floor() as i64)
+}
+
+// Share corners that are within the tolerance of each other and drop the facets that
+// collapse as a result. Points near a cell boundary can be welded to ones in the next cell
+// over, so the cells around it are searched as well.
+fn weld(facets: &[Facet]) -> Model {
+    let mut mesh = Mesh::new();
+    let mut cells: HashMap<(i64, i64, i64), Vec<u32>> = HashMap::new();
+    let mut normal_sums: Vec<Vec3<f64>> = Vec::new();
+
+    for facet in facets.iter() {
+        let mut idxs = [0; 3];
+        for (i, p) in facet.corners.iter().enumerate() {
+            let (cx, cy, cz) = weld_cell(p);
+            let mut found = None;
+            'search: for dx in -1..2 {
+                for dy in -1..2 {
+                    for dz in -1..2 {
+                        if let Some(candidates) = cells.get(&(cx + dx, cy + dy, cz + dz)) {
+                            for &c in candidates.iter() {
+                                let d = mesh.positions[c as usize] - *p;
+                                if d * d <= WELD_TOLERANCE * WELD_TOLERANCE {
+                                    found = Some(c);
+                                    break 'search;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            idxs[i] = match found {
+                Some(c) => c,
+                None => {
+                    let index = mesh.push_vertex(&Vertex::new(*p));
+                    cells.entry((cx, cy, cz)).or_insert_with(Vec::new).push(index);
+                    normal_sums.push(Vec3::new(0.0, 0.0, 0.0));
+                    index
+                }
+            };
+        }
+
+        if idxs[0] == idxs[1] || idxs[1] == idxs[2] || idxs[0] == idxs[2] {
+            continue;
+        }
+
+        // Every facet adds its normal to its corners, weighted by its area so slivers don't
+        // skew the result. Plenty of exporters write zeros, in which case it's worked out
+        // from the corners.
+        let p = [mesh.positions[idxs[0] as usize], mesh.positions[idxs[1] as usize], mesh.positions[idxs[2] as usize]];
+        let area_normal = cross_product(p[1] - p[0], p[2] - p[0]);
+        let n = facet.normal;
+        let weighted = if n.x == 0.0 && n.y == 0.0 && n.z == 0.0 {
+            area_normal
+        } else {
+            n.normalize() * (area_normal * area_normal).sqrt()
+        };
+        for &i in idxs.iter() {
+            normal_sums[i as usize] = normal_sums[i as usize] + weighted;
+        }
+        mesh.indices.extend(idxs.iter());
+    }
+
+    // Corners only used by facets that collapsed are left without a normal
+    mesh.normals = normal_sums.into_iter().map(|n| {
+        if n.x == 0.0 && n.y == 0.0 && n.z == 0.0 { n } else { n.normalize() }
+    }).collect();
+
+    let points = mesh.positions.iter().map(|&p| Vertex::new(p)).collect();
+    let mut model = Model::from_mesh(mesh);
+    model.points = points;
+    model
+}
+
+pub fn write_stl_file(model: &Model, filename: &str, format: StlFormat) -> Result<(), Error> {
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    let name = Path::new(filename).file_stem().and_then(|s| s.to_str()).unwrap_or("model");
+
+    match format {
+        StlFormat::Ascii => {
+            try!(write!(f, "solid {}\n", name));
+            for i in 0..model.mesh.triangle_count() {
+                let face = model.mesh.triangle(i);
+                let n = face.surface_normal();
+                try!(write!(f, "  facet normal {:e} {:e} {:e}\n    outer loop\n", n.x, n.y, n.z));
+                for v in face.vertices.iter() {
+                    try!(write!(f, "      vertex {:e} {:e} {:e}\n", v.coords.x, v.coords.y, v.coords.z));
+                }
+                try!(write!(f, "    endloop\n  endfacet\n"));
+            }
+            try!(write!(f, "endsolid {}\n", name));
+        },
+        StlFormat::Binary => {
+            // The header can be anything except the start of an ASCII file
+            let mut header = [0u8; BINARY_HEADER_SIZE];
+            let text = format!("binary STL {}", name);
+            let len = text.len().min(BINARY_HEADER_SIZE);
+            header[..len].copy_from_slice(&text.as_bytes()[..len]);
+            try!(f.write_all(&header));
+            try!(f.write_u32::<LittleEndian>(model.mesh.triangle_count() as u32));
+
+            for i in 0..model.mesh.triangle_count() {
+                let face = model.mesh.triangle(i);
+                let n = face.surface_normal();
+                let mut points = vec![n];
+                points.extend(face.vertices.iter().map(|v| v.coords));
+                for p in points.iter() {
+                    try!(f.write_f32::<LittleEndian>(p.x as f32));
+                    try!(f.write_f32::<LittleEndian>(p.y as f32));
+                    try!(f.write_f32::<LittleEndian>(p.z as f32));
+                }
+                try!(f.write_u16::<LittleEndian>(0));
+            }
+        },
+    }
+
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempFile;
+
+    // A tetrahedron, with the first facet missing its normal
+    const TETRAHEDRON: &'static [u8] = b"solid tet
+facet normal 0 0 0
+outer loop
+vertex 0 0 0
+vertex 0 1 0
+vertex 1 0 0
+endloop
+endfacet
+facet normal 0 -1 0
+outer loop
+vertex 0 0 0
+vertex 1 0 0
+vertex 0 0 1
+endloop
+endfacet
+facet normal -1 0 0
+outer loop
+vertex 0 0 0
+vertex 0 0 1
+vertex 0 1 0
+endloop
+endfacet
+facet normal 0.577 0.577 0.577
+outer loop
+vertex 1 0 0
+vertex 0 1 0
+vertex 0 0 1
+endloop
+endfacet
+endsolid tet
+";
+
+    fn same_triangles(a: &Model, b: &Model) {
+        assert_eq!(a.mesh.triangle_count(), b.mesh.triangle_count());
+        assert_eq!(a.mesh.vertex_count(), b.mesh.vertex_count());
+        for i in 0..a.mesh.triangle_count() {
+            for (u, v) in a.mesh.triangle(i).vertices.iter().zip(b.mesh.triangle(i).vertices.iter()) {
+                let d = u.coords - v.coords;
+                assert!(d * d < 1e-12, "{:?} vs {:?}", u.coords, v.coords);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let model = decode_stl(TETRAHEDRON).unwrap();
+        assert_eq!(model.mesh.triangle_count(), 4);
+        assert_eq!(model.mesh.vertex_count(), 4);
+
+        for &format in [StlFormat::Ascii, StlFormat::Binary].iter() {
+            let file = TempFile::new(&format!("round_trip_{:?}.stl", format));
+            write_stl_file(&model, file.name(), format).unwrap();
+            same_triangles(&model, &read_stl_file(file.name()).unwrap());
+        }
+    }
+
+    #[test]
+    fn welds_nearby_corners() {
+        // The shared corners are a hair apart, on either side of a grid line
+        let model = decode_stl(b"solid quad
+facet normal 0 0 1
+outer loop
+vertex 0 0 0
+vertex 1 0 0
+vertex 0.000009999 1 0
+endloop
+endfacet
+facet normal 0 0 0
+outer loop
+vertex 1.000001 0 0
+vertex 1 1 0
+vertex 0.0000100001 1 0
+endloop
+endfacet
+endsolid quad
+").unwrap();
+        assert_eq!(model.mesh.vertex_count(), 4);
+        assert_eq!(model.mesh.triangle_count(), 2);
+
+        // Corners get the average of the facets around them, so the one at the origin points
+        // out between the three facets on the axes and the others point away from it
+        let model = decode_stl(TETRAHEDRON).unwrap();
+        for i in 0..model.mesh.vertex_count() {
+            let p = model.mesh.positions[i];
+            let n = model.mesh.vertex(i).normal().unwrap();
+            assert!((n * n - 1.0).abs() < 1e-9);
+            if p * p == 0.0 {
+                assert!(n.x < 0.0 && n.y < 0.0 && n.z < 0.0);
+            } else {
+                assert!(n * p > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(decode_stl(b"not an stl").is_err());
+        assert!(decode_stl(b"solid x\nfacet normal 0 0 1\nouter loop\nvertex 0 zero 0\n").is_err());
+
+        // A binary file claiming more facets than it has isn't binary, and isn't ASCII either
+        let mut data = vec![0u8; BINARY_HEADER_SIZE];
+        data.extend(&[10, 0, 0, 0]);
+        data.extend(&[0; BINARY_FACET_SIZE]);
+        assert!(decode_stl(&data).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use image::{Image, Color};
+
+// A file in the temp directory, deleted again when it goes out of scope. Tests run in
+// parallel, so each one needs its own name.
+pub struct TempFile {
+    pub path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        TempFile { path: env::temp_dir().join(format!("rustrender-{}-{}", process::id(), name)) }
+    }
+
+    pub fn name(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// A directory in the temp directory, deleted along with everything in it
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = TempDir { path: TempFile::new(name).path.clone() };
+        fs::create_dir_all(&dir.path).unwrap();
+        dir
+    }
+
+    // Where a file in the directory goes
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Flat rows at the bottom for the run length encoders, and gradients with varying alpha
+// above them so anything dropped or reordered shows up
+pub fn test_image(width: i32, height: i32) -> Image {
+    let mut image = Image::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let c = if y < height / 2 {
+                Color(7, 200, 30, 255)
+            } else {
+                Color((x * 5) as u8, (y * 11) as u8, (x * y + 3) as u8, (255 - x * 3) as u8)
+            };
+            image.set_pixel(x, y, c);
+        }
+    }
+    image
+}
+
+// Formats without alpha read back as opaque
+pub fn assert_same_pixels(expected: &Image, actual: &Image, alpha: bool) {
+    assert_eq!((expected.width, expected.height), (actual.width, actual.height));
+    for y in 0..expected.height {
+        for x in 0..expected.width {
+            let c = expected.get_pixel(x, y);
+            let c = if alpha { c } else { c.with_alpha(255) };
+            assert_eq!(c, actual.get_pixel(x, y), "pixel {}, {}", x, y);
+        }
+    }
+}
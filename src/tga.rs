@@ -1,8 +1,10 @@
 use image::{Image, Color};
+use geo::Vec2;
 use std::fs::File;
-use std::io::Read;
-use std::io::Error;
-use byteorder::{ReadBytesExt, LittleEndian};
+use std::io::{Read, Write, BufWriter};
+use std::io::{Error, ErrorKind};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use error::LoadError;
 
 enum ImageType {
     NoImageData = 0,
@@ -92,52 +94,552 @@ impl TGAHeader {
             image_descriptor: try!(r.read_u8()),
         })
     }
+
+    fn to_writer(&self, w: &mut Write) -> Result<(), Error> {
+        try!(w.write_u8(self.id_length));
+        try!(w.write_u8(self.color_map_type));
+        try!(w.write_u8(self.data_type_code));
+        try!(w.write_u16::<LittleEndian>(self.color_map_origin));
+        try!(w.write_u16::<LittleEndian>(self.color_map_length));
+        try!(w.write_u8(self.color_map_depth));
+        try!(w.write_u16::<LittleEndian>(self.x_origin));
+        try!(w.write_u16::<LittleEndian>(self.y_origin));
+        try!(w.write_u16::<LittleEndian>(self.width));
+        try!(w.write_u16::<LittleEndian>(self.height));
+        try!(w.write_u8(self.bits_per_pixel));
+        try!(w.write_u8(self.image_descriptor));
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TGAWriteOptions {
+    // Run length encode the pixels (type 10 instead of type 2)
+    pub rle: bool,
+    // Write 32 bit BGRA rather than 24 bit BGR
+    pub alpha: bool,
+    // Append the TGA 2.0 extension area and footer
+    pub extension: bool,
 }
 
-pub fn read_tga_file(filename: &str) -> Result<Image, Error> {
+// Size of the TGA 2.0 extension area, it's fixed
+const EXTENSION_SIZE: u16 = 495;
+const FOOTER_SIGNATURE: &'static [u8] = b"TRUEVISION-XFILE.\0";
+
+pub fn read_tga_file(filename: &str) -> Result<Image, LoadError> {
+    read_tga(filename).map_err(|e| LoadError::io(filename, e))
+}
+
+fn read_tga(filename: &str) -> Result<Image, Error> {
     let mut f = try!(File::open(filename));
-    let header = TGAHeader::from_reader(&mut f).unwrap();
-    // TODO: Don't assume there is no color map, or all the other assumptions I'm making
+    let header = try!(TGAHeader::from_reader(&mut f));
     let width = header.width as usize;
     let height = header.height as usize;
     let bytes_per_pixel = (header.bits_per_pixel as usize + 7) / 8;
 
-    // Check the image type - we only currently handle run length encoded true color
     let image_type = ImageType::new(header.data_type_code);
-
-    let num_bytes = (height * width * bytes_per_pixel);
-    let mut image_buf = Vec::with_capacity(num_bytes);
-
     match image_type {
-        ImageType::RunTrueColor => {
-            while image_buf.len() < num_bytes {
-                let run_packet = try!(f.read_u8());
-                if (run_packet & 0x80) != 0 {
-                    // The highest bit is an indicator to repeat pixels
-                    let repeat_count = ((run_packet & !0x80) + 1) as usize;
-                    let mut data = Vec::with_capacity(bytes_per_pixel);
-                    try!(f.by_ref().take(bytes_per_pixel as u64).read_to_end(&mut data));
-                    for _ in 0usize..repeat_count {
-                        image_buf.extend(data.iter().map(|&c| c));
-                    }
-                } else {
-                    // We're dealing with non-encoded pixels
-                    let num_raw_bytes = (run_packet + 1) as usize * bytes_per_pixel;
-                    try!(f.by_ref().take(num_raw_bytes as u64).read_to_end(&mut image_buf));
-                }
-            }
+        ImageType::NoImageData | ImageType::Unknown => {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("Can't handle TGA image type {}", header.data_type_code)));
         },
-        _ => panic!("Can't handle this image type")
+        _ => {}
+    }
+    try!(check_depths(&header, &image_type));
+    if width == 0 || height == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "TGA image has no pixels"));
+    }
+
+    // Skip over the image ID, nothing we need is in there
+    let mut image_id = Vec::with_capacity(header.id_length as usize);
+    try!((&mut f).take(header.id_length as u64).read_to_end(&mut image_id));
+
+    // The color map comes before the image data whether or not the image uses it
+    let color_map = try!(read_color_map(&mut f, &header));
+    let alpha_bits = header.image_descriptor & 0x0f;
+
+    let num_bytes = height * width * bytes_per_pixel;
+    let image_buf = if image_type.is_encoded() {
+        try!(read_run_length_data(&mut f, num_bytes, bytes_per_pixel))
+    } else {
+        // Don't reserve from the header, a truncated file could claim gigabytes
+        let mut data = Vec::new();
+        try!((&mut f).take(num_bytes as u64).read_to_end(&mut data));
+        data
+    };
+
+    if image_buf.len() < num_bytes {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "TGA image data is truncated"));
     }
 
     let mut color_buf: Vec<Color> = Vec::with_capacity(width * height);
 
-    for chunk in image_buf.chunks(3) {
-        let color = Color(chunk[0], chunk[1], chunk[2]);
+    for chunk in image_buf[..num_bytes].chunks(bytes_per_pixel) {
+        let color = if image_type.is_color_mapped() {
+            // Indices are little endian and offset by the first entry in the map
+            let index = chunk.iter().rev().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            let index = index.wrapping_sub(header.color_map_origin as usize);
+            match color_map.get(index) {
+                Some(&c) => c,
+                None => return Err(Error::new(ErrorKind::InvalidData, "TGA color map index out of range")),
+            }
+        } else if image_type.is_color() {
+            try!(pixel_to_color(chunk, alpha_bits))
+        } else {
+            // Grayscale, with an alpha byte after the value if it's 16 bit
+            let alpha = if chunk.len() > 1 && alpha_bits > 0 { chunk[1] } else { 255 };
+            Color(chunk[0], chunk[0], chunk[0], alpha)
+        };
         color_buf.push(color);
     }
 
+    // Bits 4 and 5 of the descriptor say which corner the first pixel is in. The renderer
+    // expects the bottom-left, so flip anything that isn't stored that way.
+    if header.image_descriptor & 0x10 != 0 {
+        for row in color_buf.chunks_mut(width) {
+            row.reverse();
+        }
+    }
+    if header.image_descriptor & 0x20 != 0 {
+        color_buf = color_buf.chunks(width).rev().flat_map(|row| row.iter().cloned()).collect();
+    }
+
     let mut image = Image::new(width as i32, height as i32);
     image.set_data_buffer(color_buf);
+    image.origin = Vec2::new(header.x_origin as i32, header.y_origin as i32);
     Ok(image)
 }
+
+// Pixels have to be a size the decoder knows, and color-mapped images need a map to look
+// their indices up in
+fn check_depths(header: &TGAHeader, image_type: &ImageType) -> Result<(), Error> {
+    let bits = header.bits_per_pixel;
+    let pixels_ok = if image_type.is_color_mapped() {
+        header.color_map_type == 1 && (bits == 8 || bits == 16)
+    } else if image_type.is_color() {
+        bits == 15 || bits == 16 || bits == 24 || bits == 32
+    } else {
+        bits == 8 || bits == 16
+    };
+    if !pixels_ok {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              format!("Can't handle {} bit pixels in TGA image type {}",
+                                      bits, header.data_type_code)));
+    }
+
+    match (header.color_map_type, header.color_map_depth) {
+        (0, _) | (1, 15) | (1, 16) | (1, 24) | (1, 32) => Ok(()),
+        (1, depth) => Err(Error::new(ErrorKind::InvalidData,
+                                     format!("Can't handle {} bit TGA color map entries", depth))),
+        (map_type, _) => Err(Error::new(ErrorKind::InvalidData,
+                                        format!("Can't handle TGA color map type {}", map_type))),
+    }
+}
+
+fn read_color_map(r: &mut Read, header: &TGAHeader) -> Result<Vec<Color>, Error> {
+    if header.color_map_type == 0 {
+        return Ok(Vec::new());
+    }
+
+    let entry_size = (header.color_map_depth as usize + 7) / 8;
+    let num_bytes = header.color_map_length as usize * entry_size;
+    let mut data = Vec::new();
+    try!(r.take(num_bytes as u64).read_to_end(&mut data));
+
+    if data.len() < num_bytes {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "TGA color map is truncated"));
+    }
+
+    // Color map entries carry their own alpha if they're big enough to
+    let alpha_bits = match header.color_map_depth {
+        32 => 8,
+        16 => 1,
+        _ => 0,
+    };
+
+    let mut colors = Vec::with_capacity(header.color_map_length as usize);
+    for entry in data.chunks(entry_size) {
+        colors.push(try!(pixel_to_color(entry, alpha_bits)));
+    }
+    Ok(colors)
+}
+
+fn read_run_length_data(r: &mut Read, num_bytes: usize, bytes_per_pixel: usize) -> Result<Vec<u8>, Error> {
+    let mut image_buf = Vec::new();
+
+    while image_buf.len() < num_bytes {
+        let run_packet = try!(r.read_u8());
+        if (run_packet & 0x80) != 0 {
+            // The highest bit is an indicator to repeat pixels
+            let repeat_count = ((run_packet & !0x80) + 1) as usize;
+            let mut data = Vec::with_capacity(bytes_per_pixel);
+            try!(r.take(bytes_per_pixel as u64).read_to_end(&mut data));
+            if data.len() < bytes_per_pixel {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "TGA run length packet is truncated"));
+            }
+            for _ in 0usize..repeat_count {
+                image_buf.extend(data.iter().map(|&c| c));
+            }
+        } else {
+            // We're dealing with non-encoded pixels
+            let num_raw_bytes = (run_packet as usize + 1) * bytes_per_pixel;
+            let read = try!(r.take(num_raw_bytes as u64).read_to_end(&mut image_buf));
+            if read < num_raw_bytes {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "TGA raw packet is truncated"));
+            }
+        }
+    }
+
+    Ok(image_buf)
+}
+
+// True color pixels and color map entries are both stored BGR(A), the alpha bits from
+// the descriptor say whether the extra bits are actually alpha or just padding
+fn pixel_to_color(bytes: &[u8], alpha_bits: u8) -> Result<Color, Error> {
+    match bytes.len() {
+        2 => {
+            // 15/16 bit is ARRRRRGG GGGBBBBB, little endian
+            let v = (bytes[0] as u16) | ((bytes[1] as u16) << 8);
+            let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+            let alpha = if alpha_bits > 0 && (v & 0x8000) == 0 { 0 } else { 255 };
+            Ok(Color(expand(v & 0x1f), expand((v >> 5) & 0x1f), expand((v >> 10) & 0x1f), alpha))
+        },
+        3 => Ok(Color(bytes[0], bytes[1], bytes[2], 255)),
+        4 => {
+            let alpha = if alpha_bits > 0 { bytes[3] } else { 255 };
+            Ok(Color(bytes[0], bytes[1], bytes[2], alpha))
+        },
+        n => Err(Error::new(ErrorKind::InvalidData,
+                            format!("Can't handle {} bit TGA pixels", n * 8))),
+    }
+}
+
+pub fn write_tga_file(image: &Image, filename: &str, options: &TGAWriteOptions) -> Result<(), Error> {
+    let header = TGAHeader {
+        id_length: 0,
+        color_map_type: 0,
+        data_type_code: if options.rle { ImageType::RunTrueColor } else { ImageType::RawTrueColor } as u8,
+        color_map_origin: 0,
+        color_map_length: 0,
+        color_map_depth: 0,
+        x_origin: image.origin.x as u16,
+        y_origin: image.origin.y as u16,
+        width: image.width as u16,
+        height: image.height as u16,
+        bits_per_pixel: if options.alpha { 32 } else { 24 },
+        // Low bits of the descriptor are the number of alpha bits, origin is bottom-left
+        image_descriptor: if options.alpha { 8 } else { 0 },
+    };
+
+    let mut f = BufWriter::new(try!(File::create(filename)));
+    try!(header.to_writer(&mut f));
+
+    let bytes_per_pixel = if options.alpha { 4 } else { 3 };
+    let mut written = 18;
+
+    for y in 0..image.height {
+        let row: Vec<Color> = (0..image.width).map(|x| image.get_pixel(x, y)).collect();
+        let data = if options.rle {
+            encode_run_length_row(&row, bytes_per_pixel)
+        } else {
+            row.iter().flat_map(|c| color_to_pixel(c, bytes_per_pixel)).collect()
+        };
+        try!(f.write_all(&data));
+        written += data.len();
+    }
+
+    if options.extension {
+        try!(write_extension_area(&mut f, options.alpha));
+        // The footer points back at the extension area we just wrote
+        try!(f.write_u32::<LittleEndian>(written as u32));
+        try!(f.write_u32::<LittleEndian>(0));
+        try!(f.write_all(FOOTER_SIGNATURE));
+    }
+
+    f.flush()
+}
+
+fn color_to_pixel(c: &Color, bytes_per_pixel: usize) -> Vec<u8> {
+    let bytes = [c.0, c.1, c.2, c.3];
+    bytes[..bytes_per_pixel].to_vec()
+}
+
+// Packets never cross scanlines, as TGA 2.0 asks
+fn encode_run_length_row(row: &[Color], bytes_per_pixel: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < row.len() {
+        // How many times does this pixel repeat?
+        let mut run = 1;
+        while i + run < row.len() && run < 128 && row[i + run] == row[i] {
+            run += 1;
+        }
+
+        if run > 1 {
+            out.push(0x80 | (run - 1) as u8);
+            out.extend(color_to_pixel(&row[i], bytes_per_pixel));
+            i += run;
+        } else {
+            // Gather raw pixels until the next repeat starts
+            let start = i;
+            while i < row.len() && i - start < 128 &&
+                !(i + 1 < row.len() && row[i + 1] == row[i]) {
+                i += 1;
+            }
+            // A repeat starting right away still needs at least one raw pixel to move on
+            if i == start {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            for c in &row[start..i] {
+                out.extend(color_to_pixel(c, bytes_per_pixel));
+            }
+        }
+    }
+
+    out
+}
+
+fn write_extension_area(w: &mut Write, alpha: bool) -> Result<(), Error> {
+    let fixed_string = |w: &mut Write, s: &str, len: usize| -> Result<(), Error> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        w.write_all(&bytes)
+    };
+
+    try!(w.write_u16::<LittleEndian>(EXTENSION_SIZE));
+    // Author name and comments
+    try!(fixed_string(w, "", 41));
+    try!(fixed_string(w, "", 324));
+    // Date/time stamp, zero means not set
+    try!(w.write_all(&[0; 12]));
+    // Job name and job time
+    try!(fixed_string(w, "", 41));
+    try!(w.write_all(&[0; 6]));
+    // Software ID and version
+    try!(fixed_string(w, "rustrender", 41));
+    try!(w.write_u16::<LittleEndian>(10));
+    try!(w.write_u8(b' '));
+    // Key color
+    try!(w.write_u32::<LittleEndian>(0));
+    // Pixel aspect ratio and gamma, zero denominators mean not specified
+    try!(w.write_all(&[0; 8]));
+    // Color correction, postage stamp and scan line table offsets
+    try!(w.write_all(&[0; 12]));
+    // Attributes type - 3 means the alpha channel is real alpha
+    try!(w.write_u8(if alpha { 3 } else { 0 }));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{TempFile, test_image, assert_same_pixels};
+
+    fn header(image_type: u8, bits_per_pixel: u8, width: u16, height: u16) -> TGAHeader {
+        TGAHeader {
+            id_length: 0,
+            color_map_type: 0,
+            data_type_code: image_type,
+            color_map_origin: 0,
+            color_map_length: 0,
+            color_map_depth: 0,
+            x_origin: 0,
+            y_origin: 0,
+            width: width,
+            height: height,
+            bits_per_pixel: bits_per_pixel,
+            image_descriptor: 0,
+        }
+    }
+
+    // Hand-built files for the paths the writer never produces
+    fn write_raw(name: &str, header: &TGAHeader, data: &[u8]) -> TempFile {
+        let file = TempFile::new(name);
+        let mut bytes = Vec::new();
+        header.to_writer(&mut bytes).unwrap();
+        bytes.extend_from_slice(data);
+        ::std::fs::write(&file.path, &bytes).unwrap();
+        file
+    }
+
+    // Pixels in file order, which is bottom-left first unless the descriptor says otherwise
+    fn assert_pixels(image: &Image, expected: &[Color]) {
+        let actual: Vec<Color> = (0..image.height)
+            .flat_map(|y| (0..image.width).map(move |x| (x, y)))
+            .map(|(x, y)| image.get_pixel(x, y))
+            .collect();
+        assert_eq!(actual, expected);
+    }
+
+    fn assert_invalid(name: &str, header: &TGAHeader, data: &[u8]) {
+        let file = write_raw(name, header, data);
+        match read_tga(file.name()) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {},
+            other => panic!("{}: expected invalid data, got {:?}", name, other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        // Wider than the 128 pixels a packet can hold
+        let image = test_image(150, 7);
+        for &rle in [false, true].iter() {
+            for &alpha in [false, true].iter() {
+                for &extension in [false, true].iter() {
+                    let file = TempFile::new(&format!("round_trip_{}_{}_{}.tga", rle, alpha, extension));
+                    let options = TGAWriteOptions { rle: rle, alpha: alpha, extension: extension };
+                    write_tga_file(&image, file.name(), &options).unwrap();
+                    assert_same_pixels(&image, &read_tga_file(file.name()).unwrap(), alpha);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_file() {
+        let image = test_image(20, 10);
+        let file = TempFile::new("truncated.tga");
+        write_tga_file(&image, file.name(), &TGAWriteOptions::default()).unwrap();
+        let data = ::std::fs::read(&file.path).unwrap();
+        ::std::fs::write(&file.path, &data[..data.len() / 2]).unwrap();
+        assert!(read_tga_file(file.name()).is_err());
+    }
+
+    #[test]
+    fn bad_depths() {
+        let pixels = [0u8; 64];
+        assert_invalid("zero_bits.tga", &header(2, 0, 2, 2), &pixels);
+        assert_invalid("odd_bits.tga", &header(2, 12, 2, 2), &pixels);
+        assert_invalid("gray_24_bits.tga", &header(3, 24, 2, 2), &pixels);
+        // Color-mapped without a color map
+        assert_invalid("no_map.tga", &header(1, 8, 2, 2), &pixels);
+
+        let mut mapped = header(1, 8, 2, 2);
+        mapped.color_map_type = 1;
+        mapped.color_map_length = 2;
+        assert_invalid("zero_map_depth.tga", &mapped, &pixels);
+        mapped.color_map_depth = 24;
+        mapped.bits_per_pixel = 24;
+        assert_invalid("wide_index.tga", &mapped, &pixels);
+        mapped.bits_per_pixel = 8;
+        mapped.color_map_type = 7;
+        assert_invalid("map_type.tga", &mapped, &pixels);
+    }
+
+    #[test]
+    fn zero_sized() {
+        let mut flipped = header(2, 24, 0, 2);
+        flipped.image_descriptor = 0x30;
+        assert_invalid("zero_width.tga", &flipped, &[]);
+        assert_invalid("zero_height.tga", &header(2, 24, 2, 0), &[]);
+    }
+
+    #[test]
+    fn color_mapped() {
+        // Three 24 bit entries, numbered from 2
+        let map = [10, 20, 30, 40, 50, 60, 70, 80, 90];
+        let colors = [Color(10, 20, 30, 255), Color(40, 50, 60, 255), Color(70, 80, 90, 255)];
+        let mut mapped = header(1, 8, 2, 2);
+        mapped.color_map_type = 1;
+        mapped.color_map_origin = 2;
+        mapped.color_map_length = 3;
+        mapped.color_map_depth = 24;
+        let expected = [colors[2], colors[0], colors[1], colors[1]];
+
+        let mut data = map.to_vec();
+        data.extend_from_slice(&[4, 2, 3, 3]);
+        let file = write_raw("mapped.tga", &mapped, &data);
+        assert_pixels(&read_tga_file(file.name()).unwrap(), &expected);
+
+        // Run length encoded: two raw indices, then a repeat of two
+        mapped.data_type_code = 9;
+        let mut data = map.to_vec();
+        data.extend_from_slice(&[0x01, 4, 2, 0x81, 3]);
+        let file = write_raw("mapped_rle.tga", &mapped, &data);
+        assert_pixels(&read_tga_file(file.name()).unwrap(), &expected);
+
+        // Indices outside the map are errors
+        mapped.data_type_code = 1;
+        let mut data = map.to_vec();
+        data.extend_from_slice(&[4, 2, 1, 5]);
+        assert_invalid("mapped_range.tga", &mapped, &data);
+    }
+
+    #[test]
+    fn grayscale() {
+        let file = write_raw("gray.tga", &header(3, 8, 3, 1), &[0, 128, 255]);
+        assert_pixels(&read_tga_file(file.name()).unwrap(),
+                      &[Color(0, 0, 0, 255), Color(128, 128, 128, 255), Color(255, 255, 255, 255)]);
+
+        // 16 bit is value then alpha, which only counts when the descriptor has alpha bits
+        let mut gray = header(11, 16, 3, 1);
+        let data = [0x81, 100, 50, 0x00, 200, 0];
+        let file = write_raw("gray_rle.tga", &gray, &data);
+        assert_pixels(&read_tga_file(file.name()).unwrap(),
+                      &[Color(100, 100, 100, 255), Color(100, 100, 100, 255), Color(200, 200, 200, 255)]);
+        gray.image_descriptor = 8;
+        let file = write_raw("gray_alpha.tga", &gray, &data);
+        assert_pixels(&read_tga_file(file.name()).unwrap(),
+                      &[Color(100, 100, 100, 50), Color(100, 100, 100, 50), Color(200, 200, 200, 0)]);
+    }
+
+    #[test]
+    fn sixteen_bit_and_alpha() {
+        // ARRRRRGG GGGBBBBB: opaque red, transparent blue, transparent half green
+        let data = [0x00, 0xfc, 0x1f, 0x00, 0x00, 0x02];
+        let mut pixels = header(2, 16, 3, 1);
+        let file = write_raw("16_bit.tga", &pixels, &data);
+        assert_pixels(&read_tga_file(file.name()).unwrap(),
+                      &[Color(0, 0, 255, 255), Color(255, 0, 0, 255), Color(0, 132, 0, 255)]);
+        pixels.image_descriptor = 1;
+        let file = write_raw("16_bit_alpha.tga", &pixels, &data);
+        assert_pixels(&read_tga_file(file.name()).unwrap(),
+                      &[Color(0, 0, 255, 255), Color(255, 0, 0, 0), Color(0, 132, 0, 0)]);
+        pixels.bits_per_pixel = 15;
+        pixels.image_descriptor = 0;
+        let file = write_raw("15_bit.tga", &pixels, &data);
+        assert_pixels(&read_tga_file(file.name()).unwrap(),
+                      &[Color(0, 0, 255, 255), Color(255, 0, 0, 255), Color(0, 132, 0, 255)]);
+
+        // The fourth byte of 32 bit pixels is padding unless the descriptor says it's alpha
+        let data = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut pixels = header(2, 32, 2, 1);
+        let file = write_raw("32_bit.tga", &pixels, &data);
+        assert_pixels(&read_tga_file(file.name()).unwrap(), &[Color(1, 2, 3, 255), Color(5, 6, 7, 255)]);
+        pixels.image_descriptor = 8;
+        let file = write_raw("32_bit_alpha.tga", &pixels, &data);
+        assert_pixels(&read_tga_file(file.name()).unwrap(), &[Color(1, 2, 3, 4), Color(5, 6, 7, 8)]);
+    }
+
+    #[test]
+    fn origins_and_image_id() {
+        let (a, b, c, d) = (Color(1, 1, 1, 255), Color(2, 2, 2, 255), Color(3, 3, 3, 255), Color(4, 4, 4, 255));
+        let data = [1, 1, 1, 2, 2, 2, 3, 3, 3, 4, 4, 4];
+        let cases = [
+            (0x00, [a, b, c, d]),
+            (0x10, [b, a, d, c]),
+            (0x20, [c, d, a, b]),
+            (0x30, [d, c, b, a]),
+        ];
+        for &(descriptor, expected) in cases.iter() {
+            let mut pixels = header(2, 24, 2, 2);
+            pixels.image_descriptor = descriptor;
+            let file = write_raw(&format!("origin_{}.tga", descriptor), &pixels, &data);
+            assert_pixels(&read_tga_file(file.name()).unwrap(), &expected);
+        }
+
+        // The image ID is skipped and the header origin is kept
+        let mut pixels = header(2, 24, 2, 2);
+        pixels.id_length = 3;
+        pixels.x_origin = 5;
+        pixels.y_origin = 9;
+        let mut with_id = b"id!".to_vec();
+        with_id.extend_from_slice(&data);
+        let file = write_raw("image_id.tga", &pixels, &with_id);
+        let image = read_tga_file(file.name()).unwrap();
+        assert_pixels(&image, &[a, b, c, d]);
+        assert_eq!((image.origin.x, image.origin.y), (5, 9));
+    }
+}
@@ -0,0 +1,139 @@
+use image::{Image, Color};
+use hdr::{HdrImage, HdrColor};
+
+// How the bytes of an 8 bit color relate to light
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorSpace {
+    // Gamma encoded, which is what image files almost always hold
+    Srgb,
+    // Bytes are proportional to light, e.g. normal or bump maps
+    Linear,
+}
+
+impl ColorSpace {
+    // To linear light, alpha is always stored linearly
+    pub fn decode(&self, c: Color) -> HdrColor {
+        let linear = HdrColor::from_color(c);
+        match *self {
+            ColorSpace::Srgb => HdrColor::new(srgb_to_linear(linear.r), srgb_to_linear(linear.g),
+                                              srgb_to_linear(linear.b), linear.a),
+            ColorSpace::Linear => linear,
+        }
+    }
+
+    // From linear light, anything outside 0 to 1 is clipped
+    pub fn encode(&self, c: HdrColor) -> Color {
+        match *self {
+            ColorSpace::Srgb => HdrColor::new(linear_to_srgb(c.r), linear_to_srgb(c.g),
+                                              linear_to_srgb(c.b), c.a).to_color(),
+            ColorSpace::Linear => c.to_color(),
+        }
+    }
+}
+
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(v: f32) -> f32 {
+    let v = v.max(0.0).min(1.0);
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Squeezes unbounded light into 0 to 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneCurve {
+    // Just clip, fine when nothing goes past 1.0
+    Clamp,
+    // x / (1 + x), never quite reaches white
+    Reinhard,
+    // Reinhard with the given value mapped to white
+    ReinhardExtended(f32),
+    // Narkowicz's fit of the ACES filmic curve
+    Aces,
+}
+
+impl ToneCurve {
+    pub fn apply(&self, x: f32) -> f32 {
+        let x = x.max(0.0);
+        let y = match *self {
+            ToneCurve::Clamp => x,
+            ToneCurve::Reinhard => x / (1.0 + x),
+            ToneCurve::ReinhardExtended(white) => x * (1.0 + x / (white * white)) / (1.0 + x),
+            ToneCurve::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        };
+        y.min(1.0)
+    }
+}
+
+// Turns an HDR framebuffer into something displayable: exposure, then the curve, then
+// encoding to the output color space
+#[derive(Clone, Copy, Debug)]
+pub struct ToneMapper {
+    pub curve: ToneCurve,
+    // In stops, every +1 doubles the light
+    pub exposure: f32,
+    pub output: ColorSpace,
+}
+
+impl Default for ToneMapper {
+    fn default() -> Self {
+        ToneMapper::new(ToneCurve::Clamp)
+    }
+}
+
+impl ToneMapper {
+    pub fn new(curve: ToneCurve) -> Self {
+        ToneMapper {
+            curve: curve,
+            exposure: 0.0,
+            output: ColorSpace::Srgb,
+        }
+    }
+
+    pub fn reinhard() -> Self {
+        ToneMapper::new(ToneCurve::Reinhard)
+    }
+
+    pub fn aces() -> Self {
+        ToneMapper::new(ToneCurve::Aces)
+    }
+
+    pub fn with_exposure(self, exposure: f32) -> Self {
+        ToneMapper {
+            exposure: exposure,
+            ..self
+        }
+    }
+
+    pub fn with_output(self, output: ColorSpace) -> Self {
+        ToneMapper {
+            output: output,
+            ..self
+        }
+    }
+
+    pub fn map(&self, c: HdrColor) -> Color {
+        let c = c.scale(2f32.powf(self.exposure));
+        let mapped = HdrColor::new(self.curve.apply(c.r), self.curve.apply(c.g), self.curve.apply(c.b), c.a);
+        self.output.encode(mapped)
+    }
+
+    pub fn apply(&self, image: &HdrImage) -> Image {
+        let mut out = Image::new(image.width, image.height);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                out.set_pixel(x, y, self.map(image.get_pixel(x, y)));
+            }
+        }
+        out
+    }
+}
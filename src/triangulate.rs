@@ -0,0 +1,192 @@
+use geo::{Vec2, Vec3};
+
+// Split a polygon into triangles by ear clipping, returning indices into the points.
+// Works for concave polygons as long as they're roughly planar and don't intersect
+// themselves. Triangles keep the winding of the polygon.
+pub fn ear_clip(points: &[Vec3<f64>]) -> Vec<[usize; 3]> {
+    let n = points.len();
+    if n < 3 {
+        return Vec::new();
+    }
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+
+    let flat = project(points);
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = Vec::with_capacity(n - 2);
+
+    while remaining.len() > 3 {
+        let count = remaining.len();
+        let ear = (0..count).find(|&i| {
+            let a = remaining[(i + count - 1) % count];
+            let b = remaining[i];
+            let c = remaining[(i + 1) % count];
+            is_ear(&flat, &remaining, a, b, c)
+        });
+
+        match ear {
+            Some(i) => {
+                triangles.push([remaining[(i + count - 1) % count], remaining[i], remaining[(i + 1) % count]]);
+                remaining.remove(i);
+            },
+            None => {
+                // Degenerate or self intersecting, a fan is as good as anything
+                for i in 1..count - 1 {
+                    triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+                }
+                return triangles;
+            },
+        }
+    }
+
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+    triangles
+}
+
+// Flatten onto the axis plane the polygon faces most, flipped if needed so it winds
+// counter-clockwise in 2D
+fn project(points: &[Vec3<f64>]) -> Vec<Vec2<f64>> {
+    // Newell's method, which copes with concave polygons
+    let mut normal = Vec3::new(0.0, 0.0, 0.0);
+    for i in 0..points.len() {
+        let p = points[i];
+        let q = points[(i + 1) % points.len()];
+        normal.x += (p.y - q.y) * (p.z + q.z);
+        normal.y += (p.z - q.z) * (p.x + q.x);
+        normal.z += (p.x - q.x) * (p.y + q.y);
+    }
+
+    let (ax, ay, az) = (normal.x.abs(), normal.y.abs(), normal.z.abs());
+    points.iter().map(|p| {
+        if az >= ax && az >= ay {
+            if normal.z >= 0.0 { Vec2::new(p.x, p.y) } else { Vec2::new(p.y, p.x) }
+        } else if ax >= ay {
+            if normal.x >= 0.0 { Vec2::new(p.y, p.z) } else { Vec2::new(p.z, p.y) }
+        } else {
+            if normal.y >= 0.0 { Vec2::new(p.z, p.x) } else { Vec2::new(p.x, p.z) }
+        }
+    }).collect()
+}
+
+fn cross(a: Vec2<f64>, b: Vec2<f64>, c: Vec2<f64>) -> f64 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+fn is_ear(flat: &[Vec2<f64>], remaining: &[usize], a: usize, b: usize, c: usize) -> bool {
+    let (pa, pb, pc) = (flat[a], flat[b], flat[c]);
+
+    // Reflex or flat corners can't be clipped
+    if cross(pa, pb, pc) <= 0.0 {
+        return false;
+    }
+
+    // No other corner may be inside the ear, including ones on its edges
+    !remaining.iter().any(|&i| {
+        if i == a || i == b || i == c {
+            return false;
+        }
+        let p = flat[i];
+        if (p.x == pa.x && p.y == pa.y) || (p.x == pb.x && p.y == pb.y) || (p.x == pc.x && p.y == pc.y) {
+            return false;
+        }
+        cross(pa, pb, p) >= 0.0 && cross(pb, pc, p) >= 0.0 && cross(pc, pa, p) >= 0.0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn polygon(points: &[(f64, f64)]) -> Vec<Vec3<f64>> {
+        points.iter().map(|&(x, y)| Vec3::new(x, y, 0.0)).collect()
+    }
+
+    // Twice the signed area, positive for counter-clockwise
+    fn area(points: &[Vec3<f64>]) -> f64 {
+        (0..points.len()).map(|i| {
+            let (p, q) = (points[i], points[(i + 1) % points.len()]);
+            p.x * q.y - q.x * p.y
+        }).sum()
+    }
+
+    fn triangle(points: &[Vec3<f64>], tri: [usize; 3]) -> Vec<Vec3<f64>> {
+        tri.iter().map(|&i| points[i]).collect()
+    }
+
+    fn inside(points: &[Vec3<f64>], p: Vec3<f64>) -> bool {
+        let mut inside = false;
+        for i in 0..points.len() {
+            let (a, b) = (points[i], points[(i + 1) % points.len()]);
+            if (a.y > p.y) != (b.y > p.y) && p.x < a.x + (p.y - a.y) * (b.x - a.x) / (b.y - a.y) {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    // Triangles that all wind the same way as the polygon, sit inside it and add up to its
+    // area can't overlap or leave gaps
+    fn assert_covers(points: &[Vec3<f64>]) {
+        let triangles = ear_clip(points);
+        assert_eq!(triangles.len(), points.len() - 2);
+        let sign = area(points).signum();
+        let mut total = 0.0;
+        for &tri in triangles.iter() {
+            let corners = triangle(points, tri);
+            let a = area(&corners);
+            assert!(a * sign > 0.0, "{:?} winds the wrong way", tri);
+            let centroid = (corners[0] + corners[1] + corners[2]) * (1.0 / 3.0);
+            assert!(inside(points, centroid), "{:?} is outside the polygon", tri);
+            total += a;
+        }
+        assert!((total - area(points)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn concave() {
+        // An arrow head, with its reflex corner at 2
+        let arrow = polygon(&[(0.0, 0.0), (4.0, 2.0), (1.0, 2.0), (0.0, 5.0)]);
+        assert_covers(&arrow);
+        // A comb, whose first corners aren't ears
+        let comb = polygon(&[(0.0, 0.0), (5.0, 0.0), (5.0, 3.0), (4.0, 3.0), (4.0, 1.0), (3.0, 1.0),
+                             (3.0, 3.0), (2.0, 3.0), (2.0, 1.0), (1.0, 1.0), (1.0, 3.0), (0.0, 3.0)]);
+        assert_covers(&comb);
+
+        // The same shapes wound clockwise stay clockwise
+        let reversed: Vec<Vec3<f64>> = comb.iter().rev().cloned().collect();
+        assert_covers(&reversed);
+    }
+
+    #[test]
+    fn off_axis() {
+        // An L shape standing in the xz plane, tilted a little off it
+        let points: Vec<Vec3<f64>> = [(0.0, 0.0), (2.0, 0.0), (2.0, 1.0), (1.0, 1.0), (1.0, 2.0), (0.0, 2.0)]
+            .iter().map(|&(x, z)| Vec3::new(x, 0.1 * x, z)).collect();
+        let triangles = ear_clip(&points);
+        assert_eq!(triangles.len(), 4);
+        // Every triangle faces the same way as the polygon does
+        let normal = |t: [usize; 3]| {
+            let (a, b, c) = (points[t[0]], points[t[1]], points[t[2]]);
+            let (u, v) = (b - a, c - a);
+            u.z * v.x - u.x * v.z
+        };
+        assert!(triangles.iter().all(|&t| normal(t) < 0.0));
+    }
+
+    #[test]
+    fn degenerate() {
+        assert!(ear_clip(&polygon(&[(0.0, 0.0), (1.0, 0.0)])).is_empty());
+
+        // All in a line, anything is fine as long as the indices are
+        let line = polygon(&[(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (3.0, 0.0), (4.0, 0.0)]);
+        let triangles = ear_clip(&line);
+        assert_eq!(triangles.len(), 3);
+        assert!(triangles.iter().all(|t| t.iter().all(|&i| i < line.len())));
+
+        // A corner in the middle of an edge still gets the whole square covered
+        let square = polygon(&[(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (2.0, 2.0), (0.0, 2.0)]);
+        let total: f64 = ear_clip(&square).iter().map(|&t| area(&triangle(&square, t))).sum();
+        assert!((total - area(&square)).abs() < 1e-9);
+    }
+}
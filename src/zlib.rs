@@ -0,0 +1,502 @@
+use std::io::{Error, ErrorKind};
+
+// Self contained zlib (RFC 1950) and deflate (RFC 1951), enough for PNG and OpenEXR.
+// Inflate handles stored, fixed and dynamic Huffman blocks. Deflate does LZ77 matching with
+// hash chains and writes fixed Huffman blocks, which compresses reasonably well without
+// having to build code tables.
+
+const MAX_BITS: usize = 15;
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// How many earlier positions with the same hash we're willing to check for a match
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: usize = 15;
+
+// Base lengths and extra bits for length codes 257 to 285
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+                                35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+                                3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+// Base distances and extra bits for distance codes 0 to 29
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+                              257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+                              8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+                              7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order the code length code lengths are stored in for dynamic blocks
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the most bytes we can sum before b might overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+// Unwrap a zlib stream and check its checksum
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() < 6 {
+        return Err(invalid("zlib stream is too short"));
+    }
+
+    let cmf = data[0];
+    let flg = data[1];
+    if cmf & 0x0f != 8 || ((cmf as u16) << 8 | flg as u16) % 31 != 0 {
+        return Err(invalid("Not a deflate zlib stream"));
+    }
+    if flg & 0x20 != 0 {
+        return Err(invalid("zlib preset dictionaries aren't supported"));
+    }
+
+    let (out, used) = try!(inflate(&data[2..]));
+    let end = 2 + used;
+    if data.len() < end + 4 {
+        return Err(invalid("zlib stream is missing its checksum"));
+    }
+
+    let expected = ((data[end] as u32) << 24) | ((data[end + 1] as u32) << 16) |
+        ((data[end + 2] as u32) << 8) | data[end + 3] as u32;
+    if adler32(&out) != expected {
+        return Err(invalid("zlib checksum mismatch"));
+    }
+
+    Ok(out)
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, default compression level
+    let mut out = vec![0x78, 0x9c];
+    out.extend(deflate(data));
+    let adler = adler32(data);
+    out.extend(&[(adler >> 24) as u8, (adler >> 16) as u8, (adler >> 8) as u8, adler as u8]);
+    out
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data: data,
+            pos: 0,
+            bit_buf: 0,
+            bit_count: 0,
+        }
+    }
+
+    // Deflate packs bits starting from the least significant
+    fn bits(&mut self, n: u32) -> Result<u32, Error> {
+        while self.bit_count < n {
+            if self.pos >= self.data.len() {
+                return Err(invalid("Unexpected end of deflate data"));
+            }
+            self.bit_buf |= (self.data[self.pos] as u32) << self.bit_count;
+            self.pos += 1;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1u64 << n) - 1) as u32;
+        self.bit_buf >>= n;
+        self.bit_count -= n;
+        Ok(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+// Canonical Huffman code, stored as the number of codes of each length and the symbols in
+// code order - decoding walks it a bit at a time, like zlib's puff
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, Error> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Make sure the code isn't over-subscribed
+        let mut left: i32 = 1;
+        for len in 1..MAX_BITS + 1 {
+            left <<= 1;
+            left -= counts[len] as i32;
+            if left < 0 {
+                return Err(invalid("Over-subscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..MAX_BITS + 1 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Ok(Huffman {
+            counts: counts,
+            symbols: symbols,
+        })
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u16, Error> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..MAX_BITS + 1 {
+            code |= try!(r.bits(1)) as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(invalid("Invalid Huffman code"))
+    }
+}
+
+// Raw deflate data, returns the output and how many input bytes were consumed
+pub fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    let mut r = BitReader::new(data);
+    let mut out: Vec<u8> = Vec::new();
+
+    loop {
+        let last = try!(r.bits(1));
+        match try!(r.bits(2)) {
+            0 => try!(inflate_stored(&mut r, &mut out)),
+            1 => {
+                let (lit, dist) = try!(fixed_tables());
+                try!(inflate_block(&mut r, &mut out, &lit, &dist));
+            },
+            2 => {
+                let (lit, dist) = try!(dynamic_tables(&mut r));
+                try!(inflate_block(&mut r, &mut out, &lit, &dist));
+            },
+            _ => return Err(invalid("Invalid deflate block type")),
+        }
+
+        if last == 1 {
+            break;
+        }
+    }
+
+    Ok((out, r.pos))
+}
+
+fn inflate_stored(r: &mut BitReader, out: &mut Vec<u8>) -> Result<(), Error> {
+    r.align_to_byte();
+    if r.pos + 4 > r.data.len() {
+        return Err(invalid("Unexpected end of stored block"));
+    }
+
+    let len = r.data[r.pos] as usize | (r.data[r.pos + 1] as usize) << 8;
+    let nlen = r.data[r.pos + 2] as usize | (r.data[r.pos + 3] as usize) << 8;
+    if len != !nlen & 0xffff {
+        return Err(invalid("Stored block length is corrupt"));
+    }
+    r.pos += 4;
+
+    if r.pos + len > r.data.len() {
+        return Err(invalid("Unexpected end of stored block"));
+    }
+    out.extend_from_slice(&r.data[r.pos..r.pos + len]);
+    r.pos += len;
+    Ok(())
+}
+
+fn fixed_tables() -> Result<(Huffman, Huffman), Error> {
+    let mut lengths = [0u8; 288];
+    for (i, len) in lengths.iter_mut().enumerate() {
+        *len = match i {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    Ok((try!(Huffman::new(&lengths)), try!(Huffman::new(&[5u8; 30]))))
+}
+
+fn dynamic_tables(r: &mut BitReader) -> Result<(Huffman, Huffman), Error> {
+    let nlen = try!(r.bits(5)) as usize + 257;
+    let ndist = try!(r.bits(5)) as usize + 1;
+    let ncode = try!(r.bits(4)) as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &i in CODE_LENGTH_ORDER[..ncode].iter() {
+        code_lengths[i] = try!(r.bits(3)) as u8;
+    }
+    let code_huffman = try!(Huffman::new(&code_lengths));
+
+    // The literal/length and distance code lengths are run length coded as one list
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let symbol = try!(code_huffman.decode(r));
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if i == 0 {
+                    return Err(invalid("Repeated code length with nothing before it"));
+                }
+                (lengths[i - 1], 3 + try!(r.bits(2)) as usize)
+            },
+            17 => (0, 3 + try!(r.bits(3)) as usize),
+            _ => (0, 11 + try!(r.bits(7)) as usize),
+        };
+        if i + repeat > nlen + ndist {
+            return Err(invalid("Too many code lengths"));
+        }
+        for _ in 0..repeat {
+            lengths[i] = value;
+            i += 1;
+        }
+    }
+
+    if lengths[256] == 0 {
+        return Err(invalid("Deflate block has no end code"));
+    }
+
+    Ok((try!(Huffman::new(&lengths[..nlen])), try!(Huffman::new(&lengths[nlen..]))))
+}
+
+fn inflate_block(r: &mut BitReader, out: &mut Vec<u8>, lit: &Huffman, dist: &Huffman) -> Result<(), Error> {
+    loop {
+        let symbol = try!(lit.decode(r)) as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let symbol = symbol - 257;
+            if symbol >= 29 {
+                return Err(invalid("Invalid deflate length code"));
+            }
+            let length = LENGTH_BASE[symbol] as usize + try!(r.bits(LENGTH_EXTRA[symbol] as u32)) as usize;
+
+            let symbol = try!(dist.decode(r)) as usize;
+            if symbol >= 30 {
+                return Err(invalid("Invalid deflate distance code"));
+            }
+            let distance = DIST_BASE[symbol] as usize + try!(r.bits(DIST_EXTRA[symbol] as u32)) as usize;
+            if distance > out.len() {
+                return Err(invalid("Deflate distance is too far back"));
+            }
+
+            // Copy a byte at a time, the match is allowed to overlap what it's writing
+            let start = out.len() - distance;
+            for k in 0..length {
+                let byte = out[start + k];
+                out.push(byte);
+            }
+        }
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            out: Vec::new(),
+            bit_buf: 0,
+            bit_count: 0,
+        }
+    }
+
+    fn bits(&mut self, value: u32, n: u32) {
+        self.bit_buf |= value << self.bit_count;
+        self.bit_count += n;
+        while self.bit_count >= 8 {
+            self.out.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first, the opposite of everything else
+    fn code(&mut self, code: u32, len: u32) {
+        let mut reversed = 0;
+        for i in 0..len {
+            reversed |= ((code >> i) & 1) << (len - 1 - i);
+        }
+        self.bits(reversed, len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.out.push(self.bit_buf as u8);
+        }
+        self.out
+    }
+}
+
+// The fixed literal/length code from section 3.2.6 of the RFC
+fn write_fixed_literal(w: &mut BitWriter, symbol: usize) {
+    match symbol {
+        0..=143 => w.code(0x30 + symbol as u32, 8),
+        144..=255 => w.code(0x190 + (symbol - 144) as u32, 9),
+        256..=279 => w.code((symbol - 256) as u32, 7),
+        _ => w.code(0xc0 + (symbol - 280) as u32, 8),
+    }
+}
+
+fn write_match(w: &mut BitWriter, length: usize, distance: usize) {
+    let mut code = 28;
+    while LENGTH_BASE[code] as usize > length {
+        code -= 1;
+    }
+    write_fixed_literal(w, 257 + code);
+    w.bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+
+    let mut code = 29;
+    while DIST_BASE[code] as usize > distance {
+        code -= 1;
+    }
+    w.code(code as u32, 5);
+    w.bits((distance - DIST_BASE[code] as usize) as u32, DIST_EXTRA[code] as u32);
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let v = (data[i] as usize) << 16 | (data[i + 1] as usize) << 8 | data[i + 2] as usize;
+    (v.wrapping_mul(2654435761) >> 8) & ((1 << HASH_BITS) - 1)
+}
+
+// Raw deflate data as a single fixed Huffman block
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    // Final block, fixed Huffman
+    w.bits(1, 1);
+    w.bits(1, 2);
+
+    // Most recent position for each hash, and the previous position with the same hash
+    let mut head = vec![usize::max_value(); 1 << HASH_BITS];
+    let mut prev = vec![usize::max_value(); data.len()];
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(data, i);
+            let mut candidate = head[h];
+            let mut chain = 0;
+            let max_len = MAX_MATCH.min(data.len() - i);
+
+            while candidate != usize::max_value() && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let mut len = 0;
+                while len < max_len && data[candidate + len] == data[i + len] {
+                    len += 1;
+                }
+                if len > best_len {
+                    best_len = len;
+                    best_dist = i - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+
+            prev[i] = head[h];
+            head[h] = i;
+        }
+
+        if best_len >= MIN_MATCH {
+            write_match(&mut w, best_len, best_dist);
+            // Keep the hash chains up to date for the bytes we skipped over
+            for j in i + 1..i + best_len {
+                if j + MIN_MATCH <= data.len() {
+                    let h = hash(data, j);
+                    prev[j] = head[h];
+                    head[h] = j;
+                }
+            }
+            i += best_len;
+        } else {
+            write_fixed_literal(&mut w, data[i] as usize);
+            i += 1;
+        }
+    }
+
+    write_fixed_literal(&mut w, 256);
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // Long enough for matches to reach back across the whole window
+        let mut noisy = Vec::new();
+        let mut x: u32 = 1;
+        for _ in 0..100000 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            noisy.push((x >> 24) as u8 % 8);
+        }
+        let inputs: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabcabcabcabcabcabcabc".to_vec(),
+            vec![0; 70000],
+            noisy,
+        ];
+        for input in inputs.iter() {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed).unwrap(), *input);
+        }
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut compressed = compress(b"hello hello hello");
+        let last = compressed.len() - 1;
+        compressed[last] ^= 1;
+        assert!(decompress(&compressed).is_err());
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let compressed = compress(&[7; 1000]);
+        assert!(decompress(&compressed[..compressed.len() / 2]).is_err());
+    }
+}